@@ -0,0 +1,510 @@
+//! Typed x86-64 instructions, and a peephole optimizer over them.
+//!
+//! The code generator builds each function as a list of [`Line`]s instead of
+//! text, so that [`optimize`] can recognise and rewrite wasteful sequences
+//! before the function is printed in NASM syntax.
+
+use std::fmt;
+
+use crate::regalloc::{sub_register, Location};
+
+/// A condition code, as tested by `jcc`, `setcc` and `cmovcc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    E,
+    Ne,
+    G,
+    Ge,
+    L,
+    Le,
+    A,
+    Ae,
+    B,
+    Be,
+    P,
+    Np,
+}
+
+impl Cond {
+    /// The condition that holds exactly when `self` does not.
+    pub fn negate(self) -> Cond {
+        match self {
+            Cond::E => Cond::Ne,
+            Cond::Ne => Cond::E,
+            Cond::G => Cond::Le,
+            Cond::Ge => Cond::L,
+            Cond::L => Cond::Ge,
+            Cond::Le => Cond::G,
+            Cond::A => Cond::Be,
+            Cond::Ae => Cond::B,
+            Cond::B => Cond::Ae,
+            Cond::Be => Cond::A,
+            Cond::P => Cond::Np,
+            Cond::Np => Cond::P,
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Cond::E => "e",
+            Cond::Ne => "ne",
+            Cond::G => "g",
+            Cond::Ge => "ge",
+            Cond::L => "l",
+            Cond::Le => "le",
+            Cond::A => "a",
+            Cond::Ae => "ae",
+            Cond::B => "b",
+            Cond::Be => "be",
+            Cond::P => "p",
+            Cond::Np => "np",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Mov,
+    Movzx,
+    Movsx,
+    Movsxd,
+    Movq,
+    Add,
+    Sub,
+    Imul,
+    Mul,
+    Shl,
+    Shr,
+    Sar,
+    Lea,
+    Neg,
+    Btc,
+    Cqo,
+    Idiv,
+    Div,
+    Xor,
+    And,
+    Or,
+    Cmp,
+    Test,
+    Set(Cond),
+    Cmov(Cond),
+    J(Cond),
+    Jmp,
+    Push,
+    Pop,
+    Call,
+    Leave,
+    Ret,
+    Syscall,
+    Cvtsi2sd,
+    Cvttsd2si,
+    Addsd,
+    Subsd,
+    Mulsd,
+    Divsd,
+    Ucomisd,
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mnemonic = match self {
+            Opcode::Mov => "mov",
+            Opcode::Movzx => "movzx",
+            Opcode::Movsx => "movsx",
+            Opcode::Movsxd => "movsxd",
+            Opcode::Movq => "movq",
+            Opcode::Add => "add",
+            Opcode::Sub => "sub",
+            Opcode::Imul => "imul",
+            Opcode::Mul => "mul",
+            Opcode::Shl => "shl",
+            Opcode::Shr => "shr",
+            Opcode::Sar => "sar",
+            Opcode::Lea => "lea",
+            Opcode::Neg => "neg",
+            Opcode::Btc => "btc",
+            Opcode::Cqo => "cqo",
+            Opcode::Idiv => "idiv",
+            Opcode::Div => "div",
+            Opcode::Xor => "xor",
+            Opcode::And => "and",
+            Opcode::Or => "or",
+            Opcode::Cmp => "cmp",
+            Opcode::Test => "test",
+            Opcode::Set(cond) => return write!(f, "set{}", cond.suffix()),
+            Opcode::Cmov(cond) => return write!(f, "cmov{}", cond.suffix()),
+            Opcode::J(cond) => return write!(f, "j{}", cond.suffix()),
+            Opcode::Jmp => "jmp",
+            Opcode::Push => "push",
+            Opcode::Pop => "pop",
+            Opcode::Call => "call",
+            Opcode::Leave => "leave",
+            Opcode::Ret => "ret",
+            Opcode::Syscall => "syscall",
+            Opcode::Cvtsi2sd => "cvtsi2sd",
+            Opcode::Cvttsd2si => "cvttsd2si",
+            Opcode::Addsd => "addsd",
+            Opcode::Subsd => "subsd",
+            Opcode::Mulsd => "mulsd",
+            Opcode::Divsd => "divsd",
+            Opcode::Ucomisd => "ucomisd",
+        };
+        write!(f, "{}", mnemonic)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// The low `size` bytes of a general register, named by its 64-bit name.
+    Reg(&'static str, usize),
+    Xmm(u8),
+    /// The 8-byte stack slot at this offset from rbp.
+    Stack(i64),
+    /// The address `base + index * scale`, as computed by `lea`.
+    Address {
+        base: &'static str,
+        index: &'static str,
+        scale: u8,
+    },
+    Imm(i64),
+    /// A float constant, written as its bit pattern.
+    Float(f64),
+    Label(String),
+}
+
+impl Operand {
+    /// A whole 64-bit register.
+    pub fn reg(name: &'static str) -> Self {
+        Operand::Reg(name, 8)
+    }
+
+    /// Whether writing `self` can change what `other` reads.
+    fn overlaps(&self, other: &Operand) -> bool {
+        match (self, other) {
+            (Operand::Reg(a, _), Operand::Reg(b, _)) => a == b,
+            (Operand::Xmm(a), Operand::Xmm(b)) => a == b,
+            (Operand::Stack(a), Operand::Stack(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    fn is_memory(&self) -> bool {
+        matches!(self, Operand::Stack(_))
+    }
+
+    /// Whether this is all 64 bits of a register or slot, so moving into it
+    /// changes nothing else.
+    fn is_full_width(&self) -> bool {
+        matches!(
+            self,
+            Operand::Reg(_, 8) | Operand::Xmm(_) | Operand::Stack(_)
+        )
+    }
+}
+
+impl From<Location> for Operand {
+    fn from(location: Location) -> Self {
+        match location {
+            Location::Reg(reg) => Operand::reg(reg),
+            Location::Stack(offset) => Operand::Stack(offset),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Reg(name, size) => write!(f, "{}", sub_register(name, *size)),
+            Operand::Xmm(n) => write!(f, "xmm{}", n),
+            Operand::Stack(offset) => write!(f, "qword [rbp{}]", offset),
+            Operand::Address { base, index, scale } => {
+                write!(f, "[{} + {}*{}]", base, index, scale)
+            }
+            Operand::Imm(n) => write!(f, "{}", n),
+            Operand::Float(x) => write!(f, "0x{:x}", x.to_bits()),
+            Operand::Label(label) => write!(f, "{}", label),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instr {
+    pub op: Opcode,
+    pub operands: Vec<Operand>,
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.op)?;
+        for (i, operand) in self.operands.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { " " } else { ", " }, operand)?;
+        }
+        // Spell out float constants for whoever reads the listing
+        for operand in &self.operands {
+            if let Operand::Float(x) = operand {
+                write!(f, " ; {:?}", x)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    Label(String),
+    Instr(Instr),
+    Blank,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Label(label) => write!(f, "{}:", label),
+            Line::Instr(instr) => write!(f, "    {}", instr),
+            Line::Blank => Ok(()),
+        }
+    }
+}
+
+/// Builds an instruction line.
+pub fn instr<const N: usize>(op: Opcode, operands: [Operand; N]) -> Line {
+    Line::Instr(Instr {
+        op,
+        operands: operands.into(),
+    })
+}
+
+/// A rewrite of the lines at the start of a window: how many of them it
+/// replaces, and what with.
+type Rule = fn(&[Line]) -> Option<(usize, Vec<Line>)>;
+
+const RULES: [Rule; 5] = [
+    push_pop,
+    redundant_move_back,
+    move_to_itself,
+    jump_to_next,
+    branch_over_jump,
+];
+
+/// Applies the rewrite rules to `lines` until none of them matches. Local
+/// labels that nothing jumps to are dropped first, since they keep the
+/// instructions around them from being seen as adjacent.
+pub fn optimize(lines: &mut Vec<Line>) {
+    loop {
+        let mut changed = remove_unused_labels(lines);
+        let mut i = 0;
+        while i < lines.len() {
+            match RULES.iter().find_map(|rule| rule(&lines[i..])) {
+                Some((len, replacement)) => {
+                    lines.splice(i..i + len, replacement);
+                    changed = true;
+                }
+                None => i += 1,
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+}
+
+fn remove_unused_labels(lines: &mut Vec<Line>) -> bool {
+    let targets: Vec<String> = lines
+        .iter()
+        .filter_map(|line| match line {
+            Line::Instr(instr) => match instr.operands.first() {
+                Some(Operand::Label(label)) => Some(label.clone()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+    let before = lines.len();
+    lines.retain(|line| match line {
+        Line::Label(label) => !label.starts_with('.') || targets.contains(label),
+        _ => true,
+    });
+    lines.len() != before
+}
+
+/// The instruction on `line`, if it is one with opcode `op`.
+fn as_op(line: &Line, op: Opcode) -> Option<&[Operand]> {
+    match line {
+        Line::Instr(instr) if instr.op == op => Some(&instr.operands),
+        _ => None,
+    }
+}
+
+/// `push x`, then moves that leave `x` and the stack alone, then `pop y`:
+/// the value can be moved to `y` directly once the moves are done.
+fn push_pop(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    let [src] = as_op(lines.first()?, Opcode::Push)? else {
+        return None;
+    };
+    let rsp = Operand::reg("rsp");
+    let mut moves = Vec::new();
+    for line in &lines[1..] {
+        if let Some([dst]) = as_op(line, Opcode::Pop) {
+            if src.is_memory() && dst.is_memory() {
+                return None;
+            }
+            let len = moves.len() + 2;
+            if src != dst {
+                moves.push(instr(Opcode::Mov, [dst.clone(), src.clone()]));
+            }
+            return Some((len, moves));
+        }
+        match as_op(line, Opcode::Mov)? {
+            [dst, from] if !dst.overlaps(src) && !dst.overlaps(&rsp) && !from.overlaps(&rsp) => {
+                moves.push(line.clone());
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// `mov a, b` then `mov b, a`: the second moves back what is already there.
+fn redundant_move_back(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    let op = match lines.first()? {
+        Line::Instr(instr) if matches!(instr.op, Opcode::Mov | Opcode::Movq) => instr.op,
+        _ => return None,
+    };
+    let [a, b] = as_op(&lines[0], op)? else {
+        return None;
+    };
+    let [c, d] = as_op(lines.get(1)?, op)? else {
+        return None;
+    };
+    (a == d && b == c && a.is_full_width() && b.is_full_width())
+        .then(|| (2, vec![lines[0].clone()]))
+}
+
+/// `mov r, r` on a whole register does nothing. (On a 32-bit register it
+/// clears the upper half, so it stays.)
+fn move_to_itself(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    match as_op(lines.first()?, Opcode::Mov)? {
+        [a, b] if a == b && a.is_full_width() => Some((1, Vec::new())),
+        _ => None,
+    }
+}
+
+/// A jump, conditional or not, to the label on the next line.
+fn jump_to_next(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    let Line::Instr(Instr { op, operands }) = lines.first()? else {
+        return None;
+    };
+    let (Opcode::Jmp | Opcode::J(_), [Operand::Label(target)]) = (op, operands.as_slice()) else {
+        return None;
+    };
+    match lines.get(1)? {
+        Line::Label(next) if next == target => Some((1, Vec::new())),
+        _ => None,
+    }
+}
+
+/// `jcc a` over a `jmp b` to the label `a` right after it: branching to `b`
+/// on the opposite condition does the same in one jump.
+fn branch_over_jump(lines: &[Line]) -> Option<(usize, Vec<Line>)> {
+    let Line::Instr(Instr {
+        op: Opcode::J(cond),
+        operands,
+    }) = lines.first()?
+    else {
+        return None;
+    };
+    let [Operand::Label(over)] = operands.as_slice() else {
+        return None;
+    };
+    let [jump_target] = as_op(lines.get(1)?, Opcode::Jmp)? else {
+        return None;
+    };
+    match lines.get(2)? {
+        Line::Label(next) if next == over => Some((
+            2,
+            vec![instr(Opcode::J(cond.negate()), [jump_target.clone()])],
+        )),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(name: &str) -> Operand {
+        Operand::Label(name.to_string())
+    }
+
+    fn print(lines: &[Line]) -> Vec<String> {
+        lines
+            .iter()
+            .map(|line| line.to_string().trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_pushes_and_pops_become_moves() {
+        let mut lines = vec![
+            instr(Opcode::Push, [Operand::Imm(40)]),
+            instr(Opcode::Push, [Operand::Stack(-8)]),
+            instr(Opcode::Pop, [Operand::reg("rsi")]),
+            instr(Opcode::Pop, [Operand::reg("rdi")]),
+            instr(Opcode::Call, [label("fn_f")]),
+        ];
+        optimize(&mut lines);
+        assert_eq!(
+            print(&lines),
+            ["mov rsi, qword [rbp-8]", "mov rdi, 40", "call fn_f"]
+        );
+
+        // Swapping two registers needs the stack
+        let mut lines = vec![
+            instr(Opcode::Push, [Operand::reg("rsi")]),
+            instr(Opcode::Push, [Operand::reg("rdi")]),
+            instr(Opcode::Pop, [Operand::reg("rsi")]),
+            instr(Opcode::Pop, [Operand::reg("rdi")]),
+        ];
+        optimize(&mut lines);
+        assert_eq!(print(&lines), ["push rsi", "mov rsi, rdi", "pop rdi"]);
+    }
+
+    #[test]
+    fn test_redundant_moves() {
+        let mut lines = vec![
+            instr(Opcode::Mov, [Operand::Stack(-8), Operand::reg("rcx")]),
+            instr(Opcode::Mov, [Operand::reg("rcx"), Operand::Stack(-8)]),
+            instr(Opcode::Movq, [Operand::reg("rsi"), Operand::Xmm(0)]),
+            instr(Opcode::Movq, [Operand::Xmm(0), Operand::reg("rsi")]),
+            instr(Opcode::Mov, [Operand::reg("rbx"), Operand::reg("rbx")]),
+            instr(
+                Opcode::Mov,
+                [Operand::Reg("rbx", 4), Operand::Reg("rbx", 4)],
+            ),
+        ];
+        optimize(&mut lines);
+        assert_eq!(
+            print(&lines),
+            ["mov qword [rbp-8], rcx", "movq rsi, xmm0", "mov ebx, ebx"]
+        );
+    }
+
+    #[test]
+    fn test_jumps() {
+        let mut lines = vec![
+            instr(Opcode::J(Cond::L), [label(".body_1")]),
+            instr(Opcode::Jmp, [label(".end_2")]),
+            Line::Label(".body_1".to_string()),
+            instr(Opcode::Add, [Operand::reg("rcx"), Operand::Imm(1)]),
+            instr(Opcode::Jmp, [label(".end_2")]),
+            Line::Label(".end_2".to_string()),
+            instr(Opcode::Ret, []),
+        ];
+        optimize(&mut lines);
+        assert_eq!(
+            print(&lines),
+            ["jge .end_2", "add rcx, 1", ".end_2:", "ret"]
+        );
+    }
+}
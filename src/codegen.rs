@@ -1,42 +1,73 @@
-use std::collections::HashMap;
+use crate::{
+    asm::{self, instr, Cond, Line, Opcode},
+    ir::{self, Instr, Module, Operand, Terminator},
+    lower::lower,
+    parser::{Op, Stmt},
+    regalloc::{self, Location},
+    runtime::RUNTIME,
+    strength,
+    target::Target,
+    types::Type,
+};
 
-use crate::parser::{Expr, Op, Stmt};
+/// Registers carrying the first six call arguments, in order.
+pub const ARG_REGS: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
 
+/// Generates x86-64 assembly from phi-free IR.
+///
+/// Values live where the register allocator put them. Integers are kept
+/// sign- or zero-extended to the full 64 bits, and floats are carried in
+/// general registers as their raw bit pattern, moving into xmm0 and xmm1 only
+/// for SSE arithmetic. rax, rdx and r11 are scratch registers. Each function
+/// is built as typed instructions and run through the peephole optimizer
+/// before it is printed.
 pub struct CodeGen {
     output: String,
-    vars: HashMap<String, i64>,
-    stack_offset: i64,
-    label_counter: usize,
+    /// Instructions of the current function.
+    lines: Vec<Line>,
+    /// Location of every value in the current function.
+    locations: Vec<Option<Location>>,
+    /// Callee-saved registers the current function restores before
+    /// returning, and the stack slots they are saved in.
+    saved: Vec<(&'static str, i64)>,
+    uses_runtime: bool,
+    target: Target,
 }
 
 impl CodeGen {
     pub fn new() -> Self {
+        Self::with_target(Target::default())
+    }
+
+    /// Generates code for `target`, which the output records at its top.
+    pub fn with_target(target: Target) -> Self {
         Self {
             output: String::new(),
-            vars: HashMap::new(),
-            stack_offset: 0,
-            label_counter: 0,
+            lines: Vec::new(),
+            locations: Vec::new(),
+            saved: Vec::new(),
+            uses_runtime: false,
+            target,
         }
     }
 
-    fn new_label(&mut self, prefix: &str) -> String {
-        let label = format!(".{}_{}", prefix, self.label_counter);
-        self.label_counter += 1;
-        label
-    }
-
     fn emit(&mut self, line: &str) {
         self.output.push_str(line);
         self.output.push('\n');
     }
 
-    fn emit_indent(&mut self, line: &str) {
-        self.output.push_str("    ");
-        self.output.push_str(line);
-        self.output.push('\n');
+    fn emit_instr<const N: usize>(&mut self, op: Opcode, operands: [asm::Operand; N]) {
+        self.lines.push(instr(op, operands));
+    }
+
+    /// Lowers `stmts` to IR and generates assembly for it.
+    pub fn generate(self, stmts: &[Stmt]) -> String {
+        self.generate_module(&lower(stmts))
     }
 
-    pub fn generate(mut self, stmts: &[Stmt]) -> String {
+    pub fn generate_module(mut self, module: &Module) -> String {
+        let target = format!("; target: {}", self.target);
+        self.emit(&target);
         // Data section (empty for now, but needed for future string literals etc.)
         self.emit("section .data");
         self.emit("");
@@ -48,243 +79,886 @@ impl CodeGen {
         // Text section
         self.emit("section .text");
         self.emit("global _start");
-        self.emit("");
-
-        self.emit("_start:");
-        // Set up stack frame
-        self.emit_indent("push rbp");
-        self.emit_indent("mov rbp, rsp");
 
-        // Reserve stack space for variables
-        // Count how many let statements we have
-        let var_count = stmts
-            .iter()
-            .filter(|s| matches!(s, Stmt::Let(_, _)))
-            .count();
-
-        if var_count > 0 {
-            // Align to 16 bytes for ABI compliance
-            let stack_space = ((var_count * 8 + 15) / 16) * 16;
-            self.emit_indent(&format!("sub rsp, {}", stack_space));
+        for f in &module.functions {
+            self.gen_function(f);
         }
 
-        self.emit("");
-
-        // Generate code for each statement
-        for stmt in stmts {
-            self.gen_stmt(stmt);
+        if self.uses_runtime {
+            self.output.push_str(RUNTIME);
         }
 
-        // Default exit with code 0 if no exit statement was encountered
-        self.emit("");
-        self.emit_indent("; default exit");
-        self.emit_indent("mov rax, 60");
-        self.emit_indent("xor rdi, rdi");
-        self.emit_indent("syscall");
-
         self.output
     }
 
-    fn gen_stmt(&mut self, stmt: &Stmt) {
-        match stmt {
-            Stmt::Let(name, expr) => {
-                self.emit_indent(&format!("; let {} = ...", name));
-
-                // Generate code for the expression, result will be in rax
-                self.gen_expr(expr);
+    fn gen_prologue(&mut self, slot_count: usize) {
+        // Set up stack frame
+        self.emit_instr(Opcode::Push, [reg("rbp")]);
+        self.emit_instr(Opcode::Mov, [reg("rbp"), reg("rsp")]);
 
-                // Allocate stack space for this variable
-                self.stack_offset -= 8;
-                self.vars.insert(name.clone(), self.stack_offset);
+        // Reserve stack space for spilled values and saved registers
+        if slot_count > 0 {
+            // Align to 16 bytes for ABI compliance
+            let stack_space = (slot_count * 8).div_ceil(16) * 16;
+            self.emit_instr(
+                Opcode::Sub,
+                [reg("rsp"), asm::Operand::Imm(stack_space as i64)],
+            );
+        }
+        for (saved, offset) in self.saved.clone() {
+            self.emit_instr(Opcode::Mov, [asm::Operand::Stack(offset), reg(saved)]);
+        }
 
-                // Store the result on the stack
-                self.emit_indent(&format!("mov [rbp{}], rax", self.stack_offset));
-                self.emit("");
-            }
-            Stmt::Exit(expr) => {
-                self.emit_indent("; exit");
+        self.lines.push(Line::Blank);
+    }
 
-                // Generate code for the expression, result will be in rax
-                self.gen_expr(expr);
+    fn gen_function(&mut self, f: &ir::Function) {
+        let allocation = regalloc::allocate(f);
+        self.locations = allocation.locations;
+        // The entry function never returns, so it has nothing to restore
+        self.saved = if f.entry {
+            Vec::new()
+        } else {
+            allocation
+                .callee_saved
+                .iter()
+                .enumerate()
+                .map(|(i, reg)| (*reg, -8 * (allocation.spill_slots + i + 1) as i64))
+                .collect()
+        };
 
-                // syscall: exit(rax)
-                self.emit_indent("mov rdi, rax");
-                self.emit_indent("mov rax, 60");
-                self.emit_indent("syscall");
-                self.emit("");
-            }
-            Stmt::If(cond, then_body, elif_branches, else_body) => {
-                let end_label = self.new_label("if_end");
+        self.lines.clear();
+        if f.entry {
+            self.lines.push(Line::Label("_start".to_string()));
+        } else {
+            self.lines.push(Line::Label(format!("fn_{}", f.name)));
+        }
+        self.gen_prologue(allocation.spill_slots + self.saved.len());
 
-                // Generate condition for if
-                self.emit_indent("; if condition");
-                self.gen_expr(cond);
-                self.emit_indent("cmp rax, 0");
+        // Move the arguments to where their parameters live. Going through
+        // the stack keeps one move from clobbering another's source.
+        let moves: Vec<(&'static str, Location)> = f
+            .params
+            .iter()
+            .zip(ARG_REGS)
+            .map(|(param, reg)| (reg, self.location(*param)))
+            .filter(|(reg, location)| *location != Location::Reg(reg))
+            .collect();
+        for (arg, _) in &moves {
+            self.emit_instr(Opcode::Push, [reg(arg)]);
+        }
+        for (_, location) in moves.iter().rev() {
+            self.emit_instr(Opcode::Pop, [(*location).into()]);
+        }
 
-                if elif_branches.is_empty() && else_body.is_none() {
-                    // Simple if without elif or else
-                    self.emit_indent(&format!("je {}", end_label));
-                    self.emit_indent("; then block");
-                    for stmt in then_body {
-                        self.gen_stmt(stmt);
+        let preds = f.predecessors();
+        let uses = use_counts(f);
+        for (id, block) in f.blocks.iter().enumerate() {
+            if !preds[id].is_empty() {
+                self.lines.push(Line::Label(f.label(ir::BlockId(id))));
+            }
+            let fused = fused_comparison(block, &uses);
+            let selecting = fused_selects(block, &uses);
+            let tail = tail_call(f, block);
+            let mut condition = None;
+            // The comparison whose outcome the flags hold for the selects
+            // that follow it
+            let mut flags = None;
+            for (i, instr) in block.instrs.iter().enumerate() {
+                match instr {
+                    Instr::Binary {
+                        op, ty, lhs, rhs, ..
+                    } if Some(i) == fused => {
+                        condition = Some(self.gen_compare(op, *ty, lhs, rhs));
                     }
-                } else {
-                    // If with elif and/or else branches
-                    let mut next_label = self.new_label("elif");
-                    self.emit_indent(&format!("je {}", next_label));
-
-                    // Then block
-                    self.emit_indent("; then block");
-                    for stmt in then_body {
-                        self.gen_stmt(stmt);
+                    Instr::Binary {
+                        dst,
+                        op,
+                        ty,
+                        lhs,
+                        rhs,
+                    } if selecting.contains(&i) => {
+                        flags = Some((*dst, self.gen_compare(op, *ty, lhs, rhs)));
                     }
-                    self.emit_indent(&format!("jmp {}", end_label));
-
-                    // Elif branches
-                    for (elif_cond, elif_body) in elif_branches {
-                        self.emit(&format!("{}:", next_label));
-                        next_label = self.new_label("elif");
-
-                        self.emit_indent("; elif condition");
-                        self.gen_expr(elif_cond);
-                        self.emit_indent("cmp rax, 0");
-                        self.emit_indent(&format!("je {}", next_label));
-
-                        self.emit_indent("; elif block");
-                        for stmt in elif_body {
-                            self.gen_stmt(stmt);
-                        }
-                        self.emit_indent(&format!("jmp {}", end_label));
+                    Instr::Select {
+                        dst,
+                        cond: Operand::Value(cond),
+                        then_value,
+                        else_value,
+                    } if flags.is_some_and(|(value, _)| value == *cond) => {
+                        let (_, condition) = flags.unwrap();
+                        self.gen_select(*dst, condition, then_value, else_value);
                     }
-
-                    // Else branch (or final label)
-                    self.emit(&format!("{}:", next_label));
-                    if let Some(else_stmts) = else_body {
-                        self.emit_indent("; else block");
-                        for stmt in else_stmts {
-                            self.gen_stmt(stmt);
-                        }
+                    Instr::Call { func, args, .. } if Some(i) == tail => {
+                        // The callee returns straight to our caller
+                        self.gen_arguments(args);
+                        self.gen_epilogue();
+                        self.emit_instr(Opcode::Jmp, [label(format!("fn_{}", func))]);
                     }
+                    _ => self.gen_instr(instr),
                 }
-
-                self.emit(&format!("{}:", end_label));
-                self.emit("");
             }
-            Stmt::While(cond, body) => {
-                let start_label = self.new_label("while_start");
-                let end_label = self.new_label("while_end");
+            match condition {
+                _ if tail.is_some() => {}
+                Some(condition) => {
+                    self.gen_conditional_jump(f, condition, &block.term, ir::BlockId(id + 1))
+                }
+                None => self.gen_terminator(f, &block.term, ir::BlockId(id + 1)),
+            }
+        }
 
-                // Label for loop start
-                self.emit(&format!("{}:", start_label));
+        asm::optimize(&mut self.lines);
+        self.emit("");
+        for line in std::mem::take(&mut self.lines) {
+            self.emit(&line.to_string());
+        }
+    }
 
-                // Generate condition
-                self.emit_indent("; while condition");
-                self.gen_expr(cond);
-                self.emit_indent("cmp rax, 0");
-                self.emit_indent(&format!("je {}", end_label));
+    fn location(&self, value: ir::Value) -> Location {
+        self.locations[value.0].expect("every value used is assigned")
+    }
 
-                // Generate body
-                self.emit_indent("; while body");
-                for stmt in body {
-                    self.gen_stmt(stmt);
-                }
+    /// The register `operand` lives in, if it lives in one.
+    fn register_of(&self, operand: &Operand) -> Option<&'static str> {
+        match operand {
+            Operand::Value(value) => match self.location(*value) {
+                Location::Reg(reg) => Some(reg),
+                Location::Stack(_) => None,
+            },
+            _ => None,
+        }
+    }
 
-                // Jump back to start
-                self.emit_indent(&format!("jmp {}", start_label));
+    /// The register to compute `dst` in: its own, or rax when it is spilled.
+    fn dst_register(&self, dst: ir::Value) -> &'static str {
+        match self.location(dst) {
+            Location::Reg(reg) => reg,
+            Location::Stack(_) => "rax",
+        }
+    }
 
-                // End label
-                self.emit(&format!("{}:", end_label));
-                self.emit("");
+    /// `operand` as an instruction's source: a register, a stack slot or a
+    /// 32-bit immediate. Other constants are first loaded into `scratch`.
+    fn source(&mut self, operand: &Operand, scratch: &'static str) -> asm::Operand {
+        match operand {
+            Operand::Value(value) => self.location(*value).into(),
+            Operand::Int(n) if i32::try_from(*n).is_ok() => asm::Operand::Imm(*n),
+            _ => {
+                self.gen_load(operand, scratch);
+                reg(scratch)
             }
         }
     }
 
-    fn gen_expr(&mut self, expr: &Expr) {
-        match expr {
-            Expr::Num(n) => {
-                self.emit_indent(&format!("mov rax, {}", n));
+    /// `operand` as a register or stack slot, loading it into `scratch` if
+    /// it is a constant.
+    fn register_or_memory(&mut self, operand: &Operand, scratch: &'static str) -> asm::Operand {
+        match operand {
+            Operand::Value(value) => self.location(*value).into(),
+            _ => {
+                self.gen_load(operand, scratch);
+                reg(scratch)
             }
-            Expr::Ident(name) => {
-                let offset = self
-                    .vars
-                    .get(name)
-                    .unwrap_or_else(|| panic!("undefined variable: {}", name));
-                self.emit_indent(&format!("mov rax, [rbp{}]", offset));
-            }
-            Expr::BinOp(left, op, right) => {
-                // Evaluate right side first and push onto stack
-                self.gen_expr(right);
-                self.emit_indent("push rax");
+        }
+    }
 
-                // Evaluate left side (result in rax)
-                self.gen_expr(left);
+    /// Loads `operand` into `dst`.
+    fn gen_load(&mut self, operand: &Operand, dst: &'static str) {
+        let src = match operand {
+            Operand::Int(n) => asm::Operand::Imm(*n),
+            // Floats travel through general registers as their raw bit pattern
+            Operand::Float(f) => asm::Operand::Float(*f),
+            Operand::Value(value) => match self.location(*value) {
+                Location::Reg(src) if src == dst => return,
+                location => location.into(),
+            },
+        };
+        self.emit_instr(Opcode::Mov, [reg(dst), src]);
+    }
 
-                // Pop right side into rbx
-                self.emit_indent("pop rbx");
+    /// Moves `src` into the location of `value`, unless it is already there.
+    fn gen_store(&mut self, value: ir::Value, src: &'static str) {
+        match self.location(value) {
+            Location::Reg(dst) if dst == src => {}
+            location => self.emit_instr(Opcode::Mov, [location.into(), reg(src)]),
+        }
+    }
 
-                // Perform the operation
-                match op {
-                    Op::Add => {
-                        self.emit_indent("add rax, rbx");
-                    }
-                    Op::Sub => {
-                        self.emit_indent("sub rax, rbx");
-                    }
-                    Op::Mul => {
-                        self.emit_indent("imul rax, rbx");
-                    }
-                    Op::Div => {
-                        // For signed division:
-                        // cqo sign-extends rax into rdx:rax
-                        // idiv rbx divides rdx:rax by rbx, quotient in rax, remainder in rdx
-                        self.emit_indent("cqo");
-                        self.emit_indent("idiv rbx");
-                    }
-                    Op::Eq => {
-                        self.emit_indent("cmp rax, rbx");
-                        self.emit_indent("sete al");
-                        self.emit_indent("movzx rax, al");
+    fn gen_instr(&mut self, instr: &Instr) {
+        match instr {
+            Instr::Copy { dst, src } => match self.location(*dst) {
+                Location::Reg(dst) => self.gen_load(src, dst),
+                location => {
+                    // Memory can't be copied straight to memory
+                    let src = if matches!(src, Operand::Value(_)) && self.register_of(src).is_none()
+                    {
+                        self.gen_load(src, "rax");
+                        reg("rax")
+                    } else {
+                        self.source(src, "rax")
+                    };
+                    let dst = location.into();
+                    if src != dst {
+                        self.emit_instr(Opcode::Mov, [dst, src]);
                     }
-                    Op::NotEq => {
-                        self.emit_indent("cmp rax, rbx");
-                        self.emit_indent("setne al");
-                        self.emit_indent("movzx rax, al");
-                    }
-                    Op::Gt => {
-                        self.emit_indent("cmp rax, rbx");
-                        self.emit_indent("setg al");
-                        self.emit_indent("movzx rax, al");
+                }
+            },
+            Instr::Binary {
+                dst,
+                op,
+                ty,
+                lhs,
+                rhs,
+            } => {
+                if op.is_comparison() {
+                    let condition = self.gen_compare(op, *ty, lhs, rhs);
+                    self.gen_set_condition(*dst, condition);
+                } else if ty.is_float() {
+                    self.gen_float_binop(*dst, op, lhs, rhs);
+                } else {
+                    self.gen_int_binop(*dst, op, *ty, lhs, rhs);
+                }
+            }
+            Instr::Neg { dst, ty, src } => {
+                let dst_reg = self.dst_register(*dst);
+                self.gen_load(src, dst_reg);
+                if ty.is_float() {
+                    // Flip the sign bit
+                    self.emit_instr(Opcode::Btc, [reg(dst_reg), asm::Operand::Imm(63)]);
+                } else {
+                    self.emit_instr(Opcode::Neg, [reg(dst_reg)]);
+                    self.gen_truncate(*ty, dst_reg);
+                }
+                self.gen_store(*dst, dst_reg);
+            }
+            Instr::Cast { dst, from, to, src } => {
+                let dst_reg = self.dst_register(*dst);
+                match (from.is_float(), to.is_float()) {
+                    (false, true) => {
+                        let src = self.register_or_memory(src, "rax");
+                        self.emit_instr(Opcode::Cvtsi2sd, [asm::Operand::Xmm(0), src]);
+                        self.emit_instr(Opcode::Movq, [reg(dst_reg), asm::Operand::Xmm(0)]);
                     }
-                    Op::Gte => {
-                        self.emit_indent("cmp rax, rbx");
-                        self.emit_indent("setge al");
-                        self.emit_indent("movzx rax, al");
+                    (true, false) => {
+                        let src = self.register_or_memory(src, "rax");
+                        self.emit_instr(Opcode::Movq, [asm::Operand::Xmm(0), src]);
+                        self.emit_instr(Opcode::Cvttsd2si, [reg(dst_reg), asm::Operand::Xmm(0)]);
+                        self.gen_truncate(*to, dst_reg);
                     }
-                    Op::Lt => {
-                        self.emit_indent("cmp rax, rbx");
-                        self.emit_indent("setl al");
-                        self.emit_indent("movzx rax, al");
+                    (true, true) => self.gen_load(src, dst_reg),
+                    (false, false) => {
+                        self.gen_load(src, dst_reg);
+                        self.gen_truncate(*to, dst_reg);
                     }
-                    Op::Lte => {
-                        self.emit_indent("cmp rax, rbx");
-                        self.emit_indent("setle al");
-                        self.emit_indent("movzx rax, al");
+                }
+                self.gen_store(*dst, dst_reg);
+            }
+            Instr::Select {
+                dst,
+                cond,
+                then_value,
+                else_value,
+            } => {
+                match self.register_or_memory(cond, "rax") {
+                    asm::Operand::Reg(cond, _) => {
+                        self.emit_instr(Opcode::Test, [reg(cond), reg(cond)])
                     }
+                    cond => self.emit_instr(Opcode::Cmp, [cond, asm::Operand::Imm(0)]),
+                }
+                let condition = Condition::Code(Cond::Ne);
+                self.gen_select(*dst, condition, then_value, else_value);
+            }
+            Instr::Call { dst, func, args } => {
+                self.gen_arguments(args);
+                self.emit_instr(Opcode::Call, [label(format!("fn_{}", func))]);
+                if let Some(dst) = dst {
+                    self.gen_store(*dst, "rax");
+                }
+            }
+            Instr::Print { ty, src } => {
+                let routine = match ty {
+                    Type::F64 => "_crab_print_f64",
+                    Type::Bool => "_crab_print_bool",
+                    _ if ty.is_signed() => "_crab_print_i64",
+                    _ => "_crab_print_u64",
+                };
+                if ty.is_float() {
+                    let src = self.register_or_memory(src, "rax");
+                    self.emit_instr(Opcode::Movq, [asm::Operand::Xmm(0), src]);
+                } else {
+                    self.gen_load(src, "rdi");
+                }
+                self.emit_instr(Opcode::Call, [label(routine.to_string())]);
+                self.uses_runtime = true;
+            }
+            Instr::Phi { .. } => unreachable!("phis are removed before code generation"),
+        }
+    }
+
+    /// Moves the arguments of a call into their registers.
+    fn gen_arguments(&mut self, args: &[Operand]) {
+        // Push the arguments left to right, then pop them into place
+        for arg in args {
+            let arg = self.source(arg, "rax");
+            self.emit_instr(Opcode::Push, [arg]);
+        }
+        for arg in ARG_REGS[..args.len()].iter().rev() {
+            self.emit_instr(Opcode::Pop, [reg(arg)]);
+        }
+    }
+
+    /// Restores the callee-saved registers and drops the stack frame, leaving
+    /// the return address on top of the stack.
+    fn gen_epilogue(&mut self) {
+        for (saved, offset) in self.saved.clone() {
+            self.emit_instr(Opcode::Mov, [reg(saved), asm::Operand::Stack(offset)]);
+        }
+        self.emit_instr(Opcode::Leave, []);
+    }
+
+    /// Emits `term`; `next` is the block laid out right after this one, which
+    /// needs no jump to reach.
+    fn gen_terminator(&mut self, f: &ir::Function, term: &Terminator, next: ir::BlockId) {
+        match term {
+            Terminator::Jump(target) => {
+                if *target != next {
+                    self.emit_instr(Opcode::Jmp, [label(f.label(*target))]);
                 }
             }
-            Expr::UnaryOp(op, expr) => {
-                self.gen_expr(expr);
-                match op {
-                    Op::Sub => {
-                        self.emit_indent("neg rax");
+            Terminator::Branch {
+                cond,
+                then_block,
+                else_block,
+            } => {
+                let Operand::Value(value) = cond else {
+                    // A constant condition always takes the same edge
+                    let taken = if *cond == Operand::Int(0) {
+                        *else_block
+                    } else {
+                        *then_block
+                    };
+                    self.gen_terminator(f, &Terminator::Jump(taken), next);
+                    return;
+                };
+                match self.location(*value) {
+                    Location::Reg(cond) => self.emit_instr(Opcode::Test, [reg(cond), reg(cond)]),
+                    location => {
+                        self.emit_instr(Opcode::Cmp, [location.into(), asm::Operand::Imm(0)])
                     }
-                    _ => {
-                        println!("Unary Operator error");
+                }
+                self.gen_conditional_jump(f, Condition::Code(Cond::Ne), term, next);
+            }
+            Terminator::Return(value) => {
+                if let Some(value) = value {
+                    self.gen_load(value, "rax");
+                }
+                self.gen_epilogue();
+                self.emit_instr(Opcode::Ret, []);
+            }
+            Terminator::Exit(code) => {
+                // syscall: exit(rdi)
+                self.gen_load(code, "rdi");
+                self.emit_instr(Opcode::Mov, [reg("rax"), asm::Operand::Imm(60)]);
+                self.emit_instr(Opcode::Syscall, []);
+            }
+            Terminator::Pending => unreachable!("blocks are terminated before code generation"),
+        }
+    }
+
+    /// Emits the branch `term` as conditional jumps on the flags, which hold
+    /// its condition as `condition`.
+    fn gen_conditional_jump(
+        &mut self,
+        f: &ir::Function,
+        condition: Condition,
+        term: &Terminator,
+        next: ir::BlockId,
+    ) {
+        let Terminator::Branch {
+            then_block,
+            else_block,
+            ..
+        } = term
+        else {
+            unreachable!("only a branch reads a condition");
+        };
+        let (then_label, else_label) = (label(f.label(*then_block)), label(f.label(*else_block)));
+        match condition {
+            Condition::Code(code) if *then_block == next => {
+                self.emit_instr(Opcode::J(code.negate()), [else_label]);
+            }
+            Condition::Code(code) => {
+                self.emit_instr(Opcode::J(code), [then_label]);
+                if *else_block != next {
+                    self.emit_instr(Opcode::Jmp, [else_label]);
+                }
+            }
+            // Equal needs ZF set and PF clear
+            Condition::FloatEq => {
+                self.emit_instr(Opcode::J(Cond::Ne), [else_label.clone()]);
+                self.emit_instr(Opcode::J(Cond::P), [else_label]);
+                if *then_block != next {
+                    self.emit_instr(Opcode::Jmp, [then_label]);
+                }
+            }
+            Condition::FloatNotEq => {
+                self.emit_instr(Opcode::J(Cond::Ne), [then_label.clone()]);
+                self.emit_instr(Opcode::J(Cond::P), [then_label]);
+                if *else_block != next {
+                    self.emit_instr(Opcode::Jmp, [else_label]);
+                }
+            }
+        }
+    }
+
+    /// Emits integer arithmetic on operands of type `ty`.
+    fn gen_int_binop(&mut self, dst: ir::Value, op: &Op, ty: Type, lhs: &Operand, rhs: &Operand) {
+        let reduced = match (op, lhs, rhs) {
+            (Op::Mul, _, Operand::Int(c)) => self.gen_mul_by_constant(dst, ty, lhs, *c),
+            (Op::Mul, Operand::Int(c), _) => self.gen_mul_by_constant(dst, ty, rhs, *c),
+            (Op::Div, _, Operand::Int(d)) if self.gen_div_by_constant(ty, lhs, *d) => {
+                self.gen_truncate(ty, "rax");
+                self.gen_store(dst, "rax");
+                true
+            }
+            _ => false,
+        };
+        if reduced {
+            return;
+        }
+        match op {
+            Op::Add | Op::Sub | Op::Mul => {
+                let (mut lhs, mut rhs) = (lhs, rhs);
+                let mut dst_reg = self.dst_register(dst);
+                // Loading the left operand into the destination would
+                // overwrite the right one
+                if self.register_of(rhs) == Some(dst_reg) && self.register_of(lhs) != Some(dst_reg)
+                {
+                    if *op == Op::Sub {
+                        dst_reg = "rax";
+                    } else {
+                        std::mem::swap(&mut lhs, &mut rhs);
                     }
                 }
+                self.gen_load(lhs, dst_reg);
+                let rhs = self.source(rhs, "r11");
+                let opcode = match op {
+                    Op::Add => Opcode::Add,
+                    Op::Sub => Opcode::Sub,
+                    _ => Opcode::Imul,
+                };
+                self.emit_instr(opcode, [reg(dst_reg), rhs]);
+                self.gen_truncate(ty, dst_reg);
+                self.gen_store(dst, dst_reg);
+            }
+            Op::Div => {
+                self.gen_load(lhs, "rax");
+                let divisor = self.register_or_memory(rhs, "r11");
+                if ty.is_signed() {
+                    // cqo sign-extends rax into rdx:rax; idiv leaves the
+                    // quotient in rax and the remainder in rdx
+                    self.emit_instr(Opcode::Cqo, []);
+                    self.emit_instr(Opcode::Idiv, [divisor]);
+                } else {
+                    // Unsigned division takes a zeroed rdx as the high half
+                    let edx = asm::Operand::Reg("rdx", 4);
+                    self.emit_instr(Opcode::Xor, [edx.clone(), edx]);
+                    self.emit_instr(Opcode::Div, [divisor]);
+                }
+                self.gen_truncate(ty, "rax");
+                self.gen_store(dst, "rax");
+            }
+            _ => unreachable!("comparisons are emitted by gen_compare"),
+        }
+    }
+
+    /// Multiplies `lhs` by the constant `c` with `lea` and shifts, if that
+    /// is cheaper than `imul`, returning whether it did.
+    fn gen_mul_by_constant(&mut self, dst: ir::Value, ty: Type, lhs: &Operand, c: i64) -> bool {
+        // c = factor * 2^shift, where lea can multiply by the factor
+        let shift = c.trailing_zeros().min(63);
+        let factor = c >> shift;
+        if c < 0 || !matches!(factor, 0 | 1 | 3 | 5 | 9) {
+            return false;
+        }
+        let dst_reg = self.dst_register(dst);
+        if c == 0 {
+            self.emit_instr(Opcode::Mov, [reg(dst_reg), asm::Operand::Imm(0)]);
+        } else {
+            self.gen_load(lhs, dst_reg);
+            if factor > 1 {
+                let scaled = asm::Operand::Address {
+                    base: dst_reg,
+                    index: dst_reg,
+                    scale: factor as u8 - 1,
+                };
+                self.emit_instr(Opcode::Lea, [reg(dst_reg), scaled]);
+            }
+            if shift > 0 {
+                self.emit_instr(Opcode::Shl, [reg(dst_reg), asm::Operand::Imm(shift as i64)]);
+            }
+            self.gen_truncate(ty, dst_reg);
+        }
+        self.gen_store(dst, dst_reg);
+        true
+    }
+
+    /// Divides `lhs` by the constant `d` without a division instruction,
+    /// leaving the quotient in rax, and returns whether it could. Division
+    /// by 0, and signed division by -1, are left to trap in `div`/`idiv`.
+    fn gen_div_by_constant(&mut self, ty: Type, lhs: &Operand, d: i64) -> bool {
+        let (rax, rdx) = (reg("rax"), reg("rdx"));
+        let imm = asm::Operand::Imm;
+        if ty.is_signed() {
+            if d == 0 || d == -1 {
+                return false;
+            }
+            if d == 1 {
+                self.gen_load(lhs, "rax");
+            } else if let Some(k) = strength::log2_exact(d.unsigned_abs()) {
+                // Shifting rounds towards negative infinity, so a negative
+                // dividend is first biased by 2^k - 1
+                self.gen_load(lhs, "rax");
+                self.emit_instr(Opcode::Mov, [rdx.clone(), rax.clone()]);
+                self.emit_instr(Opcode::Sar, [rdx.clone(), imm(63)]);
+                self.emit_instr(Opcode::Shr, [rdx.clone(), imm(64 - k as i64)]);
+                self.emit_instr(Opcode::Add, [rax.clone(), rdx]);
+                self.emit_instr(Opcode::Sar, [rax.clone(), imm(k as i64)]);
+                if d < 0 {
+                    self.emit_instr(Opcode::Neg, [rax]);
+                }
+            } else {
+                let magic = strength::signed_magic(d);
+                let dividend = self.register_or_memory(lhs, "r11");
+                self.emit_instr(Opcode::Mov, [rax.clone(), imm(magic.multiplier)]);
+                // rdx gets the high half of the product
+                self.emit_instr(Opcode::Imul, [dividend.clone()]);
+                if d > 0 && magic.multiplier < 0 {
+                    self.emit_instr(Opcode::Add, [rdx.clone(), dividend]);
+                } else if d < 0 && magic.multiplier > 0 {
+                    self.emit_instr(Opcode::Sub, [rdx.clone(), dividend]);
+                }
+                if magic.shift > 0 {
+                    self.emit_instr(Opcode::Sar, [rdx.clone(), imm(magic.shift as i64)]);
+                }
+                // Add one to a negative quotient so it rounds towards zero
+                self.emit_instr(Opcode::Mov, [rax.clone(), rdx.clone()]);
+                self.emit_instr(Opcode::Shr, [rax.clone(), imm(63)]);
+                self.emit_instr(Opcode::Add, [rax, rdx]);
+            }
+            return true;
+        }
+
+        let d = d as u64;
+        if d == 0 {
+            return false;
+        }
+        if d == 1 {
+            self.gen_load(lhs, "rax");
+        } else if let Some(k) = strength::log2_exact(d) {
+            self.gen_load(lhs, "rax");
+            self.emit_instr(Opcode::Shr, [rax, imm(k as i64)]);
+        } else if d >= 1 << 63 {
+            // The quotient can only be 0 or 1
+            self.gen_load(lhs, "rax");
+            self.emit_instr(Opcode::Mov, [reg("r11"), imm(d as i64)]);
+            self.emit_instr(Opcode::Cmp, [rax.clone(), reg("r11")]);
+            self.emit_instr(Opcode::Set(Cond::Ae), [asm::Operand::Reg("rax", 1)]);
+            self.emit_instr(Opcode::Movzx, [rax, asm::Operand::Reg("rax", 1)]);
+        } else {
+            let magic = strength::unsigned_magic(d);
+            let dividend = self.register_or_memory(lhs, "r11");
+            self.emit_instr(Opcode::Mov, [rax.clone(), imm(magic.multiplier as i64)]);
+            self.emit_instr(Opcode::Mul, [dividend.clone()]);
+            if magic.add {
+                // The multiplier's 65th bit: (((n - hi) >> 1) + hi) >> (s - 1)
+                self.emit_instr(Opcode::Mov, [rax.clone(), dividend]);
+                self.emit_instr(Opcode::Sub, [rax.clone(), rdx.clone()]);
+                self.emit_instr(Opcode::Shr, [rax.clone(), imm(1)]);
+                self.emit_instr(Opcode::Add, [rax.clone(), rdx]);
+                if magic.shift > 1 {
+                    self.emit_instr(Opcode::Shr, [rax, imm(magic.shift as i64 - 1)]);
+                }
+            } else {
+                if magic.shift > 0 {
+                    self.emit_instr(Opcode::Shr, [rdx.clone(), imm(magic.shift as i64)]);
+                }
+                self.emit_instr(Opcode::Mov, [rax, rdx]);
+            }
+        }
+        true
+    }
+
+    /// Compares `lhs` with `rhs`, which have type `ty`, returning the
+    /// condition under which `lhs op rhs` holds.
+    fn gen_compare(&mut self, op: &Op, ty: Type, lhs: &Operand, rhs: &Operand) -> Condition {
+        if ty.is_float() {
+            let lhs = self.register_or_memory(lhs, "rax");
+            self.emit_instr(Opcode::Movq, [asm::Operand::Xmm(0), lhs]);
+            let rhs = self.register_or_memory(rhs, "r11");
+            self.emit_instr(Opcode::Movq, [asm::Operand::Xmm(1), rhs]);
+            // ucomisd reports "unordered" (a NaN operand) through PF and CF,
+            // so `<`/`<=` swap operands and test with a/ae, which are false
+            // on NaN
+            let (swap, condition) = match op {
+                Op::Eq => (false, Condition::FloatEq),
+                Op::NotEq => (false, Condition::FloatNotEq),
+                Op::Gt => (false, Condition::Code(Cond::A)),
+                Op::Gte => (false, Condition::Code(Cond::Ae)),
+                Op::Lt => (true, Condition::Code(Cond::A)),
+                Op::Lte => (true, Condition::Code(Cond::Ae)),
+                _ => unreachable!(),
+            };
+            let (a, b) = if swap { (1, 0) } else { (0, 1) };
+            self.emit_instr(
+                Opcode::Ucomisd,
+                [asm::Operand::Xmm(a), asm::Operand::Xmm(b)],
+            );
+            return condition;
+        }
+
+        let code = match (op, ty.is_signed()) {
+            (Op::Eq, _) => Cond::E,
+            (Op::NotEq, _) => Cond::Ne,
+            (Op::Gt, true) => Cond::G,
+            (Op::Gte, true) => Cond::Ge,
+            (Op::Lt, true) => Cond::L,
+            (Op::Lte, true) => Cond::Le,
+            (Op::Gt, false) => Cond::A,
+            (Op::Gte, false) => Cond::Ae,
+            (Op::Lt, false) => Cond::B,
+            (Op::Lte, false) => Cond::Be,
+            _ => unreachable!(),
+        };
+        let lhs = match self.register_of(lhs) {
+            Some(lhs) => lhs,
+            None => {
+                self.gen_load(lhs, "rax");
+                "rax"
+            }
+        };
+        let rhs = self.source(rhs, "r11");
+        self.emit_instr(Opcode::Cmp, [reg(lhs), rhs]);
+        Condition::Code(code)
+    }
+
+    /// Sets `dst` to `then_value` if the flags hold `condition`, and to
+    /// `else_value` otherwise, with conditional moves instead of a branch.
+    fn gen_select(
+        &mut self,
+        dst: ir::Value,
+        condition: Condition,
+        then_value: &Operand,
+        else_value: &Operand,
+    ) {
+        let dst_reg = self.dst_register(dst);
+        let in_dst = |value: &Operand| self.register_of(value) == Some(dst_reg);
+        // Start from the operand that may already be in place, so that loading
+        // it does not overwrite the other one
+        let (condition, then_value, else_value) = match condition {
+            Condition::Code(code) if in_dst(then_value) && !in_dst(else_value) => {
+                (Condition::Code(code.negate()), else_value, then_value)
+            }
+            _ => (condition, then_value, else_value),
+        };
+        // Float `==` reads the else value again after the first move
+        let target = if in_dst(then_value)
+            || matches!(condition, Condition::FloatEq) && in_dst(else_value)
+        {
+            "rax"
+        } else {
+            dst_reg
+        };
+        self.gen_load(else_value, target);
+        let then_value = self.register_or_memory(then_value, "r11");
+        match condition {
+            Condition::Code(code) => {
+                self.emit_instr(Opcode::Cmov(code), [reg(target), then_value]);
+            }
+            // Equal needs ZF set and PF clear
+            Condition::FloatEq => {
+                self.emit_instr(Opcode::Cmov(Cond::E), [reg(target), then_value]);
+                let else_value = self.register_or_memory(else_value, "rdx");
+                self.emit_instr(Opcode::Cmov(Cond::P), [reg(target), else_value]);
+            }
+            Condition::FloatNotEq => {
+                self.emit_instr(Opcode::Cmov(Cond::Ne), [reg(target), then_value.clone()]);
+                self.emit_instr(Opcode::Cmov(Cond::P), [reg(target), then_value]);
+            }
+        }
+        self.gen_store(dst, target);
+    }
+
+    /// Materialises `condition` as a 0 or 1 in `dst`.
+    fn gen_set_condition(&mut self, dst: ir::Value, condition: Condition) {
+        let dst_reg = self.dst_register(dst);
+        let (al, dl) = (asm::Operand::Reg("rax", 1), asm::Operand::Reg("rdx", 1));
+        let low = match condition {
+            Condition::Code(code) => {
+                let low = asm::Operand::Reg(dst_reg, 1);
+                self.emit_instr(Opcode::Set(code), [low.clone()]);
+                low
+            }
+            Condition::FloatEq => {
+                self.emit_instr(Opcode::Set(Cond::E), [al.clone()]);
+                self.emit_instr(Opcode::Set(Cond::Np), [dl.clone()]);
+                self.emit_instr(Opcode::And, [al.clone(), dl]);
+                al
+            }
+            Condition::FloatNotEq => {
+                self.emit_instr(Opcode::Set(Cond::Ne), [al.clone()]);
+                self.emit_instr(Opcode::Set(Cond::P), [dl.clone()]);
+                self.emit_instr(Opcode::Or, [al.clone(), dl]);
+                al
+            }
+        };
+        self.emit_instr(Opcode::Movzx, [reg(dst_reg), low]);
+        self.gen_store(dst, dst_reg);
+    }
+
+    /// Emits SSE2 arithmetic on the f64 bit patterns of `lhs` and `rhs`.
+    fn gen_float_binop(&mut self, dst: ir::Value, op: &Op, lhs: &Operand, rhs: &Operand) {
+        let lhs = self.register_or_memory(lhs, "rax");
+        self.emit_instr(Opcode::Movq, [asm::Operand::Xmm(0), lhs]);
+        let rhs = self.register_or_memory(rhs, "r11");
+        self.emit_instr(Opcode::Movq, [asm::Operand::Xmm(1), rhs]);
+        let dst_reg = self.dst_register(dst);
+        let opcode = match op {
+            Op::Add => Opcode::Addsd,
+            Op::Sub => Opcode::Subsd,
+            Op::Mul => Opcode::Mulsd,
+            Op::Div => Opcode::Divsd,
+            _ => unreachable!("comparisons are emitted by gen_compare"),
+        };
+        self.emit_instr(opcode, [asm::Operand::Xmm(0), asm::Operand::Xmm(1)]);
+        self.emit_instr(Opcode::Movq, [reg(dst_reg), asm::Operand::Xmm(0)]);
+        self.gen_store(dst, dst_reg);
+    }
+
+    /// Wraps the value in `target` to the width of `ty`, sign- or
+    /// zero-extending it back to 64 bits.
+    fn gen_truncate(&mut self, ty: Type, target: &'static str) {
+        let (op, size) = match ty {
+            Type::I8 => (Opcode::Movsx, 1),
+            Type::U8 | Type::Bool => (Opcode::Movzx, 1),
+            Type::I16 => (Opcode::Movsx, 2),
+            Type::U16 => (Opcode::Movzx, 2),
+            Type::I32 => (Opcode::Movsxd, 4),
+            // Writing a 32-bit register clears the upper half
+            Type::U32 => {
+                let low = asm::Operand::Reg(target, 4);
+                self.emit_instr(Opcode::Mov, [low.clone(), low]);
+                return;
             }
+            Type::I64 | Type::U64 | Type::F64 | Type::Unit => return,
+        };
+        self.emit_instr(op, [reg(target), asm::Operand::Reg(target, size)]);
+    }
+}
+
+/// The whole of the 64-bit register `name`.
+fn reg(name: &'static str) -> asm::Operand {
+    asm::Operand::reg(name)
+}
+
+fn label(name: String) -> asm::Operand {
+    asm::Operand::Label(name)
+}
+
+/// How the outcome of a comparison is read from the flags it sets.
+#[derive(Debug, Clone, Copy)]
+enum Condition {
+    /// A single condition code.
+    Code(Cond),
+    /// Float `==`, which also needs the operands to be ordered.
+    FloatEq,
+    /// Float `!=`, which also holds when the operands are unordered.
+    FloatNotEq,
+}
+
+/// How many times each value of `f` is read.
+fn use_counts(f: &ir::Function) -> Vec<usize> {
+    let mut uses = vec![0; f.values.len()];
+    for block in &f.blocks {
+        let operands = block
+            .instrs
+            .iter()
+            .flat_map(Instr::operands)
+            .chain(block.term.operands());
+        for value in operands.filter_map(|operand| operand.as_value()) {
+            uses[value.0] += 1;
+        }
+    }
+    uses
+}
+
+/// The index of the call in `block` whose value the block returns, with
+/// nothing left to do in between, which can then jump to the callee instead.
+/// The entry function has no caller to return to, so it makes no tail calls.
+fn tail_call(f: &ir::Function, block: &ir::Block) -> Option<usize> {
+    let Some(Instr::Call { dst, .. }) = block.instrs.last() else {
+        return None;
+    };
+    let returns_call = match (&block.term, dst) {
+        (Terminator::Return(Some(Operand::Value(value))), Some(dst)) => value == dst,
+        (Terminator::Return(None), _) => true,
+        _ => false,
+    };
+    (returns_call && !f.entry).then(|| block.instrs.len() - 1)
+}
+
+/// The index of the comparison in `block` that can set the flags for its
+/// branch directly: one whose result is read only by the branch, followed by
+/// nothing but copies, which leave the flags alone.
+fn fused_comparison(block: &ir::Block, uses: &[usize]) -> Option<usize> {
+    let Terminator::Branch {
+        cond: Operand::Value(cond),
+        ..
+    } = block.term
+    else {
+        return None;
+    };
+    if uses[cond.0] != 1 {
+        return None;
+    }
+    let (i, instr) = block
+        .instrs
+        .iter()
+        .enumerate()
+        .rev()
+        .find(|(_, instr)| !matches!(instr, Instr::Copy { .. }))?;
+    match instr {
+        Instr::Binary { dst, op, .. } if *dst == cond && op.is_comparison() => {
+            // A copy after it must not overwrite the condition
+            let rewritten = block.instrs[i + 1..]
+                .iter()
+                .any(|copy| copy.dst() == Some(cond));
+            (!rewritten).then_some(i)
+        }
+        _ => None,
+    }
+}
+
+/// The indices of comparisons in `block` that can set the flags for the
+/// selects right after them: ones read by nothing else, which keeps them from
+/// having to be materialised. Loading a select's operands leaves the flags
+/// alone, so several selects can share a comparison.
+fn fused_selects(block: &ir::Block, uses: &[usize]) -> Vec<usize> {
+    let mut fused = Vec::new();
+    for (i, instr) in block.instrs.iter().enumerate() {
+        let Instr::Binary { dst, op, .. } = instr else {
+            continue;
+        };
+        let cond = Operand::Value(*dst);
+        let readers = block.instrs[i + 1..].iter().take_while(|next| {
+            matches!(next, Instr::Select { cond: c, then_value, else_value, .. }
+                if *c == cond && *then_value != cond && *else_value != cond)
+        });
+        if op.is_comparison() && uses[dst.0] > 0 && readers.count() == uses[dst.0] {
+            fused.push(i);
         }
     }
+    fused
 }
 
 impl Default for CodeGen {
@@ -298,42 +972,67 @@ mod comparison_tests {
     use super::*;
     use crate::lexer::Lexer;
     use crate::parser::Parser;
+    use crate::resolve::Resolver;
 
     #[test]
     fn test_comparison_eq() {
         let source = "let x = 5 == 5; exit(x);";
         let tokens = Lexer::new(source).tokenize();
-        let stmts = Parser::new(tokens).parse();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
         let asm = CodeGen::new().generate(&stmts);
 
-        assert!(asm.contains("cmp rax, rbx"));
-        assert!(asm.contains("sete al"));
-        assert!(asm.contains("movzx rax, al"));
+        assert!(asm.contains("cmp rax, 5"));
+        assert!(asm.contains("sete cl"));
+        assert!(asm.contains("movzx rcx, cl"));
     }
 
     #[test]
     fn test_comparison_gt() {
         let source = "let x = 10 > 5; exit(x);";
         let tokens = Lexer::new(source).tokenize();
-        let stmts = Parser::new(tokens).parse();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
         let asm = CodeGen::new().generate(&stmts);
 
-        assert!(asm.contains("cmp rax, rbx"));
-        assert!(asm.contains("setg al"));
+        assert!(asm.contains("cmp rax, 5"));
+        assert!(asm.contains("setg cl"));
     }
 
     #[test]
     fn test_while_loop() {
-        let source = "let x = 0; while (x < 5) { let x = x + 1; } exit(x);";
+        let source = "let mut x = 0; while (x < 5) { x = x + 1; } exit(x);";
         let tokens = Lexer::new(source).tokenize();
-        let stmts = Parser::new(tokens).parse();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
         let asm = CodeGen::new().generate(&stmts);
 
         // Should contain while loop structure
         assert!(asm.contains(".while_start_"));
         assert!(asm.contains(".while_end_"));
         assert!(asm.contains("jmp .while_start_"));
-        assert!(asm.contains("je .while_end_"));
+        // The comparison branches on its own flags
+        assert!(asm.contains("cmp rcx, 5\n    jge .while_end_"));
+        assert!(!asm.contains("setl"));
+    }
+
+    #[test]
+    fn test_fused_branches() {
+        let source = "fn f(a: u64, x: f64) -> bool { let big = a > 9; if (a <= 3) { return big; } elif (x == 1.0) { return true; } return false; } exit(f(2, 1.0) as u8);";
+        let tokens = Lexer::new(source).tokenize();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
+        crate::typeck::TypeChecker::new().check(&mut stmts).unwrap();
+        let asm = CodeGen::new().generate(&stmts);
+
+        // `big` is a value, so it is still materialised
+        assert!(asm.contains("seta "));
+        assert!(asm.contains("cmp rdi, 3\n    ja .elif_"));
+        assert!(!asm.contains("setbe"));
+        // Float equality also needs the operands to be ordered
+        assert!(asm.contains("ucomisd xmm0, xmm1\n    jne .if_end_"));
+        assert!(asm.contains("jp .if_end_"));
+        assert!(!asm.contains("setnp"));
     }
 }
 
@@ -342,16 +1041,17 @@ mod tests {
     use super::*;
     use crate::lexer::Lexer;
     use crate::parser::Parser;
+    use crate::resolve::Resolver;
 
     #[test]
     fn test_simple_exit() {
         let source = "exit(42);";
         let tokens = Lexer::new(source).tokenize();
-        let stmts = Parser::new(tokens).parse();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
         let asm = CodeGen::new().generate(&stmts);
 
-        assert!(asm.contains("mov rax, 42"));
-        assert!(asm.contains("mov rdi, rax"));
+        assert!(asm.contains("mov rdi, 42"));
         assert!(asm.contains("mov rax, 60"));
         assert!(asm.contains("syscall"));
     }
@@ -360,23 +1060,162 @@ mod tests {
     fn test_let_and_exit() {
         let source = "let x = 10; exit(x);";
         let tokens = Lexer::new(source).tokenize();
-        let stmts = Parser::new(tokens).parse();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
         let asm = CodeGen::new().generate(&stmts);
 
-        assert!(asm.contains("mov rax, 10"));
-        assert!(asm.contains("mov [rbp-8], rax"));
-        assert!(asm.contains("mov rax, [rbp-8]"));
+        assert!(asm.contains("mov rcx, 10"));
+        assert!(asm.contains("mov rdi, rcx"));
+        assert!(!asm.contains("[rbp"));
     }
 
     #[test]
     fn test_arithmetic() {
         let source = "exit(2 + 3 * 4);";
         let tokens = Lexer::new(source).tokenize();
-        let stmts = Parser::new(tokens).parse();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
+        let asm = CodeGen::new().generate(&stmts);
+
+        // Multiplying by a power of two is a shift
+        assert!(asm.contains("shl rcx, 2"));
+        assert!(!asm.contains("imul"));
+        assert!(asm.contains("add rcx, 2"));
+    }
+
+    #[test]
+    fn test_sized_integers() {
+        let source =
+            "let x: u8 = 200; let y = x / 3; let b = y < x; let z = -1 as i32; exit(z as u8);";
+        let tokens = Lexer::new(source).tokenize();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
+        crate::typeck::TypeChecker::new().check(&mut stmts).unwrap();
+        let asm = CodeGen::new().generate(&stmts);
+
+        // Division by a constant multiplies by its magic number instead
+        assert!(asm.contains("mul rcx\n    shr rdx, 1\n    mov rax, rdx\n    movzx rax, al"));
+        assert!(!asm.contains("div"));
+        assert!(asm.contains("setb sil"));
+        assert!(asm.contains("movsxd rcx, ecx"));
+        assert!(asm.contains("movzx rcx, cl"));
+    }
+
+    #[test]
+    fn test_float_ops() {
+        let source =
+            "let x = 1.5; let y = x * 2.0 - 0.25; print(y < x); print(-y); exit(y as i64);";
+        let tokens = Lexer::new(source).tokenize();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
+        crate::typeck::TypeChecker::new().check(&mut stmts).unwrap();
+        let asm = CodeGen::new().generate(&stmts);
+
+        assert!(asm.contains("mov rcx, 0x3ff8000000000000 ; 1.5"));
+        assert!(asm.contains("mulsd xmm0, xmm1"));
+        assert!(asm.contains("subsd xmm0, xmm1"));
+        assert!(asm.contains("ucomisd xmm1, xmm0"));
+        assert!(asm.contains("call _crab_print_bool"));
+        assert!(asm.contains("call _crab_print_f64"));
+        assert!(asm.contains("cvttsd2si rbx, xmm0"));
+        assert!(asm.contains("_crab_print_f64:"));
+    }
+
+    #[test]
+    fn test_function_call() {
+        let source = "fn add(a: i64, b: u8) -> i64 { return a + b as i64; } exit(add(40, 2));";
+        let tokens = Lexer::new(source).tokenize();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
+        let asm = CodeGen::new().generate(&stmts);
+
+        assert!(asm.contains("fn_add:"));
+        // The arguments are used where they arrive
+        assert!(asm.contains("add rdi, rsi\n    mov rax, rdi\n    leave\n    ret"));
+        assert!(asm.contains("mov rsi, 2\n    mov rdi, 40\n    call fn_add"));
+    }
+
+    #[test]
+    fn test_tight_loop_stays_in_registers() {
+        let source =
+            "let mut i = 0; let mut s = 0; while (i < 1000) { s = s + i * 3; i = i + 1; } exit(s);";
+        let tokens = Lexer::new(source).tokenize();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
+        let asm = CodeGen::new().generate(&stmts);
+
+        let start = asm.find(".while_start_").unwrap();
+        let end = asm.rfind(".while_end_").unwrap();
+        let body = &asm[start..end];
+        assert!(!body.contains("rbp"));
+        assert!(!body.contains("push"));
+        assert!(!body.contains("pop"));
+    }
+
+    #[test]
+    fn test_callee_saved_registers_are_restored() {
+        let source = "fn f(n: i64) -> i64 { let a = n * 2; print(n); return a + n; } exit(f(1));";
+        let tokens = Lexer::new(source).tokenize();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
+        let asm = CodeGen::new().generate(&stmts);
+
+        // `n` and `a` outlive the call to print
+        assert!(asm.contains("mov qword [rbp-8], rbx\n    mov qword [rbp-16], r12"));
+        assert!(asm.contains("mov rbx, rdi"));
+        assert!(
+            asm.contains("mov rbx, qword [rbp-8]\n    mov r12, qword [rbp-16]\n    leave\n    ret")
+        );
+    }
+
+    #[test]
+    fn test_tail_calls_jump() {
+        let source = "fn f(n: i64, acc: i64) -> i64 { if (n == 0) { return acc; } return f(n - 1, acc + n); } \
+             fn g(n: i64) -> i64 { become f(n, 0); } exit(g(3));";
+        let tokens = Lexer::new(source).tokenize();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
+        let asm = CodeGen::new().generate(&stmts);
+
+        assert!(!asm.contains("call fn_f"));
+        // Both calls reuse the frame they were made from
+        assert!(asm.contains("mov rdi, rcx\n    leave\n    jmp fn_f"));
+        assert!(asm.contains("mov rsi, 0\n    leave\n    jmp fn_f"));
+        // The entry function has no frame to give up
+        assert!(asm.contains("call fn_g"));
+    }
+
+    #[test]
+    fn test_intrinsics_use_conditional_moves() {
+        let source =
+            "fn f(a: i64, b: i64) -> i64 { return max(a, b) + min(a, b) + abs(a); } exit(f(1, 2));";
+        let tokens = Lexer::new(source).tokenize();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
+        let asm = CodeGen::new().generate(&stmts);
+
+        let body = &asm[asm.find("fn_f:").unwrap()..];
+        assert!(body.contains("cmp rdi, rsi\n    mov rcx, rsi\n    cmovg rcx, rdi"));
+        assert!(body.contains("cmovl"));
+        assert!(!body.contains("    j"));
+    }
+
+    #[test]
+    fn test_strength_reduction() {
+        let source = "fn f(x: i64, y: u64) -> i64 { return x * 5 + x * 24 + x / 7 + x / -4 + (y / 10) as i64; } exit(f(1, 2) as u8);";
+        let tokens = Lexer::new(source).tokenize();
+        let mut stmts = Parser::new(tokens).parse();
+        Resolver::new().resolve(&mut stmts);
+        crate::typeck::TypeChecker::new().check(&mut stmts).unwrap();
         let asm = CodeGen::new().generate(&stmts);
 
-        // Should contain multiplication and addition operations
-        assert!(asm.contains("imul rax, rbx"));
-        assert!(asm.contains("add rax, rbx"));
+        assert!(asm.contains("lea rcx, [rcx + rcx*4]"));
+        assert!(asm.contains("*2]\n    shl "));
+        // Signed division rounds towards zero
+        assert!(asm.contains("imul rdi\n"));
+        assert!(asm.contains("shr rax, 63\n    add rax, rdx"));
+        assert!(asm.contains("sar rax, 2\n    neg rax"));
+        assert!(asm.contains("mul rsi\n"));
+        assert!(!asm.contains("div"));
     }
 }
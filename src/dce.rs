@@ -0,0 +1,316 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::{
+    diagnostic::Diagnostic,
+    lexer::Span,
+    parser::{walk_exprs_mut, DeclId, Expr, ExprKind, Op, Stmt, StmtKind},
+    typeck::INTRINSICS,
+};
+
+/// Removes code that can never run or whose result is never used.
+///
+/// Statements after one that always diverges (an `exit`, a `return`, a
+/// `while (true)`, or an `if` whose every branch diverges) are dropped with a
+/// warning. `if` arms and `while` loops whose conditions are constant are
+/// pruned, keeping only the arm that is taken. A `let` or assignment to a
+/// variable that is never read is dropped too, or reduced to its initializer
+/// when evaluating that has side effects. Run after constant folding, which
+/// turns conditions into the literals this pass looks for.
+pub struct DeadCodeEliminator {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl DeadCodeEliminator {
+    pub fn new() -> Self {
+        Self {
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Eliminates dead code in `stmts`, returning warnings about the code
+    /// that could never run.
+    pub fn eliminate(mut self, stmts: &mut Vec<Stmt>) -> Vec<Diagnostic> {
+        self.eliminate_block(stmts);
+        while remove_unread_stores(stmts) {}
+        self.diagnostics.sort_by_key(|d| d.span.start);
+        self.diagnostics
+    }
+
+    fn eliminate_block(&mut self, stmts: &mut Vec<Stmt>) {
+        let mut queue: VecDeque<Stmt> = std::mem::take(stmts).into();
+        // The statement control never gets past, and the span of the code after it
+        let mut diverged: Option<(Span, Option<Span>)> = None;
+        while let Some(mut stmt) = queue.pop_front() {
+            if let Some((_, unreachable)) = &mut diverged {
+                // Functions are declarations, not code that runs in order
+                if !matches!(stmt.kind, StmtKind::Fn(_)) {
+                    *unreachable = Some(unreachable.map_or(stmt.span, |span| span.to(stmt.span)));
+                    continue;
+                }
+            }
+            match self.simplify(&mut stmt) {
+                Simplified::Keep => {}
+                Simplified::Drop => continue,
+                Simplified::Inline(body) => {
+                    for inner in body.into_iter().rev() {
+                        queue.push_front(inner);
+                    }
+                    continue;
+                }
+            }
+            if diverged.is_none() && diverges(&stmt) {
+                diverged = Some((stmt.span, None));
+            }
+            stmts.push(stmt);
+        }
+        if let Some((at, Some(unreachable))) = diverged {
+            self.diagnostics.push(
+                Diagnostic::warning(unreachable, "unreachable statement")
+                    .with_note(at, "any code following this statement is unreachable"),
+            );
+        }
+    }
+
+    fn simplify(&mut self, stmt: &mut Stmt) -> Simplified {
+        match &mut stmt.kind {
+            StmtKind::While(cond, _) if cond.kind == ExprKind::Bool(false) => Simplified::Drop,
+            StmtKind::While(_, body) => {
+                self.eliminate_block(body);
+                Simplified::Keep
+            }
+            StmtKind::If(cond, then_body, elif_branches, else_body) => {
+                let arms = std::iter::once((cond.clone(), std::mem::take(then_body)))
+                    .chain(std::mem::take(elif_branches));
+                let mut kept: Vec<(Expr, Vec<Stmt>)> = Vec::new();
+                let mut otherwise = else_body.take();
+                for (arm_cond, body) in arms {
+                    match arm_cond.kind {
+                        ExprKind::Bool(false) => {}
+                        // Later arms can't be reached and become the else branch
+                        ExprKind::Bool(true) => {
+                            otherwise = Some(body);
+                            break;
+                        }
+                        _ => kept.push((arm_cond, body)),
+                    }
+                }
+                if kept.is_empty() {
+                    return match otherwise {
+                        Some(body) => Simplified::Inline(body),
+                        None => Simplified::Drop,
+                    };
+                }
+                let mut arms = kept.into_iter();
+                let (first_cond, mut first_body) = arms.next().unwrap();
+                self.eliminate_block(&mut first_body);
+                *cond = first_cond;
+                *then_body = first_body;
+                *elif_branches = arms
+                    .map(|(arm_cond, mut body)| {
+                        self.eliminate_block(&mut body);
+                        (arm_cond, body)
+                    })
+                    .collect();
+                *else_body = otherwise.map(|mut body| {
+                    self.eliminate_block(&mut body);
+                    body
+                });
+                Simplified::Keep
+            }
+            StmtKind::Fn(f) => {
+                self.eliminate_block(&mut f.body);
+                Simplified::Keep
+            }
+            _ => Simplified::Keep,
+        }
+    }
+}
+
+impl Default for DeadCodeEliminator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+enum Simplified {
+    Keep,
+    Drop,
+    /// Replace the statement by these, in its place in the enclosing block.
+    Inline(Vec<Stmt>),
+}
+
+/// Whether control never continues past `stmt`.
+fn diverges(stmt: &Stmt) -> bool {
+    match &stmt.kind {
+        StmtKind::Exit(_) | StmtKind::Return(_) | StmtKind::Become(_) => true,
+        // There is no `break`, so only an exit or return leaves this loop
+        StmtKind::While(cond, _) => cond.kind == ExprKind::Bool(true),
+        StmtKind::If(_, then_body, elif_branches, Some(else_body)) => {
+            block_diverges(then_body)
+                && elif_branches.iter().all(|(_, body)| block_diverges(body))
+                && block_diverges(else_body)
+        }
+        _ => false,
+    }
+}
+
+fn block_diverges(stmts: &[Stmt]) -> bool {
+    stmts.iter().any(diverges)
+}
+
+/// Drops `let`s and assignments whose variable is never read, returning
+/// whether anything changed. Dropping one can leave others unread, so this
+/// is repeated until it has no effect.
+fn remove_unread_stores(stmts: &mut Vec<Stmt>) -> bool {
+    let mut read = HashSet::new();
+    walk_exprs_mut(stmts, &mut |expr| {
+        if let ExprKind::Ident(name) = &expr.kind {
+            read.extend(name.decl);
+        }
+    });
+    remove_stores(stmts, &read)
+}
+
+fn remove_stores(stmts: &mut Vec<Stmt>, read: &HashSet<DeclId>) -> bool {
+    let mut changed = false;
+    stmts.retain_mut(|stmt| {
+        let (name, expr) = match &mut stmt.kind {
+            StmtKind::Let(name, _, expr) | StmtKind::Assign(name, expr) => (name, expr),
+            StmtKind::While(_, body) => {
+                changed |= remove_stores(body, read);
+                return true;
+            }
+            StmtKind::If(_, then_body, elif_branches, else_body) => {
+                changed |= remove_stores(then_body, read);
+                for (_, body) in elif_branches {
+                    changed |= remove_stores(body, read);
+                }
+                if let Some(body) = else_body {
+                    changed |= remove_stores(body, read);
+                }
+                return true;
+            }
+            StmtKind::Fn(f) => {
+                changed |= remove_stores(&mut f.body, read);
+                return true;
+            }
+            _ => return true,
+        };
+        if name.decl.is_some_and(|decl| read.contains(&decl)) {
+            return true;
+        }
+        changed = true;
+        if is_pure(expr) {
+            return false;
+        }
+        // Keep the side effects of evaluating the value
+        let expr = expr.clone();
+        stmt.kind = StmtKind::Expr(expr);
+        true
+    });
+    changed
+}
+
+/// Whether evaluating `expr` can be skipped without changing what the
+/// program does: it calls nothing and cannot trap.
+fn is_pure(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Ident(_) | ExprKind::Num(_) | ExprKind::Float(_) | ExprKind::Bool(_) => true,
+        ExprKind::Call(name, args) => {
+            INTRINSICS.iter().any(|(n, _)| n == name) && args.iter().all(is_pure)
+        }
+        // Integer division traps on zero, and on `i64::MIN / -1`
+        ExprKind::BinOp(left, Op::Div, right) => {
+            let safe_divisor = match right.kind {
+                ExprKind::Num(n) => n != 0 && n != -1,
+                ExprKind::Float(_) => true,
+                _ => right.ty.is_some_and(|ty| ty.is_float()),
+            };
+            safe_divisor && is_pure(left) && is_pure(right)
+        }
+        ExprKind::BinOp(left, _, right) => is_pure(left) && is_pure(right),
+        ExprKind::UnaryOp(_, inner) | ExprKind::Cast(inner, _) => is_pure(inner),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::CodeGen,
+        fold::ConstFolder,
+        test_util::{checked, render},
+    };
+
+    fn eliminate(source: &str) -> (Vec<Stmt>, Vec<String>) {
+        let mut stmts = checked(source);
+        ConstFolder::new().fold(&mut stmts);
+        let warnings = render(&DeadCodeEliminator::new().eliminate(&mut stmts), source);
+        (stmts, warnings)
+    }
+
+    #[test]
+    fn test_code_after_exit() {
+        let (stmts, warnings) =
+            eliminate("let x = 3; print(x); exit(1); print(2); fn f() { return; print(4); }");
+        assert_eq!(stmts.len(), 3);
+        assert!(matches!(stmts[1].kind, StmtKind::Exit(_)));
+        assert_eq!(
+            warnings,
+            [
+                "unreachable statement @ print(2); (any code following this statement is unreachable @ exit(1);)",
+                "unreachable statement @ print(4); (any code following this statement is unreachable @ return;)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_constant_conditions() {
+        let (stmts, warnings) = eliminate(
+            "let mut x = 5; x = x + 1; if (1 == 0) { print(1); } elif (x > 2) { print(2); } elif (true) { print(3); } else { print(4); }",
+        );
+        assert!(warnings.is_empty());
+        match &stmts[2].kind {
+            StmtKind::If(_, then_body, elifs, Some(else_body)) => {
+                assert!(
+                    matches!(&then_body[0].kind, StmtKind::Print(e) if e.kind == ExprKind::Num(2))
+                );
+                assert!(elifs.is_empty());
+                assert!(
+                    matches!(&else_body[0].kind, StmtKind::Print(e) if e.kind == ExprKind::Num(3))
+                );
+            }
+            other => panic!("expected an if, found {:?}", other),
+        }
+
+        let (stmts, warnings) = eliminate(
+            "while (1 > 2) { print(0); } if (2 > 1) { exit(7); } else { exit(8); } print(9);",
+        );
+        assert_eq!(warnings, ["unreachable statement @ print(9); (any code following this statement is unreachable @ exit(7);)"]);
+        assert_eq!(stmts.len(), 1);
+        let asm = CodeGen::new().generate(&stmts);
+        assert!(!asm.contains("cmp"));
+        assert!(asm.contains("mov rdi, 7"));
+    }
+
+    #[test]
+    fn test_unread_lets() {
+        let (stmts, _) = eliminate(
+            "fn f() -> i64 { print(1); return 2; } let a = 5; let b = f(); let c = b; let mut d = 1; d = d + f(); let e = a / d; exit(0);",
+        );
+        let kinds: Vec<String> = stmts
+            .iter()
+            .map(|stmt| match &stmt.kind {
+                StmtKind::Fn(_) => "fn".to_string(),
+                StmtKind::Expr(_) => "expr".to_string(),
+                StmtKind::Let(name, ..) => format!("let {}", name),
+                StmtKind::Assign(name, _) => format!("{} =", name),
+                StmtKind::Exit(_) => "exit".to_string(),
+                other => format!("{:?}", other),
+            })
+            .collect();
+        // `c` goes, then `b` but not its call; `e` may divide by zero, so its
+        // initializer stays and `d` is still read
+        assert_eq!(kinds, ["fn", "expr", "let d", "d =", "expr", "exit"]);
+    }
+}
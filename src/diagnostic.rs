@@ -0,0 +1,124 @@
+use std::fmt::Write;
+
+use crate::lexer::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+/// A message about the program, tied to the source location it concerns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub span: Span,
+    pub notes: Vec<(Span, String)>,
+}
+
+impl Diagnostic {
+    pub fn error(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            level: Level::Error,
+            message: message.into(),
+            span,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            level: Level::Warning,
+            message: message.into(),
+            span,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_note(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.notes.push((span, message.into()));
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.level == Level::Error
+    }
+
+    /// Formats the diagnostic with the offending source line underlined, e.g.
+    ///
+    /// ```text
+    /// error: undefined variable `y`
+    ///   --> 1:6
+    ///   |
+    /// 1 | exit(y);
+    ///   |      ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let mut out = String::new();
+        let level = match self.level {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        };
+        writeln!(out, "{}: {}", level, self.message).unwrap();
+        render_snippet(&mut out, source, self.span);
+        for (span, note) in &self.notes {
+            writeln!(out, "note: {}", note).unwrap();
+            render_snippet(&mut out, source, *span);
+        }
+        out
+    }
+}
+
+fn render_snippet(out: &mut String, source: &str, span: Span) {
+    let start = span.start.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..]
+        .find('\n')
+        .map_or(source.len(), |i| start + i);
+    let line_no = source[..start].matches('\n').count() + 1;
+    let prefix = &source[line_start..start];
+    let col = prefix.chars().count() + 1;
+    let width = source[start..span.end.clamp(start, line_end)]
+        .chars()
+        .count()
+        .max(1);
+    let gutter = " ".repeat(line_no.to_string().len());
+
+    writeln!(out, "{} --> {}:{}", gutter, line_no, col).unwrap();
+    writeln!(out, "{} |", gutter).unwrap();
+    writeln!(out, "{} | {}", line_no, &source[line_start..line_end]).unwrap();
+    // Tabs are kept so the caret lines up however wide they are shown
+    let indent: String = prefix
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    writeln!(out, "{} | {}{}", gutter, indent, "^".repeat(width)).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let source = "let x = 1;\nexit(y);";
+        let diag = Diagnostic::error(Span::new(16, 17), "undefined variable `y`")
+            .with_note(Span::new(4, 5), "a similar name is declared here");
+        assert_eq!(
+            diag.render(source),
+            "error: undefined variable `y`\n  --> 2:6\n  |\n2 | exit(y);\n  |      ^\n\
+             note: a similar name is declared here\n  --> 1:5\n  |\n1 | let x = 1;\n  |     ^\n"
+        );
+    }
+
+    #[test]
+    fn test_render_keeps_tabs() {
+        let source = "fn f() {\n\t\tlet x = y;\n}";
+        let diag = Diagnostic::error(Span::new(19, 20), "undefined variable `y`");
+        assert_eq!(
+            diag.render(source),
+            "error: undefined variable `y`\n  --> 2:11\n  |\n2 | \t\tlet x = y;\n  | \t\t        ^\n"
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! Dominator trees and dominance frontiers over the IR's control-flow graph.
+//!
+//! Block `a` dominates block `b` when every path from the entry block to `b`
+//! passes through `a`. Immediate dominators are computed with the iterative
+//! algorithm of Cooper, Harvey and Kennedy.
+
+use crate::ir::{BlockId, Function};
+
+pub struct DomTree {
+    /// Immediate dominator of each block; `None` for the entry block and for
+    /// blocks that cannot be reached.
+    idom: Vec<Option<BlockId>>,
+    /// Reachable blocks in reverse postorder.
+    rpo: Vec<BlockId>,
+    /// Position of each block in `rpo`, if it is reachable.
+    rpo_index: Vec<Option<usize>>,
+    children: Vec<Vec<BlockId>>,
+}
+
+impl DomTree {
+    pub fn new(f: &Function) -> Self {
+        let rpo = reverse_postorder(f);
+        let mut rpo_index = vec![None; f.blocks.len()];
+        for (i, block) in rpo.iter().enumerate() {
+            rpo_index[block.0] = Some(i);
+        }
+        let preds = f.predecessors();
+
+        let mut idom: Vec<Option<BlockId>> = vec![None; f.blocks.len()];
+        idom[0] = Some(BlockId(0));
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in rpo.iter().skip(1) {
+                let mut new_idom = None;
+                for &pred in &preds[block.0] {
+                    if idom[pred.0].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => pred,
+                        Some(other) => intersect(&idom, &rpo_index, pred, other),
+                    });
+                }
+                if new_idom.is_some() && idom[block.0] != new_idom {
+                    idom[block.0] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom[0] = None;
+
+        let mut children = vec![Vec::new(); f.blocks.len()];
+        for &block in &rpo {
+            if let Some(parent) = idom[block.0] {
+                children[parent.0].push(block);
+            }
+        }
+        Self {
+            idom,
+            rpo,
+            rpo_index,
+            children,
+        }
+    }
+
+    pub fn idom(&self, block: BlockId) -> Option<BlockId> {
+        self.idom[block.0]
+    }
+
+    pub fn children(&self, block: BlockId) -> &[BlockId] {
+        &self.children[block.0]
+    }
+
+    pub fn reverse_postorder(&self) -> &[BlockId] {
+        &self.rpo
+    }
+
+    pub fn is_reachable(&self, block: BlockId) -> bool {
+        self.rpo_index[block.0].is_some()
+    }
+
+    /// Whether `a` dominates `b`. Every block dominates itself.
+    pub fn dominates(&self, a: BlockId, b: BlockId) -> bool {
+        if !self.is_reachable(a) || !self.is_reachable(b) {
+            return false;
+        }
+        let mut block = b;
+        loop {
+            if block == a {
+                return true;
+            }
+            match self.idom[block.0] {
+                Some(parent) => block = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// For each block, the blocks where its dominance ends: those it does not
+    /// strictly dominate but that have a predecessor it dominates.
+    pub fn frontiers(&self, f: &Function) -> Vec<Vec<BlockId>> {
+        let mut frontiers = vec![Vec::new(); f.blocks.len()];
+        for (block, preds) in f.predecessors().iter().enumerate() {
+            if preds.len() < 2 || !self.is_reachable(BlockId(block)) {
+                continue;
+            }
+            for &pred in preds {
+                let mut runner = pred;
+                while self.is_reachable(runner) && Some(runner) != self.idom[block] {
+                    if !frontiers[runner.0].contains(&BlockId(block)) {
+                        frontiers[runner.0].push(BlockId(block));
+                    }
+                    match self.idom[runner.0] {
+                        Some(parent) => runner = parent,
+                        None => break,
+                    }
+                }
+            }
+        }
+        frontiers
+    }
+}
+
+fn intersect(
+    idom: &[Option<BlockId>],
+    rpo_index: &[Option<usize>],
+    mut a: BlockId,
+    mut b: BlockId,
+) -> BlockId {
+    let index = |block: BlockId| rpo_index[block.0].expect("only reachable blocks are processed");
+    while a != b {
+        while index(a) > index(b) {
+            a = idom[a.0].expect("processed blocks have a dominator");
+        }
+        while index(b) > index(a) {
+            b = idom[b.0].expect("processed blocks have a dominator");
+        }
+    }
+    a
+}
+
+/// The blocks reachable from the entry, each listed before its successors
+/// except along back edges.
+pub fn reverse_postorder(f: &Function) -> Vec<BlockId> {
+    let mut visited = vec![false; f.blocks.len()];
+    let mut postorder = Vec::new();
+    // Each entry is a block and how many of its successors have been visited
+    let mut stack = vec![(BlockId(0), 0)];
+    visited[0] = true;
+    while let Some((block, next)) = stack.last_mut() {
+        let succs = f.blocks[block.0].term.successors();
+        if let Some(&succ) = succs.get(*next) {
+            *next += 1;
+            if !visited[succ.0] {
+                visited[succ.0] = true;
+                stack.push((succ, 0));
+            }
+        } else {
+            postorder.push(*block);
+            stack.pop();
+        }
+    }
+    postorder.reverse();
+    postorder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lower::lower, test_util::checked};
+
+    #[test]
+    fn test_loop_dominators_and_frontiers() {
+        let stmts = checked(
+            "let mut i = 0; while (i < 3) { if (i == 1) { print(i); } i = i + 1; } exit(i);",
+        );
+        let f = &lower(&stmts).functions[0];
+        let names: Vec<&str> = f.blocks.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "entry",
+                "while_start",
+                "while_body",
+                "if_then",
+                "if_end",
+                "while_end"
+            ]
+        );
+
+        let dom = DomTree::new(f);
+        assert_eq!(dom.idom(BlockId(1)), Some(BlockId(0)));
+        assert_eq!(dom.idom(BlockId(4)), Some(BlockId(2)));
+        assert_eq!(dom.idom(BlockId(5)), Some(BlockId(1)));
+        assert!(dom.dominates(BlockId(1), BlockId(3)));
+        assert!(!dom.dominates(BlockId(3), BlockId(4)));
+
+        let frontiers = dom.frontiers(f);
+        assert_eq!(frontiers[3], [BlockId(4)]);
+        assert_eq!(frontiers[4], [BlockId(1)]);
+        assert_eq!(frontiers[2], [BlockId(1)]);
+        assert!(frontiers[0].is_empty());
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+
+use crate::{
+    diagnostic::Diagnostic,
+    parser::{DeclId, Expr, ExprKind, Op, Stmt, StmtKind},
+    types::Type,
+};
+
+/// Evaluates constant expressions at compile time.
+///
+/// Operators whose operands are all literals are replaced by their result,
+/// computed with the same wrapping, signedness and float semantics as the
+/// generated code. An immutable binding initialized with a constant is
+/// propagated into every use, so uses of it fold too. Integer division by a
+/// constant zero, and `i64::MIN / -1`, are reported as errors instead of
+/// being folded, since they would trap at runtime.
+pub struct ConstFolder {
+    /// The value of each immutable binding whose initializer folded to a literal.
+    consts: HashMap<DeclId, ExprKind>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl ConstFolder {
+    pub fn new() -> Self {
+        Self {
+            consts: HashMap::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Folds `stmts` in place, returning the errors found. Expects the
+    /// program to be resolved and type checked.
+    pub fn fold(mut self, stmts: &mut [Stmt]) -> Vec<Diagnostic> {
+        self.fold_block(stmts);
+        self.diagnostics
+    }
+
+    fn fold_block(&mut self, stmts: &mut [Stmt]) {
+        for stmt in stmts {
+            self.fold_stmt(stmt);
+        }
+    }
+
+    fn fold_stmt(&mut self, stmt: &mut Stmt) {
+        match &mut stmt.kind {
+            StmtKind::Let(name, _, expr) => {
+                self.fold_expr(expr);
+                if let (false, Some(decl)) = (name.mutable, name.decl) {
+                    if is_literal(&expr.kind) {
+                        self.consts.insert(decl, expr.kind.clone());
+                    }
+                }
+            }
+            StmtKind::Assign(_, expr)
+            | StmtKind::Exit(expr)
+            | StmtKind::Print(expr)
+            | StmtKind::Expr(expr)
+            | StmtKind::Return(Some(expr))
+            | StmtKind::Become(expr) => self.fold_expr(expr),
+            StmtKind::Return(None) => {}
+            StmtKind::While(cond, body) => {
+                self.fold_expr(cond);
+                self.fold_block(body);
+            }
+            StmtKind::If(cond, then_body, elif_branches, else_body) => {
+                self.fold_expr(cond);
+                self.fold_block(then_body);
+                for (elif_cond, elif_body) in elif_branches {
+                    self.fold_expr(elif_cond);
+                    self.fold_block(elif_body);
+                }
+                if let Some(else_stmts) = else_body {
+                    self.fold_block(else_stmts);
+                }
+            }
+            StmtKind::Fn(f) => self.fold_block(&mut f.body),
+        }
+    }
+
+    fn fold_expr(&mut self, expr: &mut Expr) {
+        let folded = match &mut expr.kind {
+            ExprKind::Ident(name) => name.decl.and_then(|decl| self.consts.get(&decl)).cloned(),
+            ExprKind::Num(_) | ExprKind::Float(_) | ExprKind::Bool(_) => None,
+            ExprKind::BinOp(left, op, right) => {
+                self.fold_expr(left);
+                self.fold_expr(right);
+                let ty = left.ty.unwrap_or(Type::I64);
+                match division_trap(&left.kind, *op, &right.kind, ty) {
+                    Some(message) => {
+                        self.diagnostics.push(Diagnostic::error(expr.span, message));
+                        None
+                    }
+                    None => fold_binop(&left.kind, *op, &right.kind, ty),
+                }
+            }
+            ExprKind::UnaryOp(op, inner) => {
+                self.fold_expr(inner);
+                let ty = inner.ty.unwrap_or(Type::I64);
+                match (*op, &inner.kind) {
+                    (Op::Sub, ExprKind::Num(n)) => Some(ExprKind::Num(ty.wrap(n.wrapping_neg()))),
+                    (Op::Sub, ExprKind::Float(f)) => Some(ExprKind::Float(-f)),
+                    _ => None,
+                }
+            }
+            ExprKind::Cast(inner, to) => {
+                self.fold_expr(inner);
+                fold_cast(&inner.kind, *to)
+            }
+            ExprKind::Call(_, args) => {
+                for arg in args {
+                    self.fold_expr(arg);
+                }
+                None
+            }
+        };
+        if let Some(kind) = folded {
+            expr.kind = kind;
+        }
+    }
+}
+
+impl Default for ConstFolder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_literal(kind: &ExprKind) -> bool {
+    matches!(
+        kind,
+        ExprKind::Num(_) | ExprKind::Float(_) | ExprKind::Bool(_)
+    )
+}
+
+/// Why `l op r` would trap at run time, if it certainly would: integer
+/// division by zero, or of `i64::MIN` by -1.
+pub fn division_trap(l: &ExprKind, op: Op, r: &ExprKind, ty: Type) -> Option<&'static str> {
+    match (l, op, r) {
+        (_, Op::Div, ExprKind::Num(0)) => Some("attempt to divide by zero"),
+        (ExprKind::Num(i64::MIN), Op::Div, ExprKind::Num(-1)) if ty == Type::I64 => {
+            Some("attempt to compute `i64::MIN / -1`, which would overflow")
+        }
+        _ => None,
+    }
+}
+
+/// The result of `l op r` on literals whose type is `ty`, if both are literals.
+pub fn fold_binop(l: &ExprKind, op: Op, r: &ExprKind, ty: Type) -> Option<ExprKind> {
+    let kind = match (l, r) {
+        (ExprKind::Num(a), ExprKind::Num(b)) => {
+            let (a, b) = (*a, *b);
+            let ordering = if ty.is_signed() {
+                a.cmp(&b)
+            } else {
+                (a as u64).cmp(&(b as u64))
+            };
+            match op {
+                Op::Add => ExprKind::Num(ty.wrap(a.wrapping_add(b))),
+                Op::Sub => ExprKind::Num(ty.wrap(a.wrapping_sub(b))),
+                Op::Mul => ExprKind::Num(ty.wrap(a.wrapping_mul(b))),
+                Op::Div if b == 0 => return None,
+                Op::Div if ty.is_signed() => ExprKind::Num(ty.wrap(a.wrapping_div(b))),
+                Op::Div => ExprKind::Num(ty.wrap((a as u64 / b as u64) as i64)),
+                Op::Eq => ExprKind::Bool(ordering.is_eq()),
+                Op::NotEq => ExprKind::Bool(ordering.is_ne()),
+                Op::Gt => ExprKind::Bool(ordering.is_gt()),
+                Op::Gte => ExprKind::Bool(ordering.is_ge()),
+                Op::Lt => ExprKind::Bool(ordering.is_lt()),
+                Op::Lte => ExprKind::Bool(ordering.is_le()),
+            }
+        }
+        (ExprKind::Float(a), ExprKind::Float(b)) => match op {
+            Op::Add => ExprKind::Float(a + b),
+            Op::Sub => ExprKind::Float(a - b),
+            Op::Mul => ExprKind::Float(a * b),
+            Op::Div => ExprKind::Float(a / b),
+            Op::Eq => ExprKind::Bool(a == b),
+            Op::NotEq => ExprKind::Bool(a != b),
+            Op::Gt => ExprKind::Bool(a > b),
+            Op::Gte => ExprKind::Bool(a >= b),
+            Op::Lt => ExprKind::Bool(a < b),
+            Op::Lte => ExprKind::Bool(a <= b),
+        },
+        (ExprKind::Bool(a), ExprKind::Bool(b)) => match op {
+            Op::Eq => ExprKind::Bool(a == b),
+            Op::NotEq => ExprKind::Bool(a != b),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// The result of casting the literal `kind` to `to`, if it is a literal.
+pub fn fold_cast(kind: &ExprKind, to: Type) -> Option<ExprKind> {
+    let kind = match (kind, to.is_float()) {
+        (ExprKind::Num(n), true) => ExprKind::Float(*n as f64),
+        (ExprKind::Num(n), false) => ExprKind::Num(to.wrap(*n)),
+        (ExprKind::Float(f), true) => ExprKind::Float(*f),
+        (ExprKind::Float(f), false) => {
+            // cvttsd2si gives i64::MIN for NaN and out-of-range values
+            let limit = -(i64::MIN as f64);
+            let n = if f.is_nan() || *f >= limit || *f < -limit {
+                i64::MIN
+            } else {
+                *f as i64
+            };
+            ExprKind::Num(to.wrap(n))
+        }
+        (ExprKind::Bool(b), _) if to == Type::Bool => ExprKind::Bool(*b),
+        (ExprKind::Bool(b), _) => ExprKind::Num(*b as i64),
+        _ => return None,
+    };
+    Some(kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::CodeGen,
+        test_util::{checked, render},
+    };
+
+    fn fold(source: &str) -> (Vec<Stmt>, Vec<String>) {
+        let mut stmts = checked(source);
+        let errors = render(&ConstFolder::new().fold(&mut stmts), source);
+        (stmts, errors)
+    }
+
+    fn exit_code(stmts: &[Stmt]) -> &ExprKind {
+        match &stmts.last().unwrap().kind {
+            StmtKind::Exit(expr) => &expr.kind,
+            other => panic!("expected exit, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_folds_arithmetic() {
+        let (stmts, errors) = fold("exit(2 + 3 * 4);");
+        assert!(errors.is_empty());
+        assert_eq!(exit_code(&stmts), &ExprKind::Num(14));
+
+        let asm = CodeGen::new().generate(&stmts);
+        assert!(asm.contains("mov rdi, 14"));
+        assert!(!asm.contains("imul"));
+    }
+
+    #[test]
+    fn test_folding_follows_types() {
+        let (stmts, _) = fold("let a: u8 = 200 + 100; exit(a);");
+        assert_eq!(exit_code(&stmts), &ExprKind::Num(44));
+        let (stmts, _) = fold("let a: i8 = 100; exit((a * 2) as u8);");
+        assert_eq!(exit_code(&stmts), &ExprKind::Num(200));
+        let (stmts, _) = fold("let d = -7 / 2; exit(d);");
+        assert_eq!(exit_code(&stmts), &ExprKind::Num(-3));
+        let (stmts, _) = fold("let big: u64 = 0 - 1; let b = big > 1; exit(b as u8);");
+        assert_eq!(exit_code(&stmts), &ExprKind::Num(1));
+        let (stmts, _) = fold("let f = 7.9 * -1.0; exit(f as i64);");
+        assert_eq!(exit_code(&stmts), &ExprKind::Num(-7));
+    }
+
+    #[test]
+    fn test_propagates_only_immutable_bindings() {
+        let (stmts, _) = fold("let x = 4; let mut y = 5; y = y + 1; exit(x * y);");
+        match exit_code(&stmts) {
+            ExprKind::BinOp(left, Op::Mul, right) => {
+                assert_eq!(left.kind, ExprKind::Num(4));
+                assert!(matches!(right.kind, ExprKind::Ident(_)));
+            }
+            other => panic!("expected a multiplication, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_division_by_constant_zero() {
+        let (_, errors) = fold("let x = 10; let z = 5 - 5; exit(x / z); print(1 / 2);");
+        assert_eq!(errors, ["attempt to divide by zero @ x / z"]);
+        let (_, errors) = fold("let m: i64 = -9223372036854775807 - 1; exit(m / -1);");
+        assert_eq!(
+            errors,
+            ["attempt to compute `i64::MIN / -1`, which would overflow @ m / -1"]
+        );
+        // Float division by zero is well defined
+        let (_, errors) = fold("print(1.0 / 0.0);");
+        assert!(errors.is_empty());
+    }
+}
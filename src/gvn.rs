@@ -0,0 +1,294 @@
+//! Copy propagation and global value numbering over IR in SSA form.
+//!
+//! A copy, or a phi whose operands all agree, only renames a value, so its
+//! uses can read the original directly. Two pure instructions that apply the
+//! same operation to the same operands compute the same value; walking the
+//! dominator tree, a computation already made in a dominating block (or
+//! earlier in the same block) is reused instead of repeated.
+
+use std::collections::HashMap;
+
+use crate::{
+    dom::DomTree,
+    ir::{BlockId, Function, Instr, Module, Operand, Value},
+    parser::Op,
+    types::Type,
+};
+
+/// An operand that can be compared and hashed, floats by their bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum KeyOperand {
+    Value(Value),
+    Int(i64),
+    Float(u64),
+}
+
+/// What a pure instruction computes, independent of where it is stored.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Binary(Op, Type, KeyOperand, KeyOperand),
+    Neg(Type, KeyOperand),
+    Cast(Type, Type, KeyOperand),
+    Select(KeyOperand, KeyOperand, KeyOperand),
+}
+
+impl From<Operand> for KeyOperand {
+    fn from(operand: Operand) -> Self {
+        match operand {
+            Operand::Value(value) => KeyOperand::Value(value),
+            Operand::Int(n) => KeyOperand::Int(n),
+            Operand::Float(f) => KeyOperand::Float(f.to_bits()),
+        }
+    }
+}
+
+impl Key {
+    fn of(instr: &Instr) -> Option<Key> {
+        Some(match *instr {
+            Instr::Binary {
+                op, ty, lhs, rhs, ..
+            } => {
+                let (mut lhs, mut rhs) = (KeyOperand::from(lhs), KeyOperand::from(rhs));
+                // `a + b` and `b + a` are the same computation
+                if matches!(op, Op::Add | Op::Mul | Op::Eq | Op::NotEq) && rhs < lhs {
+                    std::mem::swap(&mut lhs, &mut rhs);
+                }
+                Key::Binary(op, ty, lhs, rhs)
+            }
+            Instr::Neg { ty, src, .. } => Key::Neg(ty, src.into()),
+            Instr::Cast { from, to, src, .. } => Key::Cast(from, to, src.into()),
+            Instr::Select {
+                cond,
+                then_value,
+                else_value,
+                ..
+            } => Key::Select(cond.into(), then_value.into(), else_value.into()),
+            Instr::Copy { .. } | Instr::Call { .. } | Instr::Print { .. } | Instr::Phi { .. } => {
+                return None
+            }
+        })
+    }
+}
+
+pub fn optimize_module(module: &mut Module) {
+    for f in &mut module.functions {
+        optimize(f);
+    }
+}
+
+pub fn optimize(f: &mut Function) {
+    let mut replacements = vec![None; f.values.len()];
+    propagate_copies(f, &mut replacements);
+    let dom = DomTree::new(f);
+    let mut available = Vec::new();
+    number_values(f, &dom, BlockId(0), &mut available, &mut replacements);
+    rewrite_operands(f, &replacements);
+}
+
+/// The operand `operand` stands for once every replacement is applied.
+fn resolve(replacements: &[Option<Operand>], mut operand: Operand) -> Operand {
+    while let Operand::Value(value) = operand {
+        match replacements[value.0] {
+            Some(replacement) => operand = replacement,
+            None => break,
+        }
+    }
+    operand
+}
+
+/// Drops every copy, and every phi that can only produce one operand, and
+/// records what their destinations stand for. A phi that merges a value with
+/// itself around a loop counts as trivial too.
+fn propagate_copies(f: &mut Function, replacements: &mut [Option<Operand>]) {
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &mut f.blocks {
+            block.instrs.retain(|instr| {
+                let (dst, src) = match instr {
+                    Instr::Copy { dst, src } => (*dst, resolve(replacements, *src)),
+                    Instr::Phi { dst, args } => {
+                        let mut sources = args
+                            .iter()
+                            .map(|(_, arg)| resolve(replacements, *arg))
+                            .filter(|arg| *arg != Operand::Value(*dst));
+                        let Some(first) = sources.next() else {
+                            return true;
+                        };
+                        if !sources.all(|arg| arg == first) {
+                            return true;
+                        }
+                        (*dst, first)
+                    }
+                    _ => return true,
+                };
+                if src == Operand::Value(dst) {
+                    return true;
+                }
+                replacements[dst.0] = Some(src);
+                changed = true;
+                false
+            });
+        }
+    }
+}
+
+/// Numbers the pure instructions in `block` and the blocks it dominates,
+/// replacing any whose value is already in `available`. Entries added for a
+/// block are dropped again once its subtree is done, as they do not dominate
+/// the rest of the function.
+fn number_values(
+    f: &mut Function,
+    dom: &DomTree,
+    block: BlockId,
+    available: &mut Vec<HashMap<Key, Value>>,
+    replacements: &mut [Option<Operand>],
+) {
+    let mut scope = HashMap::new();
+    let mut i = 0;
+    while i < f.blocks[block.0].instrs.len() {
+        let instr = &mut f.blocks[block.0].instrs[i];
+        if !matches!(instr, Instr::Phi { .. }) {
+            for operand in instr.operands_mut() {
+                *operand = resolve(replacements, *operand);
+            }
+        }
+        let (Some(key), Some(dst)) = (Key::of(instr), instr.dst()) else {
+            i += 1;
+            continue;
+        };
+        let existing = scope
+            .get(&key)
+            .or_else(|| available.iter().rev().find_map(|outer| outer.get(&key)));
+        match existing {
+            Some(&leader) => {
+                replacements[dst.0] = Some(Operand::Value(leader));
+                f.blocks[block.0].instrs.remove(i);
+            }
+            None => {
+                scope.insert(key, dst);
+                i += 1;
+            }
+        }
+    }
+    available.push(scope);
+    for &child in dom.children(block) {
+        number_values(f, dom, child, available, replacements);
+    }
+    available.pop();
+}
+
+fn rewrite_operands(f: &mut Function, replacements: &[Option<Operand>]) {
+    for block in &mut f.blocks {
+        let operands = block
+            .instrs
+            .iter_mut()
+            .flat_map(Instr::operands_mut)
+            .chain(block.term.operands_mut());
+        for operand in operands {
+            *operand = resolve(replacements, *operand);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::ssa_module, verify::verify};
+
+    fn optimized(source: &str) -> Function {
+        let mut module = ssa_module(source);
+        optimize_module(&mut module);
+        let f = module
+            .functions
+            .into_iter()
+            .find(|f| f.name == "f")
+            .unwrap();
+        verify(&f).unwrap();
+        f
+    }
+
+    fn count(f: &Function, pred: impl Fn(&Instr) -> bool) -> usize {
+        f.blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .filter(|instr| pred(instr))
+            .count()
+    }
+
+    #[test]
+    fn test_reuses_identical_computations() {
+        let f = optimized(
+            "fn f(x: i64, y: i64) -> i64 { return (x + y) * (y + x) - (x + y); } exit(f(1, 2) as u8);",
+        );
+        assert_eq!(
+            count(&f, |i| matches!(i, Instr::Binary { op: Op::Add, .. })),
+            1
+        );
+        assert_eq!(
+            f.to_string(),
+            "fn f(%x.0: i64, %y.1: i64) -> i64 {\n\
+             bb0: ; entry\n    %2 = add i64 %x.0, %y.1\n    %4 = mul i64 %2, %2\n    \
+             %6 = sub i64 %4, %2\n    ret %6\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_propagates_copies() {
+        let f = optimized(
+            "fn f(b: i64) -> i64 { let a = b; let mut c = a; c = c + 1; return a * c; } exit(f(1) as u8);",
+        );
+        assert_eq!(count(&f, |i| matches!(i, Instr::Copy { .. })), 0);
+        // Both `a` and `c` read `b` directly
+        assert_eq!(
+            f.to_string(),
+            "fn f(%b.0: i64) -> i64 {\n\
+             bb0: ; entry\n    %3 = add i64 %b.0, 1\n    %4 = mul i64 %b.0, %3\n    ret %4\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_reuse_needs_a_dominating_computation() {
+        let f = optimized(
+            "fn f(x: i64, c: bool) -> i64 { let mut r = x * 3; if (c) { r = r + x * 3; } else { r = x * 5 + x * 5; } return r + x * 5; } exit(f(1, true) as u8);",
+        );
+        // `x * 3` in the entry covers the one in `if_then`; neither arm
+        // dominates the return, so `x * 5` is worked out again there
+        assert_eq!(
+            count(&f, |i| matches!(
+                i,
+                Instr::Binary {
+                    op: Op::Mul,
+                    rhs: Operand::Int(3),
+                    ..
+                }
+            )),
+            1
+        );
+        assert_eq!(
+            count(&f, |i| matches!(
+                i,
+                Instr::Binary {
+                    op: Op::Mul,
+                    rhs: Operand::Int(5),
+                    ..
+                }
+            )),
+            2
+        );
+    }
+
+    #[test]
+    fn test_removes_phis_of_one_value() {
+        let f = optimized(
+            "fn f(y: i64, c: bool) -> i64 { let mut x = 0; if (c) { x = y; } else { x = y; } return x; } exit(f(1, true) as u8);",
+        );
+        assert_eq!(count(&f, |i| matches!(i, Instr::Phi { .. })), 0);
+        assert_eq!(
+            f.blocks.last().unwrap().term,
+            crate::ir::Terminator::Return(Some(Operand::Value(Value(0))))
+        );
+    }
+}
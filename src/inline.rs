@@ -0,0 +1,374 @@
+//! Inlining of small functions at their call sites, over IR in SSA form.
+//!
+//! Functions are visited callees first, so a body copied into a caller
+//! already has its own small calls inlined. A call is replaced with a copy of
+//! the callee's blocks when the callee is marked `#[inline(always)]`, or is
+//! not marked `#[inline(never)]` and has at most `threshold` instructions.
+//! A callee that can call back into its caller is never inlined there, even
+//! when marked `always`, so recursion cannot unroll without end.
+
+use crate::{
+    ir::{BlockId, Function, Instr, Module, Operand, Terminator, Value},
+    parser::Inline,
+};
+
+/// How many instructions, terminators included, a function may have for its
+/// calls to be inlined when it carries no attribute.
+pub const DEFAULT_THRESHOLD: usize = 12;
+
+pub fn inline_module(module: &mut Module, threshold: usize) {
+    let calls = call_graph(module);
+    for caller in bottom_up_order(&calls) {
+        let mut layout: Vec<BlockId> = (0..module.functions[caller].blocks.len())
+            .map(BlockId)
+            .collect();
+        // Blocks still to be searched for calls; copied bodies are not
+        // searched again, as their callee already was
+        let mut pending = layout.clone();
+        pending.reverse();
+        while let Some(block) = pending.pop() {
+            let f = &module.functions[caller];
+            let site =
+                f.blocks[block.0]
+                    .instrs
+                    .iter()
+                    .enumerate()
+                    .find_map(|(i, instr)| match instr {
+                        Instr::Call { func, .. } => {
+                            let callee = module.functions.iter().position(|g| g.name == *func)?;
+                            should_inline(module, &calls, caller, callee, threshold)
+                                .then_some((i, callee))
+                        }
+                        _ => None,
+                    });
+            let Some((i, callee)) = site else {
+                continue;
+            };
+            let callee = module.functions[callee].clone();
+            let f = &mut module.functions[caller];
+            let first = BlockId(f.blocks.len());
+            let rest = inline_call(f, block, i, &callee);
+            let at = layout.iter().position(|&b| b == block).unwrap() + 1;
+            let copied = (first.0..f.blocks.len()).map(BlockId);
+            layout.splice(at..at, copied);
+            pending.extend(rest);
+        }
+        let f = &mut module.functions[caller];
+        f.reorder_blocks(&layout);
+        // A callee that never returns leaves the code after its call dead
+        f.remove_unreachable_blocks();
+    }
+}
+
+fn should_inline(
+    module: &Module,
+    calls: &[Vec<usize>],
+    caller: usize,
+    callee: usize,
+    threshold: usize,
+) -> bool {
+    let f = &module.functions[callee];
+    let allowed = match f.inline {
+        Inline::Always => true,
+        Inline::Never => false,
+        Inline::Auto => size(f) <= threshold,
+    };
+    allowed && !f.entry && !reaches(calls, callee, caller)
+}
+
+/// Instructions and terminators in `f`.
+fn size(f: &Function) -> usize {
+    f.blocks.iter().map(|block| block.instrs.len() + 1).sum()
+}
+
+/// For each function, the functions it calls.
+fn call_graph(module: &Module) -> Vec<Vec<usize>> {
+    module
+        .functions
+        .iter()
+        .map(|f| {
+            let mut callees: Vec<usize> = f
+                .blocks
+                .iter()
+                .flat_map(|block| &block.instrs)
+                .filter_map(|instr| match instr {
+                    Instr::Call { func, .. } => {
+                        module.functions.iter().position(|g| g.name == *func)
+                    }
+                    _ => None,
+                })
+                .collect();
+            callees.sort();
+            callees.dedup();
+            callees
+        })
+        .collect()
+}
+
+/// Whether `from` can lead to a call of `to`, directly or not. A function
+/// reaches itself.
+fn reaches(calls: &[Vec<usize>], from: usize, to: usize) -> bool {
+    let mut visited = vec![false; calls.len()];
+    let mut stack = vec![from];
+    while let Some(f) = stack.pop() {
+        if f == to {
+            return true;
+        }
+        if !std::mem::replace(&mut visited[f], true) {
+            stack.extend(&calls[f]);
+        }
+    }
+    false
+}
+
+/// Every function, each after the functions it calls except around cycles.
+fn bottom_up_order(calls: &[Vec<usize>]) -> Vec<usize> {
+    let mut visited = vec![false; calls.len()];
+    let mut order = Vec::new();
+    for root in 0..calls.len() {
+        if visited[root] {
+            continue;
+        }
+        visited[root] = true;
+        // Each entry is a function and how many of its callees were visited
+        let mut stack = vec![(root, 0)];
+        while let Some((f, next)) = stack.last_mut() {
+            if let Some(&callee) = calls[*f].get(*next) {
+                *next += 1;
+                if !visited[callee] {
+                    visited[callee] = true;
+                    stack.push((callee, 0));
+                }
+            } else {
+                order.push(*f);
+                stack.pop();
+            }
+        }
+    }
+    order
+}
+
+/// Replaces the call at instruction `index` of `block` with a copy of
+/// `callee`'s blocks, appended to `f`. The instructions after the call move
+/// to a new block that the copied returns jump to, where a phi collects the
+/// returned value. Returns that block, unless the call was a tail call: then
+/// the copied returns stay returns, so tail calls in the callee stay tail
+/// calls too.
+fn inline_call(
+    f: &mut Function,
+    block: BlockId,
+    index: usize,
+    callee: &Function,
+) -> Option<BlockId> {
+    let rest = f.blocks[block.0].instrs.split_off(index + 1);
+    let Some(Instr::Call { dst, args, .. }) = f.blocks[block.0].instrs.pop() else {
+        unreachable!("inlined instruction is a call");
+    };
+    let tail = rest.is_empty()
+        && match f.blocks[block.0].term {
+            Terminator::Return(Some(value)) => Some(value) == dst.map(Operand::Value),
+            Terminator::Return(None) => dst.is_none(),
+            _ => false,
+        };
+
+    // Parameters become the arguments; every other value gets a new number
+    let values: Vec<Operand> = callee
+        .values
+        .iter()
+        .enumerate()
+        .map(
+            |(value, info)| match callee.params.iter().position(|p| p.0 == value) {
+                Some(param) => args[param],
+                None => Operand::Value(f.new_value(info.ty, info.name.clone())),
+            },
+        )
+        .collect();
+    let map_value = |value: Value| match values[value.0] {
+        Operand::Value(value) => value,
+        _ => unreachable!("only parameters become constants, and they are never assigned"),
+    };
+
+    let base = f.blocks.len();
+    let after = BlockId(base + callee.blocks.len());
+    let mut returned = Vec::new();
+    for (id, callee_block) in callee.blocks.iter().enumerate() {
+        let mut copy = callee_block.clone();
+        copy.name = format!("{}_{}", callee.name, copy.name);
+        for instr in &mut copy.instrs {
+            for operand in instr.operands_mut() {
+                if let Operand::Value(value) = *operand {
+                    *operand = values[value.0];
+                }
+            }
+            if let Some(dst) = instr.dst_mut() {
+                *dst = map_value(*dst);
+            }
+            if let Instr::Phi { args, .. } = instr {
+                for (pred, _) in args {
+                    pred.0 += base;
+                }
+            }
+        }
+        for operand in copy.term.operands_mut() {
+            if let Operand::Value(value) = *operand {
+                *operand = values[value.0];
+            }
+        }
+        for target in copy.term.successors_mut() {
+            target.0 += base;
+        }
+        if let (Terminator::Return(value), false) = (&copy.term, tail) {
+            returned.extend(value.map(|value| (BlockId(base + id), value)));
+            copy.term = Terminator::Jump(after);
+        }
+        f.blocks.push(copy);
+    }
+
+    if tail {
+        f.blocks[block.0].term = Terminator::Jump(BlockId(base));
+        return None;
+    }
+    let name = f.blocks[block.0].name.clone();
+    let cont = f.new_block(&name);
+    debug_assert_eq!(cont, after);
+    let term = std::mem::replace(&mut f.blocks[block.0].term, Terminator::Jump(BlockId(base)));
+    // Successors now come from the block holding the rest of the code
+    for succ in term.successors() {
+        for instr in &mut f.blocks[succ.0].instrs {
+            if let Instr::Phi { args, .. } = instr {
+                for (pred, _) in args {
+                    if *pred == block {
+                        *pred = cont;
+                    }
+                }
+            }
+        }
+    }
+    f.blocks[cont.0].term = term;
+    f.blocks[cont.0].instrs = rest;
+    if let Some(dst) = dst {
+        f.blocks[cont.0].instrs.insert(
+            0,
+            Instr::Phi {
+                dst,
+                args: returned,
+            },
+        );
+    }
+    Some(cont)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::ssa_module, verify::verify_module};
+
+    fn inlined(source: &str, threshold: usize) -> Module {
+        let mut module = ssa_module(source);
+        inline_module(&mut module, threshold);
+        verify_module(&module).unwrap();
+        module
+    }
+
+    /// The functions called from `name`, in order.
+    fn calls(module: &Module, name: &str) -> Vec<String> {
+        let f = module.functions.iter().find(|f| f.name == name).unwrap();
+        f.blocks
+            .iter()
+            .flat_map(|block| &block.instrs)
+            .filter_map(|instr| match instr {
+                Instr::Call { func, .. } => Some(func.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_inlines_small_functions() {
+        let module = inlined(
+            "fn sq(x: i64) -> i64 { return x * x; } fn quad(x: i64) -> i64 { return sq(sq(x)); } exit(quad(3) as u8);",
+            DEFAULT_THRESHOLD,
+        );
+        assert!(calls(&module, "quad").is_empty());
+        assert!(calls(&module, "_start").is_empty());
+        let start = &module.functions[0];
+        let muls = start
+            .blocks
+            .iter()
+            .flat_map(|block| &block.instrs)
+            .filter(|instr| matches!(instr, Instr::Binary { .. }))
+            .count();
+        assert_eq!(muls, 2);
+    }
+
+    #[test]
+    fn test_returns_meet_in_a_phi() {
+        let module = inlined(
+            "fn magnitude(x: i64) -> i64 { if (x < 0) { return -x; } return x; } exit(magnitude(-4) as u8);",
+            DEFAULT_THRESHOLD,
+        );
+        let start = &module.functions[0];
+        let phi = start
+            .blocks
+            .iter()
+            .flat_map(|block| &block.instrs)
+            .find_map(|instr| match instr {
+                Instr::Phi { args, .. } => Some(args.len()),
+                _ => None,
+            });
+        assert_eq!(phi, Some(2));
+        assert!(matches!(
+            start.blocks.last().unwrap().term,
+            Terminator::Exit(Operand::Value(_))
+        ));
+    }
+
+    #[test]
+    fn test_recursive_calls_stay() {
+        let module = inlined(
+            "fn fact(n: i64) -> i64 { if (n <= 1) { return 1; } return n * fact(n - 1); } \
+             fn even(n: i64) -> bool { if (n == 0) { return true; } return odd(n - 1); } \
+             fn odd(n: i64) -> bool { if (n == 0) { return false; } return even(n - 1); } \
+             print(even(3)); exit(fact(5) as u8);",
+            DEFAULT_THRESHOLD,
+        );
+        assert_eq!(calls(&module, "fact"), ["fact"]);
+        assert_eq!(calls(&module, "even"), ["odd"]);
+        assert_eq!(calls(&module, "odd"), ["even"]);
+        // The entry is outside both cycles, so each body is copied into it once
+        assert_eq!(calls(&module, "_start"), ["odd", "fact"]);
+    }
+
+    #[test]
+    fn test_tail_calls_stay_in_tail_position() {
+        let module = inlined(
+            "fn f(n: i64) -> i64 { if (n == 0) { return 0; } become f(n - 1); } \
+             fn g(n: i64) -> i64 { become f(n); } exit(g(3) as u8);",
+            DEFAULT_THRESHOLD,
+        );
+        let g = module.functions.iter().find(|f| f.name == "g").unwrap();
+        // The copy of `f` returns straight from `g`, so its call is still
+        // the last thing `g` does
+        assert_eq!(calls(&module, "g"), ["f"]);
+        for block in &g.blocks {
+            if let Some(Instr::Call { dst, .. }) = block.instrs.last() {
+                assert_eq!(block.term, Terminator::Return(dst.map(Operand::Value)));
+            }
+            assert!(!block.instrs.iter().any(|i| matches!(i, Instr::Phi { .. })));
+        }
+    }
+
+    #[test]
+    fn test_attributes_and_threshold() {
+        let source = "#[inline(never)] fn one() -> i64 { return 1; } \
+             #[inline(always)] fn sum(n: i64) -> i64 { let mut s = 0; let mut i = 0; while (i < n) { s = s + i; i = i + 1; } return s; } \
+             fn triple(n: i64) -> i64 { let mut s = 0; let mut i = 0; while (i < n) { s = s + i * 3; i = i + 1; } return s; } \
+             exit((one() + sum(4) + triple(2)) as u8);";
+        let module = inlined(source, DEFAULT_THRESHOLD);
+        assert_eq!(calls(&module, "_start"), ["one", "triple"]);
+        let module = inlined(source, 100);
+        assert_eq!(calls(&module, "_start"), ["one"]);
+        let module = inlined(source, 0);
+        assert_eq!(calls(&module, "_start"), ["one", "triple"]);
+    }
+}
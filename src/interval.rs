@@ -0,0 +1,643 @@
+use std::collections::HashMap;
+
+use crate::{
+    diagnostic::Diagnostic,
+    parser::{DeclId, Expr, ExprKind, Name, Op, Stmt, StmtKind},
+    types::Type,
+};
+
+/// The integers a value may take, `lo..=hi`. Bounds are the values
+/// themselves, so a `u64` above `i64::MAX` is just a large bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Interval {
+    lo: i128,
+    hi: i128,
+    /// Whether 0 is ruled out even though it lies between the bounds, as it
+    /// is for `x` where `x != 0` holds.
+    nonzero: bool,
+}
+
+/// The bounds of each integer variable at a point of the program, or `None`
+/// where control cannot get to. A variable with no entry may be anything.
+type State = Option<HashMap<DeclId, Interval>>;
+
+/// How many times a variable's bounds may grow at the head of a loop before
+/// they are widened. Until then they are joined, which gives variables that
+/// settle after a few rounds bounds tighter than the ends of their type.
+const WIDEN_DELAY: usize = 2;
+
+/// How many statements the analysis walks before it gives up, as nested
+/// loops are walked a number of times that grows with their depth.
+const MAX_STEPS: usize = 100_000;
+
+/// Warns about arithmetic that can go wrong at run time, found by tracking
+/// the range of every integer variable through the program.
+///
+/// Control flow in the AST is structured, so the program is walked as its
+/// control-flow graph: both arms of an `if` are followed and their ranges
+/// joined where they meet, with each arm narrowed by what its condition
+/// says about the variables it compares. A `while` loop is iterated until
+/// the ranges at its head stop changing, widening any bound that keeps
+/// growing to the end of its type so that this terminates, and then
+/// narrowed once more by its condition. Integer division whose divisor is
+/// always zero, or may be zero, and an exit code that is always or may be
+/// outside `0..=255`, are reported as warnings. A value nothing is known
+/// about, such as a function parameter, is not reported as possibly wrong.
+pub struct IntervalAnalysis {
+    state: State,
+    /// Off while a loop is iterated towards its fixpoint, as earlier rounds
+    /// see only some of the values its variables can take.
+    reporting: bool,
+    /// Statements left to walk. Nothing more is reported once they run out.
+    steps: usize,
+    /// The type of each integer variable, whose range a widened bound goes to.
+    types: HashMap<DeclId, Type>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl IntervalAnalysis {
+    pub fn new() -> Self {
+        Self {
+            state: None,
+            reporting: true,
+            steps: MAX_STEPS,
+            types: HashMap::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Analyzes `stmts`, returning the warnings found. Expects the program
+    /// to be resolved and type checked.
+    pub fn analyze(mut self, stmts: &[Stmt]) -> Vec<Diagnostic> {
+        self.state = Some(HashMap::new());
+        for stmt in stmts {
+            if !matches!(stmt.kind, StmtKind::Fn(_)) {
+                self.stmt(stmt);
+            }
+        }
+        for stmt in stmts {
+            if let StmtKind::Fn(f) = &stmt.kind {
+                // Parameters may be anything, so they start without bounds
+                self.state = Some(HashMap::new());
+                for (name, ty) in &f.params {
+                    if let Some(decl) = name.decl {
+                        self.types.insert(decl, *ty);
+                    }
+                }
+                self.block(&f.body);
+            }
+        }
+        self.diagnostics.sort_by_key(|d| d.span.start);
+        self.diagnostics
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        if self.steps == 0 {
+            // Treat the rest as unreachable, which is quick to walk
+            self.state = None;
+        }
+        if self.state.is_none() {
+            return;
+        }
+        self.steps -= 1;
+        match &stmt.kind {
+            StmtKind::Let(name, _, expr) | StmtKind::Assign(name, expr) => {
+                self.check_expr(expr);
+                let bounds = self.bounds(expr);
+                if let (Some(ty), Some(decl)) = (expr.ty, name.decl) {
+                    self.types.insert(decl, ty);
+                }
+                if let (Some(state), Some(decl)) = (&mut self.state, name.decl) {
+                    match bounds {
+                        Some(bounds) => state.insert(decl, bounds),
+                        None => state.remove(&decl),
+                    };
+                }
+            }
+            StmtKind::Exit(expr) => {
+                self.check_expr(expr);
+                self.check_exit(stmt, expr);
+                self.state = None;
+            }
+            StmtKind::Return(Some(expr)) | StmtKind::Become(expr) => {
+                self.check_expr(expr);
+                self.state = None;
+            }
+            StmtKind::Return(None) => self.state = None,
+            StmtKind::Print(expr) | StmtKind::Expr(expr) => self.check_expr(expr),
+            StmtKind::If(cond, then_body, elif_branches, else_body) => {
+                let branches = std::iter::once((cond, then_body))
+                    .chain(elif_branches.iter().map(|(cond, body)| (cond, body)));
+                // Where control goes once the branches tried so far are not taken
+                let mut rest = self.state.take();
+                let mut joined = None;
+                for (cond, body) in branches {
+                    self.state = rest.clone();
+                    self.check_expr(cond);
+                    self.state = self.refine(rest.clone(), cond, true);
+                    self.block(body);
+                    joined = join(joined, self.state.take());
+                    rest = self.refine(rest, cond, false);
+                }
+                self.state = rest;
+                if let Some(body) = else_body {
+                    self.block(body);
+                }
+                self.state = join(joined, self.state.take());
+            }
+            StmtKind::While(cond, body) => self.while_loop(cond, body),
+            StmtKind::Fn(_) => {}
+        }
+    }
+
+    fn while_loop(&mut self, cond: &Expr, body: &[Stmt]) {
+        let entry = self.state.take();
+        let reporting = self.reporting;
+        self.reporting = false;
+        let mut head = entry.clone();
+        let mut grown = HashMap::new();
+        loop {
+            self.state = self.refine(head.clone(), cond, true);
+            self.block(body);
+            let next = join(entry.clone(), self.state.take());
+            let widened = self.widen(head.clone(), next, &mut grown);
+            if widened == head {
+                break;
+            }
+            head = widened;
+        }
+        // Widening can overshoot bounds that the condition keeps to
+        self.state = self.refine(head.clone(), cond, true);
+        self.block(body);
+        head = join(entry, self.state.take());
+
+        self.reporting = reporting;
+        if reporting {
+            self.state = head.clone();
+            self.check_expr(cond);
+            self.state = self.refine(head.clone(), cond, true);
+            self.block(body);
+        }
+        self.state = self.refine(head, cond, false);
+    }
+
+    /// `state` where `cond` evaluates to `truth`.
+    fn refine(&self, state: State, cond: &Expr, truth: bool) -> State {
+        let mut vars = state?;
+        match &cond.kind {
+            ExprKind::Bool(b) if *b != truth => None,
+            ExprKind::BinOp(left, op, right) if op.is_comparison() && is_integer(left) => {
+                let op = if truth { *op } else { negate(*op) };
+                let (Some(l), Some(r)) = (bounds(&vars, left), bounds(&vars, right)) else {
+                    return Some(vars);
+                };
+                // No values make the comparison come out this way
+                let l = constrain(l, op, r)?;
+                let r = constrain(r, swap(op), l)?;
+                for (side, bounds) in [(left, l), (right, r)] {
+                    if let ExprKind::Ident(Name {
+                        decl: Some(decl), ..
+                    }) = &side.kind
+                    {
+                        vars.insert(*decl, bounds);
+                    }
+                }
+                Some(vars)
+            }
+            _ => Some(vars),
+        }
+    }
+
+    /// `head` joined with `next`, except that a variable whose bounds have
+    /// grown more than `WIDEN_DELAY` times before is widened instead.
+    fn widen(&self, head: State, next: State, grown: &mut HashMap<DeclId, usize>) -> State {
+        let (Some(head), Some(next)) = (&head, &next) else {
+            return head.or(next);
+        };
+        Some(
+            head.iter()
+                .filter_map(|(decl, x)| {
+                    let y = *next.get(decl)?;
+                    let joined = x.join(y);
+                    if joined == *x {
+                        return Some((*decl, joined));
+                    }
+                    let count = grown.entry(*decl).or_default();
+                    *count += 1;
+                    if *count > WIDEN_DELAY {
+                        Some((*decl, x.widen(y, self.types[decl])))
+                    } else {
+                        Some((*decl, joined))
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    fn bounds(&self, expr: &Expr) -> Option<Interval> {
+        bounds(self.state.as_ref()?, expr)
+    }
+
+    /// Whether findings at this point are reported. After the analysis gives
+    /// up, the bounds it has are missing what the statements it skipped do.
+    fn reports(&self) -> bool {
+        self.reporting && self.steps > 0 && self.state.is_some()
+    }
+
+    /// Reports the divisions in `expr` that may divide by zero.
+    fn check_expr(&mut self, expr: &Expr) {
+        if !self.reports() {
+            return;
+        }
+        match &expr.kind {
+            ExprKind::BinOp(left, op, right) => {
+                self.check_expr(left);
+                self.check_expr(right);
+                let Some(divisor) = self.bounds(right).filter(|_| *op == Op::Div) else {
+                    return;
+                };
+                let diagnostic = if divisor == Interval::constant(0) {
+                    Diagnostic::warning(expr.span, "division by zero: the divisor is always 0")
+                } else if divisor.may_be_zero() && divisor != Interval::full(right.checked_ty()) {
+                    Diagnostic::warning(expr.span, "possible division by zero")
+                        .with_note(right.span, format!("the divisor lies in {}", divisor))
+                } else {
+                    return;
+                };
+                self.diagnostics.push(diagnostic);
+            }
+            ExprKind::UnaryOp(_, inner) | ExprKind::Cast(inner, _) => self.check_expr(inner),
+            ExprKind::Call(_, args) => {
+                for arg in args {
+                    self.check_expr(arg);
+                }
+            }
+            ExprKind::Ident(_) | ExprKind::Num(_) | ExprKind::Float(_) | ExprKind::Bool(_) => {}
+        }
+    }
+
+    /// Reports an exit code that does not survive being cut to 8 bits.
+    fn check_exit(&mut self, stmt: &Stmt, code: &Expr) {
+        if !self.reports() {
+            return;
+        }
+        let Some(bounds) = self.bounds(code) else {
+            return;
+        };
+        let valid = Interval::new(0, 255);
+        let message = if bounds.hi < valid.lo || bounds.lo > valid.hi {
+            "exit code is always outside 0..=255"
+        } else if bounds.meet(valid) != Some(bounds) && bounds != Interval::full(code.checked_ty())
+        {
+            "exit code may be outside 0..=255"
+        } else {
+            return;
+        };
+        self.diagnostics
+            .push(Diagnostic::warning(stmt.span, message).with_note(
+                code.span,
+                format!(
+                    "the code lies in {}, and only its low 8 bits reach the parent process",
+                    bounds
+                ),
+            ));
+    }
+}
+
+impl Default for IntervalAnalysis {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interval {
+    fn new(lo: i128, hi: i128) -> Self {
+        Self {
+            lo,
+            hi,
+            nonzero: false,
+        }
+    }
+
+    fn constant(n: i128) -> Self {
+        Self::new(n, n)
+    }
+
+    /// Every value of the integer type `ty`.
+    fn full(ty: Type) -> Self {
+        let bits = ty.size() as u32 * 8;
+        if ty.is_signed() {
+            Self::new(-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+        } else {
+            Self::new(0, (1 << bits) - 1)
+        }
+    }
+
+    fn may_be_zero(self) -> bool {
+        self.lo <= 0 && 0 <= self.hi && !self.nonzero
+    }
+
+    fn join(self, other: Self) -> Self {
+        Self {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+            nonzero: self.nonzero && other.nonzero,
+        }
+    }
+
+    fn meet(self, other: Self) -> Option<Self> {
+        let meet = Self {
+            lo: self.lo.max(other.lo),
+            hi: self.hi.min(other.hi),
+            nonzero: self.nonzero || other.nonzero,
+        };
+        (meet.lo <= meet.hi).then_some(meet)
+    }
+
+    /// `self`, where a bound that moved on from `self` in `next` goes to
+    /// the end of `ty` at once.
+    fn widen(self, next: Self, ty: Type) -> Self {
+        let full = Self::full(ty);
+        Self {
+            lo: if next.lo < self.lo { full.lo } else { self.lo },
+            hi: if next.hi > self.hi { full.hi } else { self.hi },
+            nonzero: self.nonzero && next.nonzero,
+        }
+    }
+
+    /// The result of an operation of type `ty` that computes values in
+    /// `self` before they wrap around to fit it.
+    fn wrapped(self, ty: Type) -> Self {
+        let full = Self::full(ty);
+        if full.lo <= self.lo && self.hi <= full.hi {
+            self
+        } else {
+            full
+        }
+    }
+}
+
+impl std::fmt::Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}..={}", self.lo, self.hi)
+    }
+}
+
+/// Drops the warnings about an expression that one of `found` reports as an
+/// error, as the constant folder does for division by a constant zero.
+pub fn drop_superseded(warnings: &mut Vec<Diagnostic>, found: &[Diagnostic]) {
+    warnings.retain(|warning| !found.iter().any(|d| d.is_error() && d.span == warning.span));
+}
+
+fn join(a: State, b: State) -> State {
+    let (Some(a), Some(b)) = (&a, &b) else {
+        return a.or(b);
+    };
+    Some(
+        a.iter()
+            .filter_map(|(decl, x)| Some((*decl, x.join(*b.get(decl)?))))
+            .collect(),
+    )
+}
+
+/// The values `expr` may take in `vars`, if it is an integer.
+fn bounds(vars: &HashMap<DeclId, Interval>, expr: &Expr) -> Option<Interval> {
+    if !is_integer(expr) {
+        return None;
+    }
+    let ty = expr.checked_ty();
+    let bounds = match &expr.kind {
+        ExprKind::Num(n) if ty.is_signed() => Interval::constant(*n as i128),
+        ExprKind::Num(n) => Interval::constant(*n as u64 as i128),
+        ExprKind::Ident(name) => name
+            .decl
+            .and_then(|decl| vars.get(&decl).copied())
+            .unwrap_or(Interval::full(ty)),
+        ExprKind::BinOp(left, op, right) => {
+            let (a, b) = (bounds(vars, left)?, bounds(vars, right)?);
+            // Products of two `u64` bounds can go past `i128`, which leaves
+            // the result unbounded
+            let corners = |f: fn(i128, i128) -> Option<i128>| {
+                let products = [f(a.lo, b.lo), f(a.lo, b.hi), f(a.hi, b.lo), f(a.hi, b.hi)];
+                let products: Option<Vec<i128>> = products.into_iter().collect();
+                products.map_or(Interval::full(ty), |products| {
+                    Interval::new(
+                        *products.iter().min().unwrap(),
+                        *products.iter().max().unwrap(),
+                    )
+                })
+            };
+            let sum = |lo: Option<i128>, hi: Option<i128>| match (lo, hi) {
+                (Some(lo), Some(hi)) => Interval::new(lo, hi),
+                _ => Interval::full(ty),
+            };
+            match op {
+                Op::Add => sum(a.lo.checked_add(b.lo), a.hi.checked_add(b.hi)),
+                Op::Sub => sum(a.lo.checked_sub(b.hi), a.hi.checked_sub(b.lo)),
+                Op::Mul => corners(i128::checked_mul),
+                Op::Div => {
+                    // Divisors on either side of zero, which traps
+                    let parts = [Interval::new(b.lo, -1), Interval::new(1, b.hi)];
+                    parts
+                        .into_iter()
+                        .filter_map(|part| part.meet(b))
+                        .map(|part| {
+                            let products = [
+                                a.lo / part.lo,
+                                a.lo / part.hi,
+                                a.hi / part.lo,
+                                a.hi / part.hi,
+                            ];
+                            Interval::new(
+                                *products.iter().min().unwrap(),
+                                *products.iter().max().unwrap(),
+                            )
+                        })
+                        .reduce(Interval::join)
+                        .unwrap_or(Interval::full(ty))
+                }
+                _ => return None,
+            }
+            .wrapped(ty)
+        }
+        ExprKind::UnaryOp(_, inner) => {
+            let inner = bounds(vars, inner)?;
+            Interval::new(-inner.hi, -inner.lo).wrapped(ty)
+        }
+        ExprKind::Cast(inner, _) => match inner.ty {
+            Some(Type::Bool) => Interval::new(0, 1),
+            _ => bounds(vars, inner).map_or(Interval::full(ty), |inner| inner.wrapped(ty)),
+        },
+        ExprKind::Call(name, args) => {
+            let args: Option<Vec<Interval>> = args.iter().map(|arg| bounds(vars, arg)).collect();
+            match (name.as_str(), args.as_deref()) {
+                ("min", Some(&[a, b])) => Interval::new(a.lo.min(b.lo), a.hi.min(b.hi)),
+                ("max", Some(&[a, b])) => Interval::new(a.lo.max(b.lo), a.hi.max(b.hi)),
+                ("abs", Some(&[a])) if a.lo >= 0 => a,
+                ("abs", Some(&[a])) if a.hi <= 0 => Interval::new(-a.hi, -a.lo).wrapped(ty),
+                ("abs", Some(&[a])) => Interval::new(0, a.hi.max(-a.lo)).wrapped(ty),
+                _ => Interval::full(ty),
+            }
+        }
+        ExprKind::Float(_) | ExprKind::Bool(_) => return None,
+    };
+    Some(bounds)
+}
+
+/// The values of `x` for which `x op y` holds for some `y` in `y`, if any.
+fn constrain(x: Interval, op: Op, y: Interval) -> Option<Interval> {
+    let x = match op {
+        Op::Lt => Interval {
+            hi: x.hi.min(y.hi - 1),
+            ..x
+        },
+        Op::Lte => Interval {
+            hi: x.hi.min(y.hi),
+            ..x
+        },
+        Op::Gt => Interval {
+            lo: x.lo.max(y.lo + 1),
+            ..x
+        },
+        Op::Gte => Interval {
+            lo: x.lo.max(y.lo),
+            ..x
+        },
+        Op::Eq => return x.meet(y),
+        Op::NotEq if y.lo == y.hi && x.lo == y.lo => Interval { lo: x.lo + 1, ..x },
+        Op::NotEq if y.lo == y.hi && x.hi == y.lo => Interval { hi: x.hi - 1, ..x },
+        Op::NotEq if y == Interval::constant(0) => Interval { nonzero: true, ..x },
+        _ => x,
+    };
+    (x.lo <= x.hi).then_some(x)
+}
+
+/// The comparison that holds exactly when `op` does not.
+fn negate(op: Op) -> Op {
+    match op {
+        Op::Lt => Op::Gte,
+        Op::Lte => Op::Gt,
+        Op::Gt => Op::Lte,
+        Op::Gte => Op::Lt,
+        Op::Eq => Op::NotEq,
+        Op::NotEq => Op::Eq,
+        op => op,
+    }
+}
+
+/// The comparison that holds for `b op a` when `op` does for `a op b`.
+fn swap(op: Op) -> Op {
+    match op {
+        Op::Lt => Op::Gt,
+        Op::Lte => Op::Gte,
+        Op::Gt => Op::Lt,
+        Op::Gte => Op::Lte,
+        op => op,
+    }
+}
+
+fn is_integer(expr: &Expr) -> bool {
+    expr.ty.is_some_and(Type::is_integer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fold::ConstFolder,
+        test_util::{checked, render},
+    };
+
+    fn analyze(source: &str) -> Vec<String> {
+        render(&IntervalAnalysis::new().analyze(&checked(source)), source)
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        assert_eq!(
+            analyze("let z = 3 - 3; print(1 / z);"),
+            ["division by zero: the divisor is always 0 @ 1 / z"]
+        );
+        // The loop ends with `i` at exactly 10
+        assert_eq!(
+            analyze("let mut i = 0; while (i < 10) { i = i + 1; } print(100 / (i - 10));"),
+            ["division by zero: the divisor is always 0 @ 100 / (i - 10)"]
+        );
+        assert_eq!(
+            analyze("let mut i = 0; while (i < 10) { print(100 / (i - 5)); i = i + 1; }"),
+            ["possible division by zero @ 100 / (i - 5) (the divisor lies in -5..=4 @ (i - 5))"]
+        );
+    }
+
+    #[test]
+    fn test_constant_division_by_zero() {
+        // Without the constant folder, the warning is all there is
+        let source = "let z = 3 - 3; print(1 / z); exit(1 / 0);";
+        let mut warnings = IntervalAnalysis::new().analyze(&checked(source));
+        assert_eq!(
+            render(&warnings, source),
+            [
+                "division by zero: the divisor is always 0 @ 1 / z",
+                "division by zero: the divisor is always 0 @ 1 / 0",
+            ]
+        );
+        // With it, its errors take the place of the warnings
+        let mut stmts = checked(source);
+        let found = ConstFolder::new().fold(&mut stmts);
+        drop_superseded(&mut warnings, &found);
+        assert!(warnings.is_empty());
+        assert_eq!(
+            render(&found, source),
+            [
+                "attempt to divide by zero @ 1 / z",
+                "attempt to divide by zero @ 1 / 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_guards_and_unknowns_are_not_reported() {
+        for source in [
+            "fn f(a: i64, b: i64) -> i64 { return a / b; } print(f(1, 2));",
+            "let mut k = 1; while (k < 100) { print(50 / k); k = k * 2; }",
+            "let mut i = 0; while (i < 10) { let d = i - 5; if (d != 0) { print(10 / d); } i = i + 1; }",
+            "let mut i: u8 = 0; while (i < 200) { if (i > 0) { print(7 / i); } i = i + 1; }",
+            "let mut n = 20; while (n > 0) { print(100 / n); n = n - 3; }",
+        ] {
+            assert_eq!(analyze(source), Vec::<String>::new(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(
+            analyze("let mut i = 0; while (i < 300) { i = i + 1; } exit(i);"),
+            ["exit code is always outside 0..=255 @ exit(i); (the code lies in 300..=300, and only its low 8 bits reach the parent process @ i)"]
+        );
+        assert_eq!(
+            analyze("let mut c = 0; let mut i = 0; while (i < 10) { if (i > 5) { c = i * 40; } i = i + 1; } exit(c);"),
+            ["exit code may be outside 0..=255 @ exit(c); (the code lies in 0..=360, and only its low 8 bits reach the parent process @ c)"]
+        );
+        assert!(analyze("let c: u8 = 200; exit(c + 100); exit(255);").is_empty());
+    }
+
+    #[test]
+    fn test_u64_products_past_i128() {
+        // Squaring bounds near `u64::MAX` goes past `i128`
+        assert!(analyze(
+            "let mut h: u64 = 3; let mut i = 0; \
+             while (i < 10) { h = h * h; i = i + 1; } print(h); exit(h);"
+        )
+        .is_empty());
+        assert_eq!(
+            analyze("let a: u64 = 4000000000; print(7 / (a * a - 16000000000000000000));"),
+            ["division by zero: the divisor is always 0 @ 7 / (a * a - 16000000000000000000)"]
+        );
+    }
+}
@@ -0,0 +1,487 @@
+//! A three-address intermediate representation between the AST and assembly.
+//!
+//! Each function is a list of basic blocks. A block holds straight-line
+//! instructions that read operands and write one virtual register (a
+//! [`Value`]), and ends in a single [`Terminator`] that transfers control.
+//! Before SSA construction a value that stands for a source variable may be
+//! assigned in several places; temporaries are assigned exactly once.
+
+use std::fmt;
+
+use crate::{
+    parser::{Inline, Op},
+    types::Type,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Value(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockId(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Value(Value),
+    /// An integer or bool constant.
+    Int(i64),
+    Float(f64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    Copy {
+        dst: Value,
+        src: Operand,
+    },
+    /// `ty` is the type of both operands; comparisons produce a bool.
+    Binary {
+        dst: Value,
+        op: Op,
+        ty: Type,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Neg {
+        dst: Value,
+        ty: Type,
+        src: Operand,
+    },
+    Cast {
+        dst: Value,
+        from: Type,
+        to: Type,
+        src: Operand,
+    },
+    Call {
+        dst: Option<Value>,
+        func: String,
+        args: Vec<Operand>,
+    },
+    Print {
+        ty: Type,
+        src: Operand,
+    },
+    /// `then_value` when `cond` holds, otherwise `else_value`; both are
+    /// evaluated either way.
+    Select {
+        dst: Value,
+        cond: Operand,
+        then_value: Operand,
+        else_value: Operand,
+    },
+    /// Picks the operand of whichever predecessor control arrived from.
+    /// Phis only appear in SSA form, at the start of a block.
+    Phi {
+        dst: Value,
+        args: Vec<(BlockId, Operand)>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    Branch {
+        cond: Operand,
+        then_block: BlockId,
+        else_block: BlockId,
+    },
+    Return(Option<Operand>),
+    Exit(Operand),
+    /// Placeholder for a block that is still being built; the verifier
+    /// rejects it in a finished function.
+    Pending,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    /// What the block was lowered from, used to name its label.
+    pub name: String,
+    pub instrs: Vec<Instr>,
+    pub term: Terminator,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueInfo {
+    pub ty: Type,
+    /// The source variable this value stands for; `None` for temporaries.
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    /// Whether this is the program's entry point rather than a `fn`.
+    pub entry: bool,
+    pub params: Vec<Value>,
+    pub ret: Type,
+    /// `blocks[0]` is where execution starts.
+    pub blocks: Vec<Block>,
+    pub values: Vec<ValueInfo>,
+    pub inline: Inline,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub functions: Vec<Function>,
+}
+
+impl Operand {
+    pub fn as_value(&self) -> Option<Value> {
+        match self {
+            Operand::Value(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+impl Instr {
+    /// The value this instruction writes, if any.
+    pub fn dst(&self) -> Option<Value> {
+        match self {
+            Instr::Copy { dst, .. }
+            | Instr::Binary { dst, .. }
+            | Instr::Neg { dst, .. }
+            | Instr::Cast { dst, .. }
+            | Instr::Select { dst, .. }
+            | Instr::Phi { dst, .. } => Some(*dst),
+            Instr::Call { dst, .. } => *dst,
+            Instr::Print { .. } => None,
+        }
+    }
+
+    pub fn dst_mut(&mut self) -> Option<&mut Value> {
+        match self {
+            Instr::Copy { dst, .. }
+            | Instr::Binary { dst, .. }
+            | Instr::Neg { dst, .. }
+            | Instr::Cast { dst, .. }
+            | Instr::Select { dst, .. }
+            | Instr::Phi { dst, .. } => Some(dst),
+            Instr::Call { dst, .. } => dst.as_mut(),
+            Instr::Print { .. } => None,
+        }
+    }
+
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Instr::Copy { src, .. }
+            | Instr::Neg { src, .. }
+            | Instr::Cast { src, .. }
+            | Instr::Print { src, .. } => vec![src],
+            Instr::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Instr::Select {
+                cond,
+                then_value,
+                else_value,
+                ..
+            } => vec![cond, then_value, else_value],
+            Instr::Call { args, .. } => args.iter().collect(),
+            Instr::Phi { args, .. } => args.iter().map(|(_, arg)| arg).collect(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Instr::Copy { src, .. }
+            | Instr::Neg { src, .. }
+            | Instr::Cast { src, .. }
+            | Instr::Print { src, .. } => vec![src],
+            Instr::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Instr::Select {
+                cond,
+                then_value,
+                else_value,
+                ..
+            } => vec![cond, then_value, else_value],
+            Instr::Call { args, .. } => args.iter_mut().collect(),
+            Instr::Phi { args, .. } => args.iter_mut().map(|(_, arg)| arg).collect(),
+        }
+    }
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => vec![*then_block, *else_block],
+            Terminator::Return(_) | Terminator::Exit(_) | Terminator::Pending => vec![],
+        }
+    }
+
+    pub fn successors_mut(&mut self) -> Vec<&mut BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch {
+                then_block,
+                else_block,
+                ..
+            } => vec![then_block, else_block],
+            Terminator::Return(_) | Terminator::Exit(_) | Terminator::Pending => vec![],
+        }
+    }
+
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Terminator::Branch { cond, .. } => vec![cond],
+            Terminator::Return(Some(value)) | Terminator::Exit(value) => vec![value],
+            Terminator::Jump(_) | Terminator::Return(None) | Terminator::Pending => vec![],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Terminator::Branch { cond, .. } => vec![cond],
+            Terminator::Return(Some(value)) | Terminator::Exit(value) => vec![value],
+            Terminator::Jump(_) | Terminator::Return(None) | Terminator::Pending => vec![],
+        }
+    }
+}
+
+impl Function {
+    pub fn new_value(&mut self, ty: Type, name: Option<String>) -> Value {
+        self.values.push(ValueInfo { ty, name });
+        Value(self.values.len() - 1)
+    }
+
+    pub fn new_block(&mut self, name: &str) -> BlockId {
+        self.blocks.push(Block {
+            name: name.to_string(),
+            instrs: Vec::new(),
+            term: Terminator::Pending,
+        });
+        BlockId(self.blocks.len() - 1)
+    }
+
+    pub fn value_ty(&self, value: Value) -> Type {
+        self.values[value.0].ty
+    }
+
+    /// The type of `operand`, given the type to assume for constants.
+    pub fn operand_ty(&self, operand: &Operand, constant_ty: Type) -> Type {
+        match operand {
+            Operand::Value(value) => self.value_ty(*value),
+            Operand::Int(_) => constant_ty,
+            Operand::Float(_) => Type::F64,
+        }
+    }
+
+    /// For each block, the blocks that can jump to it.
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut preds = vec![Vec::new(); self.blocks.len()];
+        for (id, block) in self.blocks.iter().enumerate() {
+            for succ in block.term.successors() {
+                preds[succ.0].push(BlockId(id));
+            }
+        }
+        preds
+    }
+
+    /// Keeps only the blocks in `order`, laid out in that order, and
+    /// renumbers every reference to them.
+    pub fn reorder_blocks(&mut self, order: &[BlockId]) {
+        let mut new_ids = vec![None; self.blocks.len()];
+        for (new, old) in order.iter().enumerate() {
+            new_ids[old.0] = Some(BlockId(new));
+        }
+        let mut blocks: Vec<Option<Block>> = self.blocks.drain(..).map(Some).collect();
+        for old in order {
+            let mut block = blocks[old.0].take().expect("each block is listed once");
+            for target in block.term.successors_mut() {
+                *target = new_ids[target.0].expect("kept blocks only jump to kept blocks");
+            }
+            for instr in &mut block.instrs {
+                if let Instr::Phi { args, .. } = instr {
+                    // Edges from dropped blocks disappear with them
+                    args.retain_mut(|(pred, _)| match new_ids[pred.0] {
+                        Some(new) => {
+                            *pred = new;
+                            true
+                        }
+                        None => false,
+                    });
+                }
+            }
+            self.blocks.push(block);
+        }
+    }
+
+    /// Drops the blocks control can never reach from the entry block.
+    pub fn remove_unreachable_blocks(&mut self) {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut order = Vec::new();
+        let mut stack = vec![BlockId(0)];
+        reachable[0] = true;
+        while let Some(block) = stack.pop() {
+            order.push(block);
+            for succ in self.blocks[block.0].term.successors() {
+                if !reachable[succ.0] {
+                    reachable[succ.0] = true;
+                    stack.push(succ);
+                }
+            }
+        }
+        // Keep the surviving blocks in their original layout
+        order.sort();
+        self.reorder_blocks(&order);
+    }
+
+    /// A label for `block` that is unique within the function.
+    pub fn label(&self, block: BlockId) -> String {
+        format!(".{}_{}", self.blocks[block.0].name, block.0)
+    }
+
+    pub fn fmt_value(&self, value: Value) -> String {
+        match &self.values[value.0].name {
+            Some(name) => format!("%{}.{}", name, value.0),
+            None => format!("%{}", value.0),
+        }
+    }
+
+    fn fmt_operand(&self, operand: &Operand) -> String {
+        match operand {
+            Operand::Value(value) => self.fmt_value(*value),
+            Operand::Int(n) => n.to_string(),
+            Operand::Float(f) => format!("{:?}", f),
+        }
+    }
+
+    fn fmt_instr(&self, instr: &Instr) -> String {
+        match instr {
+            Instr::Copy { dst, src } => {
+                format!("{} = {}", self.fmt_value(*dst), self.fmt_operand(src))
+            }
+            Instr::Binary {
+                dst,
+                op,
+                ty,
+                lhs,
+                rhs,
+            } => format!(
+                "{} = {} {} {}, {}",
+                self.fmt_value(*dst),
+                op_name(op),
+                ty,
+                self.fmt_operand(lhs),
+                self.fmt_operand(rhs)
+            ),
+            Instr::Neg { dst, ty, src } => format!(
+                "{} = neg {} {}",
+                self.fmt_value(*dst),
+                ty,
+                self.fmt_operand(src)
+            ),
+            Instr::Cast { dst, from, to, src } => format!(
+                "{} = cast {} {} to {}",
+                self.fmt_value(*dst),
+                from,
+                self.fmt_operand(src),
+                to
+            ),
+            Instr::Call { dst, func, args } => {
+                let args: Vec<String> = args.iter().map(|arg| self.fmt_operand(arg)).collect();
+                let call = format!("call {}({})", func, args.join(", "));
+                match dst {
+                    Some(dst) => format!("{} = {}", self.fmt_value(*dst), call),
+                    None => call,
+                }
+            }
+            Instr::Print { ty, src } => format!("print {} {}", ty, self.fmt_operand(src)),
+            Instr::Select {
+                dst,
+                cond,
+                then_value,
+                else_value,
+            } => format!(
+                "{} = select {}, {}, {}",
+                self.fmt_value(*dst),
+                self.fmt_operand(cond),
+                self.fmt_operand(then_value),
+                self.fmt_operand(else_value)
+            ),
+            Instr::Phi { dst, args } => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|(pred, arg)| format!("[bb{}: {}]", pred.0, self.fmt_operand(arg)))
+                    .collect();
+                format!("{} = phi {}", self.fmt_value(*dst), args.join(", "))
+            }
+        }
+    }
+
+    fn fmt_term(&self, term: &Terminator) -> String {
+        match term {
+            Terminator::Jump(target) => format!("jmp bb{}", target.0),
+            Terminator::Branch {
+                cond,
+                then_block,
+                else_block,
+            } => format!(
+                "br {}, bb{}, bb{}",
+                self.fmt_operand(cond),
+                then_block.0,
+                else_block.0
+            ),
+            Terminator::Return(Some(value)) => format!("ret {}", self.fmt_operand(value)),
+            Terminator::Return(None) => "ret".to_string(),
+            Terminator::Exit(code) => format!("exit {}", self.fmt_operand(code)),
+            Terminator::Pending => "<pending>".to_string(),
+        }
+    }
+}
+
+/// The mnemonic used for `op` in IR dumps.
+fn op_name(op: &Op) -> &'static str {
+    match op {
+        Op::Add => "add",
+        Op::Sub => "sub",
+        Op::Mul => "mul",
+        Op::Div => "div",
+        Op::Eq => "eq",
+        Op::NotEq => "ne",
+        Op::Gt => "gt",
+        Op::Gte => "ge",
+        Op::Lt => "lt",
+        Op::Lte => "le",
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .map(|param| format!("{}: {}", self.fmt_value(*param), self.value_ty(*param)))
+            .collect();
+        writeln!(
+            f,
+            "fn {}({}) -> {} {{",
+            self.name,
+            params.join(", "),
+            self.ret
+        )?;
+        for (id, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "bb{}: ; {}", id, block.name)?;
+            for instr in &block.instrs {
+                writeln!(f, "    {}", self.fmt_instr(instr))?;
+            }
+            writeln!(f, "    {}", self.fmt_term(&block.term))?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, function) in self.functions.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", function)?;
+        }
+        Ok(())
+    }
+}
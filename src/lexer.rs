@@ -1,15 +1,28 @@
 use std::{iter::Peekable, str::Chars};
 
+use crate::diagnostic::Diagnostic;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Let,
+    Mut,
     While,
     If,
     Elif,
     Else,
     Exit,
+    Print,
+    True,
+    False,
+    As,
     Ident(String),
-    Number(i32),
+    /// An integer literal. Those above `i64::MAX`, up to `u64::MAX`, keep
+    /// their bits, as constants of unsigned types do.
+    Number(i64),
+    Float(f64),
+    Fn,
+    Return,
+    Become,
     Equal,
     Plus,
     Minus,
@@ -19,7 +32,13 @@ pub enum Token {
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
+    Hash,
     Semicolon,
+    Colon,
+    Comma,
+    Arrow,
     EqualEqual,
     NotEqual,
     Greater,
@@ -28,129 +47,256 @@ pub enum Token {
     LessEqual,
 }
 
+/// A byte range in the source text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
 pub struct Lexer<'a> {
     input: Peekable<Chars<'a>>,
+    pos: usize,
+    diagnostics: Vec<Diagnostic>,
 }
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str) -> Self {
         Self {
             input: input.chars().peekable(),
+            pos: 0,
+            diagnostics: Vec::new(),
         }
     }
+    /// The errors found in the literals tokenized so far.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
     pub fn tokenize(&mut self) -> Vec<Token> {
+        self.tokenize_spanned()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+    /// Like `tokenize`, but pairs every token with its location in the source.
+    pub fn tokenize_spanned(&mut self) -> Vec<(Token, Span)> {
         let mut tokens = vec![];
+        let mut spans = vec![];
         while let Some(&c) = self.input.peek() {
+            let start = self.pos;
             match c {
-                'a'..='z' | 'A'..='Z' => {
+                'a'..='z' | 'A'..='Z' | '_' => {
                     let mut identifier = String::new();
                     while let Some(&c @ ('a'..='z' | 'A'..='Z' | '0'..='9' | '_')) =
                         self.input.peek()
                     {
                         identifier.push(c);
-                        self.input.next();
+                        self.bump();
                     }
                     tokens.push(match identifier.as_str() {
                         "let" => Token::Let,
+                        "mut" => Token::Mut,
                         "exit" => Token::Exit,
+                        "print" => Token::Print,
                         "while" => Token::While,
                         "if" => Token::If,
                         "elif" => Token::Elif,
                         "else" => Token::Else,
+                        "true" => Token::True,
+                        "false" => Token::False,
+                        "as" => Token::As,
+                        "fn" => Token::Fn,
+                        "return" => Token::Return,
+                        "become" => Token::Become,
                         _ => Token::Ident(identifier),
                     });
                 }
                 '0'..='9' => {
-                    let mut number = 0;
-                    while let Some(&c @ ('0'..='9')) = self.input.peek() {
-                        number = number * 10 + (c as i32 - '0' as i32);
-                        self.input.next();
+                    let mut text = String::new();
+                    self.take_digits(&mut text);
+                    let mut is_float = false;
+                    if let Some('.') = self.input.peek() {
+                        is_float = true;
+                        text.push('.');
+                        self.bump();
+                        self.take_digits(&mut text);
+                    }
+                    if let Some('e' | 'E') = self.input.peek() {
+                        is_float = true;
+                        text.push('e');
+                        self.bump();
+                        if let Some(&c @ ('+' | '-')) = self.input.peek() {
+                            text.push(c);
+                            self.bump();
+                        }
+                        self.take_digits(&mut text);
+                    }
+                    let span = Span::new(start, self.pos);
+                    if is_float {
+                        let value = match text.parse::<f64>() {
+                            Ok(value) if value.is_infinite() => {
+                                self.diagnostics.push(Diagnostic::error(
+                                    span,
+                                    "float literal is too large for `f64`",
+                                ));
+                                0.0
+                            }
+                            Ok(value) => value,
+                            Err(_) => {
+                                self.diagnostics
+                                    .push(Diagnostic::error(span, "invalid float literal"));
+                                0.0
+                            }
+                        };
+                        tokens.push(Token::Float(value));
+                    } else {
+                        let value = text.parse::<u64>().unwrap_or_else(|_| {
+                            self.diagnostics.push(Diagnostic::error(
+                                span,
+                                "integer literal is too large for any integer type",
+                            ));
+                            0
+                        });
+                        tokens.push(Token::Number(value as i64));
                     }
-                    tokens.push(Token::Number(number));
                 }
                 '=' => {
                     let mut curr_token = Token::Equal;
-                    self.input.next();
+                    self.bump();
                     if let Some(&c) = self.input.peek() {
                         if c == '=' {
                             curr_token = Token::EqualEqual;
-                            self.input.next();
+                            self.bump();
                         }
                     }
                     tokens.push(curr_token);
                 }
                 '>' => {
                     let mut curr_token = Token::Greater;
-                    self.input.next();
+                    self.bump();
                     if let Some(&c) = self.input.peek() {
                         if c == '=' {
                             curr_token = Token::GreaterEqual;
+                            self.bump();
                         }
                     }
                     tokens.push(curr_token);
                 }
                 '<' => {
                     let mut curr_token = Token::Less;
-                    self.input.next();
+                    self.bump();
                     if let Some(&c) = self.input.peek() {
                         if c == '=' {
                             curr_token = Token::LessEqual;
+                            self.bump();
                         }
                     }
                     tokens.push(curr_token);
                 }
                 '!' => {
-                    self.input.next();
+                    self.bump();
                     if self.input.peek() != Some(&'=') {
                         panic!("Unexpected token");
                     }
-                    self.input.next();
+                    self.bump();
                     tokens.push(Token::NotEqual);
                 }
                 '+' => {
                     tokens.push(Token::Plus);
-                    self.input.next();
+                    self.bump();
                 }
                 '-' => {
-                    tokens.push(Token::Minus);
-                    self.input.next();
+                    self.bump();
+                    if let Some('>') = self.input.peek() {
+                        self.bump();
+                        tokens.push(Token::Arrow);
+                    } else {
+                        tokens.push(Token::Minus);
+                    }
                 }
                 '*' => {
                     tokens.push(Token::Asterisk);
-                    self.input.next();
+                    self.bump();
                 }
                 '/' => {
                     tokens.push(Token::Slash);
-                    self.input.next();
+                    self.bump();
                 }
                 '(' => {
                     tokens.push(Token::LParen);
-                    self.input.next();
+                    self.bump();
                 }
                 ')' => {
                     tokens.push(Token::RParen);
-                    self.input.next();
+                    self.bump();
                 }
                 '{' => {
                     tokens.push(Token::LBrace);
-                    self.input.next();
+                    self.bump();
                 }
                 '}' => {
                     tokens.push(Token::RBrace);
-                    self.input.next();
+                    self.bump();
+                }
+                '[' => {
+                    tokens.push(Token::LBracket);
+                    self.bump();
+                }
+                ']' => {
+                    tokens.push(Token::RBracket);
+                    self.bump();
+                }
+                '#' => {
+                    tokens.push(Token::Hash);
+                    self.bump();
                 }
                 ';' => {
                     tokens.push(Token::Semicolon);
-                    self.input.next();
+                    self.bump();
+                }
+                ':' => {
+                    tokens.push(Token::Colon);
+                    self.bump();
+                }
+                ',' => {
+                    tokens.push(Token::Comma);
+                    self.bump();
                 }
                 ' ' | '\n' | '\t' | '\r' => {
-                    self.input.next();
+                    self.bump();
                 }
                 _ => {
-                    self.input.next();
+                    self.bump();
+                    self.diagnostics.push(Diagnostic::error(
+                        Span::new(start, self.pos),
+                        format!("unexpected character `{}`", c),
+                    ));
                 }
             }
+            if tokens.len() > spans.len() {
+                spans.push(Span::new(start, self.pos));
+            }
+        }
+        tokens.into_iter().zip(spans).collect()
+    }
+    fn bump(&mut self) -> Option<char> {
+        let c = self.input.next()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+    fn take_digits(&mut self, text: &mut String) {
+        while let Some(&c @ ('0'..='9')) = self.input.peek() {
+            text.push(c);
+            self.bump();
         }
-        tokens
     }
 }
 
@@ -198,4 +344,87 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_bool_and_comparison_tokens() {
+        let tokens = Lexer::new("true >= false <= x").tokenize();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::True,
+                Token::GreaterEqual,
+                Token::False,
+                Token::LessEqual,
+                Token::Ident("x".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_float_literals() {
+        let tokens = Lexer::new("3.25 1e-9 2.5E3 7").tokenize();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Float(3.25),
+                Token::Float(1e-9),
+                Token::Float(2500.0),
+                Token::Number(7),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let tokens = Lexer::new("fn f(a: i64) -> i64 {\n  return a;\n}").tokenize_spanned();
+        assert_eq!(tokens[0], (Token::Fn, Span::new(0, 2)));
+        assert_eq!(tokens[7], (Token::Arrow, Span::new(13, 15)));
+        assert_eq!(tokens[10], (Token::Return, Span::new(24, 30)));
+    }
+
+    #[test]
+    fn test_large_integer_literals() {
+        let mut lexer = Lexer::new("9223372036854775808 18446744073709551616 1e");
+        let tokens = lexer.tokenize();
+        assert_eq!(tokens[0], Token::Number(i64::MIN));
+        let messages: Vec<_> = lexer
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.span))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (
+                    "integer literal is too large for any integer type".to_string(),
+                    Span::new(20, 40)
+                ),
+                ("invalid float literal".to_string(), Span::new(41, 43)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unexpected_characters_and_huge_floats() {
+        let mut lexer = Lexer::new("print(.5); 1e400 1e-400 $");
+        let tokens = lexer.tokenize();
+        assert_eq!(tokens[2], Token::Number(5));
+        assert_eq!(tokens[6], Token::Float(0.0));
+        let messages: Vec<_> = lexer
+            .take_diagnostics()
+            .into_iter()
+            .map(|d| (d.message, d.span))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                ("unexpected character `.`".to_string(), Span::new(6, 7)),
+                (
+                    "float literal is too large for `f64`".to_string(),
+                    Span::new(11, 16)
+                ),
+                ("unexpected character `$`".to_string(), Span::new(24, 25)),
+            ]
+        );
+    }
 }
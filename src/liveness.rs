@@ -0,0 +1,82 @@
+//! Live-variable analysis over the IR.
+//!
+//! A value is live at a point if some path from there reads it before it is
+//! written again. A phi reads each argument at the end of the matching
+//! predecessor, so its arguments count as live out of that block rather than
+//! live into the phi's own block.
+
+use std::collections::BTreeSet;
+
+use crate::{
+    dom::reverse_postorder,
+    ir::{Function, Instr, Value},
+};
+
+pub struct Liveness {
+    pub live_in: Vec<BTreeSet<Value>>,
+    pub live_out: Vec<BTreeSet<Value>>,
+}
+
+impl Liveness {
+    pub fn compute(f: &Function) -> Self {
+        let n = f.blocks.len();
+        // Values read before any write in each block, and values written in it
+        let mut uses = vec![BTreeSet::new(); n];
+        let mut defs = vec![BTreeSet::new(); n];
+        // Phi arguments, charged to the predecessor they flow out of
+        let mut phi_uses = vec![BTreeSet::new(); n];
+        for (id, block) in f.blocks.iter().enumerate() {
+            for instr in &block.instrs {
+                if let Instr::Phi { args, .. } = instr {
+                    for (pred, arg) in args {
+                        if let Some(value) = arg.as_value() {
+                            phi_uses[pred.0].insert(value);
+                        }
+                    }
+                } else {
+                    for operand in instr.operands() {
+                        if let Some(value) = operand.as_value() {
+                            if !defs[id].contains(&value) {
+                                uses[id].insert(value);
+                            }
+                        }
+                    }
+                }
+                if let Some(dst) = instr.dst() {
+                    defs[id].insert(dst);
+                }
+            }
+            for operand in block.term.operands() {
+                if let Some(value) = operand.as_value() {
+                    if !defs[id].contains(&value) {
+                        uses[id].insert(value);
+                    }
+                }
+            }
+        }
+
+        let mut live_in = vec![BTreeSet::new(); n];
+        let mut live_out: Vec<BTreeSet<Value>> = phi_uses.clone();
+        // Visiting blocks in postorder lets liveness flow backwards quickly
+        let mut order = reverse_postorder(f);
+        order.reverse();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &order {
+                let mut out = phi_uses[block.0].clone();
+                for succ in f.blocks[block.0].term.successors() {
+                    out.extend(live_in[succ.0].iter().copied());
+                }
+                let mut inn = uses[block.0].clone();
+                inn.extend(out.difference(&defs[block.0]).copied());
+                if inn != live_in[block.0] || out != live_out[block.0] {
+                    live_in[block.0] = inn;
+                    live_out[block.0] = out;
+                    changed = true;
+                }
+            }
+        }
+        Self { live_in, live_out }
+    }
+}
@@ -0,0 +1,604 @@
+//! Loop-invariant code motion and induction-variable simplification over
+//! IR in SSA form.
+//!
+//! A natural loop is found from each back edge, an edge to a block that
+//! dominates its source: the loop is that block, its header, plus every block
+//! that can reach the back edge without passing through the header. Each loop
+//! gets a preheader, a block that runs once before the loop is entered, and
+//! computations whose operands do not change while the loop runs are moved
+//! there. Multiplying a counter that steps by a fixed amount each iteration
+//! is then replaced with a second counter that steps by the product.
+
+use crate::{
+    dom::DomTree,
+    ir::{BlockId, Function, Instr, Module, Operand, Terminator, Value},
+    parser::Op,
+    types::Type,
+};
+
+pub struct Loop {
+    pub header: BlockId,
+    /// Blocks that jump back to the header.
+    pub latches: Vec<BlockId>,
+    /// Every block in the loop, the header included.
+    pub blocks: Vec<BlockId>,
+}
+
+impl Loop {
+    pub fn contains(&self, block: BlockId) -> bool {
+        self.blocks.contains(&block)
+    }
+}
+
+/// A counter that the loop's single latch advances by a fixed step.
+pub struct InductionVariable {
+    /// The header phi holding the counter's value for this iteration.
+    pub phi: Value,
+    pub ty: Type,
+    pub init: Operand,
+    pub step: Operand,
+    /// The value the counter has for the next iteration.
+    pub next: Value,
+}
+
+pub fn optimize_module(module: &mut Module) {
+    for f in &mut module.functions {
+        optimize(f);
+    }
+}
+
+pub fn optimize(f: &mut Function) {
+    let dom = DomTree::new(f);
+    let mut loops = find_loops(f, &dom);
+    let original_blocks = f.blocks.len();
+    let mut preheaders = Vec::new();
+    for i in 0..loops.len() {
+        let Some(preheader) = insert_preheader(f, &loops[i]) else {
+            continue;
+        };
+        if preheader.0 >= original_blocks {
+            preheaders.push((preheader, loops[i].header));
+            // A new block before an inner loop belongs to the loops around it
+            let header = loops[i].header;
+            for outer in &mut loops[i + 1..] {
+                if outer.contains(header) {
+                    outer.blocks.push(preheader);
+                }
+            }
+        }
+        hoist_invariants(f, &loops[i], preheader);
+        simplify_induction_variables(f, &loops[i], preheader);
+    }
+
+    if !preheaders.is_empty() {
+        // Lay each new preheader out just before its header
+        let mut order = Vec::new();
+        for id in 0..original_blocks {
+            for &(preheader, header) in &preheaders {
+                if header == BlockId(id) {
+                    order.push(preheader);
+                }
+            }
+            order.push(BlockId(id));
+        }
+        f.reorder_blocks(&order);
+    }
+}
+
+/// The natural loops of `f`, innermost first. Back edges to the same header
+/// make up a single loop.
+pub fn find_loops(f: &Function, dom: &DomTree) -> Vec<Loop> {
+    let preds = f.predecessors();
+    let mut loops = Vec::new();
+    for &header in dom.reverse_postorder() {
+        let latches: Vec<BlockId> = preds[header.0]
+            .iter()
+            .copied()
+            .filter(|&pred| dom.dominates(header, pred))
+            .collect();
+        if latches.is_empty() {
+            continue;
+        }
+        // Walk backwards from the latches; the header stops the walk
+        let mut in_loop = vec![false; f.blocks.len()];
+        in_loop[header.0] = true;
+        let mut stack = latches.clone();
+        while let Some(block) = stack.pop() {
+            if !in_loop[block.0] {
+                in_loop[block.0] = true;
+                stack.extend(preds[block.0].iter().filter(|&&p| dom.is_reachable(p)));
+            }
+        }
+        let blocks = (0..f.blocks.len())
+            .filter(|&id| in_loop[id])
+            .map(BlockId)
+            .collect();
+        loops.push(Loop {
+            header,
+            latches,
+            blocks,
+        });
+    }
+    loops.sort_by_key(|l| l.blocks.len());
+    loops
+}
+
+/// Makes sure the only way into `l` from outside is from a block that jumps
+/// straight to its header, and returns that block. The existing predecessor
+/// is reused when there is one; otherwise a new block is added and phi
+/// operands from outside the loop are merged there.
+fn insert_preheader(f: &mut Function, l: &Loop) -> Option<BlockId> {
+    let header = l.header;
+    let outside: Vec<BlockId> = f.predecessors()[header.0]
+        .iter()
+        .copied()
+        .filter(|&pred| !l.contains(pred))
+        .collect();
+    match outside[..] {
+        [] => return None,
+        [pred] if f.blocks[pred.0].term == Terminator::Jump(header) => return Some(pred),
+        _ => {}
+    }
+
+    let preheader = f.new_block("preheader");
+    f.blocks[preheader.0].term = Terminator::Jump(header);
+    for &pred in &outside {
+        for target in f.blocks[pred.0].term.successors_mut() {
+            if *target == header {
+                *target = preheader;
+            }
+        }
+    }
+    let mut merged = Vec::new();
+    for i in 0..f.blocks[header.0].instrs.len() {
+        let Instr::Phi { dst, args } = &f.blocks[header.0].instrs[i] else {
+            continue;
+        };
+        let (entering, inside): (Vec<_>, Vec<_>) = args
+            .iter()
+            .copied()
+            .partition(|(pred, _)| outside.contains(pred));
+        let incoming = match entering[..] {
+            [(_, arg)] => arg,
+            _ => {
+                let ty = f.value_ty(*dst);
+                let value = f.new_value(ty, None);
+                merged.push(Instr::Phi {
+                    dst: value,
+                    args: entering,
+                });
+                Operand::Value(value)
+            }
+        };
+        if let Instr::Phi { args, .. } = &mut f.blocks[header.0].instrs[i] {
+            *args = inside;
+            args.insert(0, (preheader, incoming));
+        }
+    }
+    f.blocks[preheader.0].instrs = merged;
+    Some(preheader)
+}
+
+/// Whether `instr` can run before the loop, and perhaps when the loop does
+/// not run at all, without changing what the program does. Division is only
+/// moved when it cannot trap.
+pub fn is_hoistable(instr: &Instr) -> bool {
+    match instr {
+        Instr::Copy { .. } | Instr::Neg { .. } | Instr::Cast { .. } | Instr::Select { .. } => true,
+        Instr::Binary {
+            op: Op::Div,
+            ty,
+            rhs,
+            ..
+        } if ty.is_integer() => match rhs {
+            Operand::Int(d) => *d != 0 && !(ty.is_signed() && *d == -1),
+            _ => false,
+        },
+        Instr::Binary { .. } => true,
+        Instr::Call { .. } | Instr::Print { .. } | Instr::Phi { .. } => false,
+    }
+}
+
+/// Values assigned anywhere in `l`.
+pub fn loop_defs(f: &Function, l: &Loop) -> Vec<bool> {
+    let mut defined = vec![false; f.values.len()];
+    for block in &l.blocks {
+        for dst in f.blocks[block.0].instrs.iter().filter_map(Instr::dst) {
+            defined[dst.0] = true;
+        }
+    }
+    defined
+}
+
+/// Moves every instruction in `l` whose operands are fixed before the loop
+/// runs into `preheader`, in an order that keeps definitions before uses.
+fn hoist_invariants(f: &mut Function, l: &Loop, preheader: BlockId) {
+    let mut defined = loop_defs(f, l);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in &l.blocks {
+            if block == preheader {
+                continue;
+            }
+            let mut i = 0;
+            while i < f.blocks[block.0].instrs.len() {
+                let instr = &f.blocks[block.0].instrs[i];
+                let invariant = is_hoistable(instr)
+                    && instr
+                        .operands()
+                        .into_iter()
+                        .filter_map(Operand::as_value)
+                        .all(|value| !defined[value.0]);
+                if !invariant {
+                    i += 1;
+                    continue;
+                }
+                let instr = f.blocks[block.0].instrs.remove(i);
+                if let Some(dst) = instr.dst() {
+                    defined[dst.0] = false;
+                }
+                f.blocks[preheader.0].instrs.push(instr);
+                changed = true;
+            }
+        }
+    }
+}
+
+/// The instruction that assigns `value` in `f`, and where it is.
+fn definition(f: &Function, value: Value) -> Option<(BlockId, usize, &Instr)> {
+    f.blocks.iter().enumerate().find_map(|(id, block)| {
+        block
+            .instrs
+            .iter()
+            .position(|instr| instr.dst() == Some(value))
+            .map(|i| (BlockId(id), i, &block.instrs[i]))
+    })
+}
+
+/// The counters in `l`'s header that each iteration adds an invariant step
+/// to, or subtracts a constant from.
+pub fn induction_variables(f: &Function, l: &Loop, defined: &[bool]) -> Vec<InductionVariable> {
+    let [latch] = l.latches[..] else {
+        return Vec::new();
+    };
+    let invariant = |operand: &Operand| match operand {
+        Operand::Value(value) => !defined[value.0],
+        Operand::Int(_) => true,
+        Operand::Float(_) => false,
+    };
+    let mut ivs = Vec::new();
+    for instr in &f.blocks[l.header.0].instrs {
+        let Instr::Phi { dst: phi, args } = instr else {
+            continue;
+        };
+        let ty = f.value_ty(*phi);
+        if !ty.is_integer() || args.len() != 2 {
+            continue;
+        }
+        let (Some(init), Some(Operand::Value(mut next))) = (
+            args.iter()
+                .find(|(pred, _)| *pred != latch)
+                .map(|(_, arg)| *arg),
+            args.iter()
+                .find(|(pred, _)| *pred == latch)
+                .map(|(_, arg)| *arg),
+        ) else {
+            continue;
+        };
+        // Look through the copies that stand for assignments to the variable
+        while let Some((
+            _,
+            _,
+            Instr::Copy {
+                src: Operand::Value(src),
+                ..
+            },
+        )) = definition(f, next)
+        {
+            next = *src;
+        }
+        let step = match definition(f, next) {
+            Some((
+                _,
+                _,
+                Instr::Binary {
+                    op: Op::Add,
+                    lhs,
+                    rhs,
+                    ..
+                },
+            )) => match (lhs, rhs) {
+                (Operand::Value(v), step) | (step, Operand::Value(v)) if v == phi => *step,
+                _ => continue,
+            },
+            Some((
+                _,
+                _,
+                Instr::Binary {
+                    op: Op::Sub,
+                    lhs: Operand::Value(v),
+                    rhs: Operand::Int(n),
+                    ..
+                },
+            )) if v == phi => Operand::Int(ty.wrap(n.wrapping_neg())),
+            _ => continue,
+        };
+        if invariant(&step) {
+            ivs.push(InductionVariable {
+                phi: *phi,
+                ty,
+                init,
+                step,
+                next,
+            });
+        }
+    }
+    ivs
+}
+
+/// `a * b` as an operand available at the end of `preheader`, adding the
+/// multiplication there unless it can be worked out now.
+fn product(f: &mut Function, preheader: BlockId, ty: Type, a: Operand, b: Operand) -> Operand {
+    match (a, b) {
+        (Operand::Int(a), Operand::Int(b)) => Operand::Int(ty.wrap(a.wrapping_mul(b))),
+        (Operand::Int(0), _) | (_, Operand::Int(0)) => Operand::Int(0),
+        (Operand::Int(1), other) | (other, Operand::Int(1)) => other,
+        (lhs, rhs) => {
+            let dst = f.new_value(ty, None);
+            f.blocks[preheader.0].instrs.push(Instr::Binary {
+                dst,
+                op: Op::Mul,
+                ty,
+                lhs,
+                rhs,
+            });
+            Operand::Value(dst)
+        }
+    }
+}
+
+/// Replaces each product of an induction variable and an invariant inside
+/// `l` with a new induction variable that steps by the product, so the loop
+/// adds where it used to multiply. Products whose value is needed after the
+/// loop are left alone.
+fn simplify_induction_variables(f: &mut Function, l: &Loop, preheader: BlockId) {
+    let defined = loop_defs(f, l);
+    let ivs = induction_variables(f, l, &defined);
+    if ivs.is_empty() {
+        return;
+    }
+    let latch = l.latches[0];
+    let invariant = |operand: &Operand| match operand {
+        Operand::Value(value) => !defined[value.0],
+        Operand::Int(_) => true,
+        Operand::Float(_) => false,
+    };
+
+    // (product, induction variable, factor)
+    let mut products = Vec::new();
+    for &block in &l.blocks {
+        for instr in &f.blocks[block.0].instrs {
+            let Instr::Binary {
+                dst,
+                op: Op::Mul,
+                lhs,
+                rhs,
+                ..
+            } = instr
+            else {
+                continue;
+            };
+            let found = ivs.iter().position(|iv| Operand::Value(iv.phi) == *lhs);
+            let (iv, factor) = match found {
+                Some(iv) => (iv, *rhs),
+                None => match ivs.iter().position(|iv| Operand::Value(iv.phi) == *rhs) {
+                    Some(iv) => (iv, *lhs),
+                    None => continue,
+                },
+            };
+            if invariant(&factor) && !used_outside(f, l, *dst) {
+                products.push((*dst, iv, factor));
+            }
+        }
+    }
+
+    // One new counter for each distinct product, shared by its repeats
+    let mut counters: Vec<(usize, Operand, Value)> = Vec::new();
+    let mut replaced = Vec::new();
+    for &(product_value, iv, factor) in &products {
+        let existing = counters
+            .iter()
+            .find(|(other, other_factor, _)| *other == iv && *other_factor == factor);
+        if let Some(&(_, _, counter)) = existing {
+            replaced.push((product_value, counter));
+            continue;
+        }
+        let iv_info = &ivs[iv];
+        let ty = iv_info.ty;
+        let init = product(f, preheader, ty, iv_info.init, factor);
+        let step = product(f, preheader, ty, iv_info.step, factor);
+        let counter = f.new_value(ty, None);
+        let next = f.new_value(ty, None);
+        let (block, i, _) = definition(f, iv_info.next).expect("the step is in the loop");
+        f.blocks[block.0].instrs.insert(
+            i + 1,
+            Instr::Binary {
+                dst: next,
+                op: Op::Add,
+                ty,
+                lhs: Operand::Value(counter),
+                rhs: step,
+            },
+        );
+        f.blocks[l.header.0].instrs.insert(
+            0,
+            Instr::Phi {
+                dst: counter,
+                args: vec![(preheader, init), (latch, Operand::Value(next))],
+            },
+        );
+        counters.push((iv, factor, counter));
+        replaced.push((product_value, counter));
+    }
+
+    for block in &mut f.blocks {
+        block.instrs.retain(|instr| {
+            !matches!(instr.dst(), Some(dst) if replaced.iter().any(|(old, _)| *old == dst))
+        });
+        let operands = block
+            .instrs
+            .iter_mut()
+            .flat_map(Instr::operands_mut)
+            .chain(block.term.operands_mut());
+        for operand in operands {
+            if let Some(&(_, counter)) = replaced
+                .iter()
+                .find(|(old, _)| Operand::Value(*old) == *operand)
+            {
+                *operand = Operand::Value(counter);
+            }
+        }
+    }
+}
+
+/// Whether `value` is read anywhere outside `l`.
+fn used_outside(f: &Function, l: &Loop, value: Value) -> bool {
+    f.blocks.iter().enumerate().any(|(id, block)| {
+        !l.contains(BlockId(id))
+            && block
+                .instrs
+                .iter()
+                .flat_map(Instr::operands)
+                .chain(block.term.operands())
+                .any(|operand| *operand == Operand::Value(value))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codegen::CodeGen, ssa, test_util::ssa_module, verify::verify};
+
+    /// Instructions in the loop headed by the first `while_start` label,
+    /// counted up to the jump back to it.
+    fn instructions_per_iteration(source: &str, optimized: bool) -> usize {
+        let mut module = ssa_module(source);
+        if optimized {
+            optimize_module(&mut module);
+        }
+        ssa::destruct_module(&mut module);
+        let asm = CodeGen::new().generate_module(&module);
+        let start = asm.find(".while_start_").unwrap();
+        let label = &asm[start..asm[start..].find(':').unwrap() + start];
+        let end = asm.find(&format!("jmp {}", label)).unwrap();
+        asm[start..end]
+            .lines()
+            .skip(1)
+            .filter(|line| !line.trim().is_empty() && !line.ends_with(':'))
+            .count()
+            + 1
+    }
+
+    #[test]
+    fn test_finds_nested_loops() {
+        let module = ssa_module(
+            "let mut i = 0; while (i < 3) { let mut j = 0; while (j < i) { j = j + 1; } i = i + 1; } exit(i);",
+        );
+        let f = &module.functions[0];
+        let loops = find_loops(f, &DomTree::new(f));
+        assert_eq!(loops.len(), 2);
+        let (inner, outer) = (&loops[0], &loops[1]);
+        assert_eq!(f.blocks[inner.header.0].name, "while_start");
+        assert!(outer.contains(inner.header));
+        assert!(inner.blocks.iter().all(|&block| outer.contains(block)));
+        assert!(!inner.contains(outer.header));
+        assert_eq!(inner.latches.len(), 1);
+    }
+
+    #[test]
+    fn test_hoists_invariant_computations() {
+        let mut module = ssa_module(
+            "fn f(n: i64, x: i64, y: i64) -> i64 { let mut s = 0; let mut i = 0; while (i < n) { s = s + x * y + s / x + y / 3; i = i + 1; } return s; } exit(f(3, 4, 5) as u8);",
+        );
+        optimize_module(&mut module);
+        let f = module.functions.iter().find(|f| f.name == "f").unwrap();
+        verify(f).unwrap();
+        let header = f
+            .blocks
+            .iter()
+            .position(|b| b.name == "while_start")
+            .unwrap();
+        let preheader = &f.blocks[header - 1];
+        assert!(matches!(preheader.term, Terminator::Jump(BlockId(h)) if h == header));
+        let ops = |block: &crate::ir::Block| -> Vec<Op> {
+            block
+                .instrs
+                .iter()
+                .filter_map(|instr| match instr {
+                    Instr::Binary { op, .. } => Some(*op),
+                    _ => None,
+                })
+                .collect()
+        };
+        // `x * y` and `y / 3` move out; `s / x` might divide by zero
+        assert_eq!(ops(preheader), [Op::Mul, Op::Div]);
+        assert_eq!(
+            ops(&f.blocks[header + 1]),
+            [Op::Add, Op::Div, Op::Add, Op::Add, Op::Add]
+        );
+    }
+
+    #[test]
+    fn test_preheader_merges_entering_phi_operands() {
+        let mut module = ssa_module(
+            "fn f(c: bool) -> i64 { let mut i = 0; if (c) { i = 5; } while (i < 10) { i = i + 1; } return i; } exit(f(true) as u8);",
+        );
+        optimize_module(&mut module);
+        let f = module.functions.iter().find(|f| f.name == "f").unwrap();
+        verify(f).unwrap();
+        let header = f
+            .blocks
+            .iter()
+            .position(|b| b.name == "while_start")
+            .unwrap();
+        assert_eq!(f.predecessors()[header].len(), 2);
+    }
+
+    #[test]
+    fn test_multiplied_counter_becomes_running_sum() {
+        let source = "fn f(n: i64) -> i64 { let mut s = 0; let mut i = 0; while (i < n) { s = s + i * 8; i = i + 1; } return s; } exit(f(10) as u8);";
+        let mut module = ssa_module(source);
+        optimize_module(&mut module);
+        let f = module.functions.iter().find(|f| f.name == "f").unwrap();
+        verify(f).unwrap();
+        let loops = find_loops(f, &DomTree::new(f));
+        let muls = loops[0]
+            .blocks
+            .iter()
+            .flat_map(|block| &f.blocks[block.0].instrs)
+            .filter(|instr| matches!(instr, Instr::Binary { op: Op::Mul, .. }))
+            .count();
+        assert_eq!(muls, 0);
+        let phis = f.blocks[loops[0].header.0]
+            .instrs
+            .iter()
+            .filter(|instr| matches!(instr, Instr::Phi { .. }))
+            .count();
+        assert_eq!(phis, 3);
+
+        assert!(
+            instructions_per_iteration(source, true) < instructions_per_iteration(source, false)
+        );
+    }
+
+    #[test]
+    fn test_fewer_instructions_per_iteration() {
+        let source = "fn f(n: i64, x: i64, y: i64) -> i64 { let mut s = 0; let mut i = 0; while (i < n) { s = s + (x * y + 3) / 4 + i * x; i = i + 1; } return s; } exit(f(10, 2, 3) as u8);";
+        let before = instructions_per_iteration(source, false);
+        let after = instructions_per_iteration(source, true);
+        // The invariant multiply, add and divide leave the loop, and `i * x`
+        // becomes an add
+        assert!(after + 8 <= before, "{} before, {} after", before, after);
+    }
+}
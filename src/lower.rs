@@ -0,0 +1,374 @@
+//! Lowering of the resolved, type-checked AST into the IR.
+
+use std::collections::HashMap;
+
+use crate::{
+    ir::{self, BlockId, Instr, Module, Operand, Terminator, Value},
+    parser::{DeclId, Expr, ExprKind, Function, Inline, Name, Op, Stmt, StmtKind},
+    types::Type,
+};
+
+/// Lowers a whole program. The top-level statements become the entry
+/// function, followed by one IR function per `fn`.
+pub fn lower(stmts: &[Stmt]) -> Module {
+    let mut entry = Builder::new("_start", true, Type::Unit);
+    for stmt in stmts {
+        if !matches!(stmt.kind, StmtKind::Fn(_)) {
+            entry.lower_stmt(stmt);
+        }
+    }
+    // Falling off the end of the program exits with code 0
+    if entry.current.is_some() {
+        entry.terminate(Terminator::Exit(Operand::Int(0)));
+    }
+
+    let mut functions = vec![entry.finish()];
+    for stmt in stmts {
+        if let StmtKind::Fn(f) = &stmt.kind {
+            functions.push(lower_function(f));
+        }
+    }
+    Module { functions }
+}
+
+fn lower_function(f: &Function) -> ir::Function {
+    let mut builder = Builder::new(&f.name, false, f.ret.unwrap_or(Type::Unit));
+    builder.func.inline = f.inline;
+    for (name, ty) in &f.params {
+        let param = builder.declare(name, *ty);
+        builder.func.params.push(param);
+    }
+    for stmt in &f.body {
+        builder.lower_stmt(stmt);
+    }
+    if builder.current.is_some() {
+        builder.terminate(Terminator::Return(None));
+    }
+    builder.finish()
+}
+
+struct Builder {
+    func: ir::Function,
+    /// The block instructions are appended to; `None` right after a
+    /// terminator, until code that can be reached again starts a new block.
+    current: Option<BlockId>,
+    vars: HashMap<DeclId, Value>,
+    /// Blocks in the order they were entered, which is source order.
+    layout: Vec<BlockId>,
+}
+
+impl Builder {
+    fn new(name: &str, entry: bool, ret: Type) -> Self {
+        let mut builder = Self {
+            func: ir::Function {
+                name: name.to_string(),
+                entry,
+                params: Vec::new(),
+                ret,
+                blocks: Vec::new(),
+                values: Vec::new(),
+                inline: Inline::Auto,
+            },
+            current: None,
+            vars: HashMap::new(),
+            layout: Vec::new(),
+        };
+        let start = builder.func.new_block("entry");
+        builder.switch_to(start);
+        builder
+    }
+
+    /// Lays the blocks out in source order, so that the entry block comes
+    /// first and a block's natural successor tends to follow it.
+    fn finish(mut self) -> ir::Function {
+        self.func.reorder_blocks(&self.layout);
+        self.func
+    }
+
+    fn switch_to(&mut self, block: BlockId) {
+        self.current = Some(block);
+        self.layout.push(block);
+    }
+
+    /// The current block, starting an unreachable one after a terminator.
+    fn block(&mut self) -> BlockId {
+        match self.current {
+            Some(block) => block,
+            None => {
+                let block = self.func.new_block("dead");
+                self.switch_to(block);
+                block
+            }
+        }
+    }
+
+    fn push(&mut self, instr: Instr) {
+        let block = self.block();
+        self.func.blocks[block.0].instrs.push(instr);
+    }
+
+    fn terminate(&mut self, term: Terminator) {
+        let block = self.block();
+        self.func.blocks[block.0].term = term;
+        self.current = None;
+    }
+
+    /// Ends the current block with a jump to `target`, unless control
+    /// already left it.
+    fn jump(&mut self, target: BlockId) {
+        if self.current.is_some() {
+            self.terminate(Terminator::Jump(target));
+        }
+    }
+
+    fn declare(&mut self, name: &Name, ty: Type) -> Value {
+        let value = self.func.new_value(ty, Some(name.name.clone()));
+        let decl = name.decl.expect("names are resolved before lowering");
+        self.vars.insert(decl, value);
+        value
+    }
+
+    fn var(&self, name: &Name) -> Value {
+        *name
+            .decl
+            .and_then(|decl| self.vars.get(&decl))
+            .unwrap_or_else(|| panic!("undefined variable: {}", name))
+    }
+
+    fn temp(&mut self, ty: Type) -> Value {
+        self.func.new_value(ty, None)
+    }
+
+    fn lower_block(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.lower_stmt(stmt);
+        }
+    }
+
+    fn lower_stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Let(name, _, expr) => {
+                let src = self.lower_expr(expr);
+                let dst = self.declare(name, expr_ty(expr));
+                self.push(Instr::Copy { dst, src });
+            }
+            StmtKind::Assign(name, expr) => {
+                let src = self.lower_expr(expr);
+                let dst = self.var(name);
+                self.push(Instr::Copy { dst, src });
+            }
+            StmtKind::Exit(expr) => {
+                let code = self.lower_expr(expr);
+                self.terminate(Terminator::Exit(code));
+            }
+            StmtKind::Print(expr) => {
+                let src = self.lower_expr(expr);
+                self.push(Instr::Print {
+                    ty: expr_ty(expr),
+                    src,
+                });
+            }
+            StmtKind::If(cond, then_body, elif_branches, else_body) => {
+                let end = self.func.new_block("if_end");
+                let branches: Vec<(&Expr, &Vec<Stmt>)> = std::iter::once((cond, then_body))
+                    .chain(elif_branches.iter().map(|(cond, body)| (cond, body)))
+                    .collect();
+                for (i, (cond, body)) in branches.iter().enumerate() {
+                    let cond = self.lower_expr(cond);
+                    let then_block =
+                        self.func
+                            .new_block(if i == 0 { "if_then" } else { "elif_then" });
+                    let else_block = if i + 1 < branches.len() {
+                        self.func.new_block("elif")
+                    } else if else_body.is_some() {
+                        self.func.new_block("else")
+                    } else {
+                        end
+                    };
+                    self.terminate(Terminator::Branch {
+                        cond,
+                        then_block,
+                        else_block,
+                    });
+                    self.switch_to(then_block);
+                    self.lower_block(body);
+                    self.jump(end);
+                    if else_block != end {
+                        self.switch_to(else_block);
+                    }
+                }
+                if let Some(else_stmts) = else_body {
+                    self.lower_block(else_stmts);
+                    self.jump(end);
+                }
+                self.switch_to(end);
+            }
+            StmtKind::While(cond, body) => {
+                let start = self.func.new_block("while_start");
+                self.jump(start);
+                self.switch_to(start);
+                let cond = self.lower_expr(cond);
+                let body_block = self.func.new_block("while_body");
+                let end = self.func.new_block("while_end");
+                self.terminate(Terminator::Branch {
+                    cond,
+                    then_block: body_block,
+                    else_block: end,
+                });
+                self.switch_to(body_block);
+                self.lower_block(body);
+                self.jump(start);
+                self.switch_to(end);
+            }
+            // Code generation turns a call whose value is returned directly
+            // into a jump, which is all `become` needs
+            StmtKind::Return(Some(expr)) | StmtKind::Become(expr) => {
+                let value = self.lower_expr(expr);
+                // A call to a function returning () has no value to pass on
+                let value = (expr_ty(expr) != Type::Unit).then_some(value);
+                self.terminate(Terminator::Return(value));
+            }
+            StmtKind::Return(None) => self.terminate(Terminator::Return(None)),
+            StmtKind::Expr(expr) => {
+                self.lower_expr(expr);
+            }
+            StmtKind::Fn(_) => unreachable!("functions are only declared at the top level"),
+        }
+    }
+
+    fn lower_expr(&mut self, expr: &Expr) -> Operand {
+        match &expr.kind {
+            ExprKind::Num(n) => Operand::Int(*n),
+            ExprKind::Float(f) => Operand::Float(*f),
+            ExprKind::Bool(b) => Operand::Int(*b as i64),
+            ExprKind::Ident(name) => Operand::Value(self.var(name)),
+            ExprKind::BinOp(left, op, right) => {
+                let lhs = self.lower_expr(left);
+                let rhs = self.lower_expr(right);
+                let dst = self.temp(expr_ty(expr));
+                self.push(Instr::Binary {
+                    dst,
+                    op: *op,
+                    ty: expr_ty(left),
+                    lhs,
+                    rhs,
+                });
+                Operand::Value(dst)
+            }
+            ExprKind::UnaryOp(Op::Sub, inner) => match (&inner.kind, self.lower_expr(inner)) {
+                // A negative literal is just a constant
+                (ExprKind::Num(_), Operand::Int(n)) => Operand::Int(n.wrapping_neg()),
+                (ExprKind::Float(_), Operand::Float(f)) => Operand::Float(-f),
+                (_, src) => {
+                    let ty = expr_ty(expr);
+                    let dst = self.temp(ty);
+                    self.push(Instr::Neg { dst, ty, src });
+                    Operand::Value(dst)
+                }
+            },
+            ExprKind::UnaryOp(op, _) => panic!("unsupported unary operator `{}`", op),
+            ExprKind::Cast(inner, ty) => {
+                let src = self.lower_expr(inner);
+                let dst = self.temp(*ty);
+                self.push(Instr::Cast {
+                    dst,
+                    from: expr_ty(inner),
+                    to: *ty,
+                    src,
+                });
+                Operand::Value(dst)
+            }
+            ExprKind::Call(name, args) if matches!(name.as_str(), "min" | "max" | "abs") => {
+                let args: Vec<Operand> = args.iter().map(|arg| self.lower_expr(arg)).collect();
+                let ty = expr_ty(expr);
+                // `abs` picks between the operand and its negation
+                let (op, then_value, else_value, rhs) = match (name.as_str(), &args[..]) {
+                    ("min", &[a, b]) => (Op::Lt, a, b, b),
+                    ("max", &[a, b]) => (Op::Gt, a, b, b),
+                    ("abs", &[a]) => {
+                        let neg = self.temp(ty);
+                        self.push(Instr::Neg {
+                            dst: neg,
+                            ty,
+                            src: a,
+                        });
+                        (Op::Lt, Operand::Value(neg), a, Operand::Int(0))
+                    }
+                    _ => unreachable!("intrinsic arity is checked"),
+                };
+                let cond = self.temp(Type::Bool);
+                self.push(Instr::Binary {
+                    dst: cond,
+                    op,
+                    ty,
+                    lhs: args[0],
+                    rhs,
+                });
+                let dst = self.temp(ty);
+                self.push(Instr::Select {
+                    dst,
+                    cond: Operand::Value(cond),
+                    then_value,
+                    else_value,
+                });
+                Operand::Value(dst)
+            }
+            ExprKind::Call(name, args) => {
+                let args = args.iter().map(|arg| self.lower_expr(arg)).collect();
+                let ty = expr_ty(expr);
+                let dst = (ty != Type::Unit).then(|| self.temp(ty));
+                self.push(Instr::Call {
+                    dst,
+                    func: name.clone(),
+                    args,
+                });
+                dst.map_or(Operand::Int(0), Operand::Value)
+            }
+        }
+    }
+}
+
+/// The checked type of `expr`; code lowered without running the type
+/// checker first treats everything as i64.
+pub fn expr_ty(expr: &Expr) -> Type {
+    expr.ty.unwrap_or(Type::I64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::checked;
+
+    fn lower_source(source: &str) -> Module {
+        lower(&checked(source))
+    }
+
+    #[test]
+    fn test_lower_while() {
+        let module = lower_source("let mut x: u8 = 0; while (x < 5) { x = x + 1; } exit(x);");
+        assert_eq!(
+            module.to_string(),
+            "fn _start() -> () {\n\
+             bb0: ; entry\n    %x.0 = 0\n    jmp bb1\n\
+             bb1: ; while_start\n    %1 = lt u8 %x.0, 5\n    br %1, bb2, bb3\n\
+             bb2: ; while_body\n    %2 = add u8 %x.0, 1\n    %x.0 = %2\n    jmp bb1\n\
+             bb3: ; while_end\n    exit %x.0\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_lower_if_chain_in_source_order() {
+        let module = lower_source(
+            "fn sign(n: i64) -> i64 { if (n > 0) { return 1; } elif (n < 0) { return -1; } return 0; } exit(sign(-4) + 1);",
+        );
+        let sign = &module.functions[1];
+        let names: Vec<&str> = sign.blocks.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, ["entry", "if_then", "elif", "elif_then", "if_end"]);
+        assert_eq!(
+            sign.blocks[3].term,
+            Terminator::Return(Some(Operand::Int(-1)))
+        );
+        assert!(module.to_string().contains("%0 = call sign(-4)"));
+    }
+}
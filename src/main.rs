@@ -1,18 +1,278 @@
+pub mod asm;
 pub mod codegen;
+pub mod dce;
+pub mod diagnostic;
+pub mod dom;
+pub mod fold;
+pub mod gvn;
+pub mod inline;
+pub mod interval;
+pub mod ir;
 pub mod lexer;
+pub mod liveness;
+pub mod loops;
+pub mod lower;
 pub mod parser;
+pub mod partial;
+pub mod passes;
+pub mod regalloc;
+pub mod resolve;
+pub mod runtime;
+pub mod select;
+pub mod ssa;
+pub mod strength;
+pub mod target;
+#[cfg(test)]
+mod test_util;
+pub mod typeck;
+pub mod types;
+pub mod unroll;
+pub mod verify;
 
-use std::fs::{read_to_string, write};
+use std::{
+    env,
+    fs::{read_to_string, write},
+    process,
+};
 
-use crate::{codegen::CodeGen, lexer::Lexer, parser::Parser};
+use crate::{
+    codegen::CodeGen,
+    diagnostic::Diagnostic,
+    interval::{drop_superseded, IntervalAnalysis},
+    lexer::Lexer,
+    lower::lower,
+    parser::Parser,
+    partial::PartialEvaluator,
+    passes::{Pass, PassManager},
+    resolve::Resolver,
+    target::Target,
+    typeck::TypeChecker,
+};
+
+/// What the compiler writes out, chosen with `--emit=ir,asm`, and the
+/// passes it runs: those of `-O0` to `-O3` (`-O2` by default), or the ones
+/// listed with `--passes=`. `--inline-threshold=N` overrides how large a
+/// function may be for calls to it to be inlined, `--unroll-factor=N` how
+/// many copies of a loop's body unrolling makes, `--print-after=` names
+/// passes whose result is dumped to stderr, and `--time-passes` reports how
+/// long each pass took. `-Z partial-eval[=STEPS]` runs the program at compile
+/// time first, for at most that many steps, and compiles what is left.
+/// `--target-cpu=` and `--target-feature=+avx2,...` choose the instruction
+/// set extensions the output may use. Code generation uses nothing past SSE2
+/// yet, so for now they only change the `; target:` line of the assembly.
+struct Options {
+    emit_ir: bool,
+    emit_asm: bool,
+    passes: PassManager,
+    time_passes: bool,
+    target: Target,
+    /// The step budget of the partial evaluator, when it runs.
+    partial_eval: Option<usize>,
+}
+
+impl Options {
+    fn from_args() -> Self {
+        let mut level = 2;
+        let mut passes = None;
+        let mut inline_threshold = None;
+        let mut unroll_factor = None;
+        let mut print_after = Vec::new();
+        let mut features = Vec::new();
+        let mut options = Options {
+            emit_ir: false,
+            emit_asm: true,
+            passes: PassManager::for_level(level),
+            time_passes: false,
+            target: Target::default(),
+            partial_eval: None,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if let Some(flag) = arg.strip_prefix("-Z") {
+                let flag = match flag {
+                    "" => args.next().unwrap_or_else(|| usage("`-Z` needs a flag")),
+                    flag => flag.to_string(),
+                };
+                options.partial_eval = Some(match flag.split_once('=') {
+                    None if flag == "partial-eval" => partial::DEFAULT_BUDGET,
+                    Some(("partial-eval", steps)) => steps
+                        .parse()
+                        .unwrap_or_else(|_| usage(&format!("invalid step budget `{}`", steps))),
+                    _ => usage(&format!("unknown flag `-Z {}`", flag)),
+                });
+                continue;
+            }
+            if let Some(threshold) = arg.strip_prefix("--inline-threshold=") {
+                inline_threshold = Some(threshold.parse().unwrap_or_else(|_| {
+                    usage(&format!("invalid inline threshold `{}`", threshold))
+                }));
+                continue;
+            }
+            if let Some(factor) = arg.strip_prefix("--unroll-factor=") {
+                unroll_factor = Some(
+                    factor
+                        .parse()
+                        .unwrap_or_else(|_| usage(&format!("invalid unroll factor `{}`", factor))),
+                );
+                continue;
+            }
+            if let Some(cpu) = arg.strip_prefix("--target-cpu=") {
+                options.target = Target::from_cpu(cpu).unwrap_or_else(|| {
+                    let cpus: Vec<&str> = Target::CPUS.iter().map(|(cpu, _)| *cpu).collect();
+                    usage(&format!(
+                        "unknown target CPU `{}`, expected one of {}",
+                        cpu,
+                        cpus.join(", ")
+                    ))
+                });
+                continue;
+            }
+            if let Some(specs) = arg.strip_prefix("--target-feature=") {
+                features.extend(specs.split(',').map(str::to_string));
+                continue;
+            }
+            if let Some(n) = arg.strip_prefix("-O") {
+                level = match n.parse() {
+                    Ok(n) if n <= 3 => n,
+                    _ => usage(&format!("invalid optimisation level `{}`", n)),
+                };
+                continue;
+            }
+            if let Some(names) = arg.strip_prefix("--passes=") {
+                passes = Some(pass_list(names));
+                continue;
+            }
+            if let Some(names) = arg.strip_prefix("--print-after=") {
+                print_after.extend(pass_list(names));
+                continue;
+            }
+            if arg == "--time-passes" {
+                options.time_passes = true;
+                continue;
+            }
+            match arg.strip_prefix("--emit=") {
+                Some(kinds) => {
+                    options.emit_asm = false;
+                    for kind in kinds.split(',') {
+                        match kind {
+                            "ir" => options.emit_ir = true,
+                            "asm" => options.emit_asm = true,
+                            _ => usage(&format!("unknown emit kind `{}`", kind)),
+                        }
+                    }
+                }
+                None => usage(&format!("unknown argument `{}`", arg)),
+            }
+        }
+        options.passes = match passes {
+            Some(passes) => PassManager::with_passes(passes).unwrap_or_else(|e| usage(&e)),
+            None => PassManager::for_level(level),
+        };
+        if let Some(threshold) = inline_threshold {
+            options.passes.inline_threshold = threshold;
+        }
+        if let Some(factor) = unroll_factor {
+            options.passes.unroll_factor = factor;
+        }
+        // Features adjust the CPU, wherever it was given
+        for spec in features {
+            options
+                .target
+                .set_feature(&spec)
+                .unwrap_or_else(|e| usage(&e));
+        }
+        for pass in print_after {
+            options
+                .passes
+                .print_after(pass)
+                .unwrap_or_else(|e| usage(&e));
+        }
+        options
+    }
+}
+
+/// The passes named in a comma-separated list; an empty list names none.
+fn pass_list(names: &str) -> Vec<Pass> {
+    names
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            Pass::from_name(name).unwrap_or_else(|| usage(&format!("unknown pass `{}`", name)))
+        })
+        .collect()
+}
+
+fn usage(problem: &str) -> ! {
+    eprintln!("error: {}", problem);
+    eprintln!(
+        "usage: parser [--emit=ir,asm] [-O0|-O1|-O2|-O3] [--passes=fold,dce,inline,gvn,select,loops,unroll] \
+         [--print-after=PASS,...] [--time-passes] [--inline-threshold=N] [--unroll-factor=N] \
+         [--target-cpu=CPU] [--target-feature=+FEATURE,-FEATURE,...] [-Z partial-eval[=STEPS]]"
+    );
+    eprintln!("note: --target-cpu and --target-feature do not change the generated code yet");
+    process::exit(2);
+}
 
 fn main() {
+    let mut options = Options::from_args();
     let source = read_to_string("./test.txt").unwrap();
-    let tokens = Lexer::new(&source).tokenize();
+    let mut lexer = Lexer::new(&source);
+    let tokens = lexer.tokenize_spanned();
+    report(&source, &lexer.take_diagnostics());
     println!("{:?}", tokens);
-    let stmts = Parser::new(tokens).parse();
+    let mut stmts = Parser::with_spans(tokens).parse();
     println!("{:?}", stmts);
-    let asm = CodeGen::new().generate(&stmts);
-    write("./output.asm", &asm).expect("failed to write output.asm");
+    let diagnostics = Resolver::new().resolve(&mut stmts);
+    report(&source, &diagnostics);
+    if let Err(diagnostics) = TypeChecker::new().check(&mut stmts) {
+        report(&source, &diagnostics);
+    }
+    // Held back until the passes that may report the same division as an
+    // error have run
+    let mut warnings = IntervalAnalysis::new().analyze(&stmts);
+    if let Some(budget) = options.partial_eval {
+        let found = PartialEvaluator::new(budget).evaluate(&mut stmts);
+        report_after(&source, &mut warnings, &found);
+    }
+    let found = options.passes.run_ast(&mut stmts);
+    report_after(&source, &mut warnings, &found);
+    report(&source, &warnings);
+    let mut module = lower(&stmts);
+    ssa::construct_module(&mut module);
+    if let Err(errors) = verify::verify_module(&module) {
+        panic!("invalid IR after SSA construction:\n{}", errors.join("\n"));
+    }
+    options.passes.run_ir(&mut module);
+    if options.time_passes {
+        eprint!("{}", options.passes.report_timings());
+    }
+    if options.emit_ir {
+        write("./output.ir", module.to_string()).expect("failed to write output.ir");
+    }
+    if options.emit_asm {
+        ssa::destruct_module(&mut module);
+        let asm = CodeGen::with_target(options.target).generate_module(&module);
+        write("./output.asm", &asm).expect("failed to write output.asm");
+    }
     println!("Done");
 }
+
+/// Prints `diagnostics` and stops compilation if any of them is an error.
+fn report(source: &str, diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        eprint!("{}", diagnostic.render(source));
+    }
+    if diagnostics.iter().any(Diagnostic::is_error) {
+        process::exit(1);
+    }
+}
+
+/// Reports `found`. If it stops compilation, the held-back `warnings` are
+/// printed first, less those about an expression it reports as an error.
+fn report_after(source: &str, warnings: &mut Vec<Diagnostic>, found: &[Diagnostic]) {
+    if found.iter().any(Diagnostic::is_error) {
+        drop_superseded(warnings, found);
+        report(source, warnings);
+    }
+    report(source, found);
+}
@@ -1,15 +1,71 @@
-use std::iter::Peekable;
+use std::{fmt, iter::Peekable};
 
-use crate::lexer::Token;
+use crate::{
+    lexer::{Span, Token},
+    types::Type,
+};
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum Expr {
-    Ident(String),
-    Num(i32),
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+    /// Filled in by the type checker; `None` until it has run.
+    pub ty: Option<Type>,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Ident(Name),
+    Num(i64),
+    Float(f64),
+    Bool(bool),
     BinOp(Box<Expr>, Op, Box<Expr>),
     UnaryOp(Op, Box<Expr>),
+    Cast(Box<Expr>, Type),
+    Call(String, Vec<Expr>),
 }
+/// Identifies one variable declaration (a `let` or a parameter).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeclId(pub usize);
+/// A variable name as written in the source. `decl` is filled in by name
+/// resolution with the declaration the name introduces or refers to.
 #[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub name: String,
+    pub span: Span,
+    pub decl: Option<DeclId>,
+    /// Whether a declaration was written `let mut`; always false for uses.
+    pub mutable: bool,
+}
+impl Name {
+    pub fn new(name: String, span: Span) -> Self {
+        Self {
+            name,
+            span,
+            decl: None,
+            mutable: false,
+        }
+    }
+}
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self {
+            kind,
+            span,
+            ty: None,
+        }
+    }
+
+    /// The type the type checker gave this expression; it must have run.
+    pub fn checked_ty(&self) -> Type {
+        self.ty.expect("expressions are type checked")
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Op {
     Add,
     Sub,
@@ -22,25 +78,208 @@ pub enum Op {
     Lt,
     Lte,
 }
+impl Op {
+    /// Whether the operator compares its operands, producing a `bool`.
+    pub fn is_comparison(self) -> bool {
+        !matches!(self, Op::Add | Op::Sub | Op::Mul | Op::Div)
+    }
+}
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Eq => "==",
+            Op::NotEq => "!=",
+            Op::Gt => ">",
+            Op::Gte => ">=",
+            Op::Lt => "<",
+            Op::Lte => "<=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
 #[derive(Debug, Clone)]
-pub enum Stmt {
-    Let(String, Expr),
+pub enum StmtKind {
+    Let(Name, Option<Type>, Expr),
+    Assign(Name, Expr),
     Exit(Expr),
+    Print(Expr),
     While(Expr, Vec<Stmt>),
     If(Expr, Vec<Stmt>, Vec<(Expr, Vec<Stmt>)>, Option<Vec<Stmt>>),
+    Fn(Function),
+    Return(Option<Expr>),
+    /// `become f(x);`, a return of a call that must reuse the caller's frame.
+    Become(Expr),
+    Expr(Expr),
+}
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<(Name, Type)>,
+    /// The declared return type; `None` when the signature has no `->`.
+    pub ret: Option<Type>,
+    pub body: Vec<Stmt>,
+    pub inline: Inline,
+}
+/// Whether calls to a function may be replaced with its body, as requested
+/// by an `#[inline(always)]` or `#[inline(never)]` attribute.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Inline {
+    /// Left to the inliner's size heuristic.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Operands that are themselves operations are parenthesized, so the
+        // printed program parses back the same whatever the precedence
+        let operand = |e: &Expr, nested: bool| match (&e.kind, nested) {
+            (ExprKind::BinOp(..), _) | (ExprKind::UnaryOp(..) | ExprKind::Cast(..), true) => {
+                format!("({})", e)
+            }
+            _ => e.to_string(),
+        };
+        match &self.kind {
+            ExprKind::Ident(name) => write!(f, "{}", name),
+            ExprKind::Num(n) if self.ty.is_some_and(|ty| !ty.is_signed()) => {
+                write!(f, "{}", *n as u64)
+            }
+            ExprKind::Num(n) => write!(f, "{}", n),
+            ExprKind::Float(x) => write!(f, "{:?}", x),
+            ExprKind::Bool(b) => write!(f, "{}", b),
+            ExprKind::BinOp(left, op, right) => write!(
+                f,
+                "{} {} {}",
+                operand(left, false),
+                op,
+                operand(right, false)
+            ),
+            ExprKind::UnaryOp(op, inner) => write!(f, "{}{}", op, operand(inner, true)),
+            ExprKind::Cast(inner, ty) => write!(f, "{} as {}", operand(inner, true), ty),
+            ExprKind::Call(name, args) => {
+                let args: Vec<String> = args.iter().map(Expr::to_string).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+        }
+    }
+}
+/// Prints the statement as source, a line per statement and nested blocks
+/// indented by four spaces, e.g. for `--print-after` of an AST pass.
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_stmt(f, self, 0)
+    }
+}
+fn write_block(f: &mut fmt::Formatter<'_>, stmts: &[Stmt], depth: usize) -> fmt::Result {
+    writeln!(f, "{{")?;
+    for stmt in stmts {
+        write_stmt(f, stmt, depth + 1)?;
+    }
+    write!(f, "{:1$}}}", "", depth * 4)
+}
+fn write_stmt(f: &mut fmt::Formatter<'_>, stmt: &Stmt, depth: usize) -> fmt::Result {
+    let indent = depth * 4;
+    write!(f, "{:1$}", "", indent)?;
+    match &stmt.kind {
+        StmtKind::Let(name, ty, expr) => {
+            let mutable = if name.mutable { "mut " } else { "" };
+            match ty {
+                Some(ty) => write!(f, "let {}{}: {} = {};", mutable, name, ty, expr)?,
+                None => write!(f, "let {}{} = {};", mutable, name, expr)?,
+            }
+        }
+        StmtKind::Assign(name, expr) => write!(f, "{} = {};", name, expr)?,
+        StmtKind::Exit(expr) => write!(f, "exit({});", expr)?,
+        StmtKind::Print(expr) => write!(f, "print({});", expr)?,
+        StmtKind::While(cond, body) => {
+            write!(f, "while ({}) ", cond)?;
+            write_block(f, body, depth)?;
+        }
+        StmtKind::If(cond, then_body, elifs, else_body) => {
+            write!(f, "if ({}) ", cond)?;
+            write_block(f, then_body, depth)?;
+            for (cond, body) in elifs {
+                write!(f, " elif ({}) ", cond)?;
+                write_block(f, body, depth)?;
+            }
+            if let Some(body) = else_body {
+                write!(f, " else ")?;
+                write_block(f, body, depth)?;
+            }
+        }
+        StmtKind::Fn(function) => {
+            match function.inline {
+                Inline::Auto => {}
+                Inline::Always => write!(f, "#[inline(always)]\n{:1$}", "", indent)?,
+                Inline::Never => write!(f, "#[inline(never)]\n{:1$}", "", indent)?,
+            }
+            let params: Vec<String> = function
+                .params
+                .iter()
+                .map(|(name, ty)| {
+                    let mutable = if name.mutable { "mut " } else { "" };
+                    format!("{}{}: {}", mutable, name, ty)
+                })
+                .collect();
+            write!(f, "fn {}({}) ", function.name, params.join(", "))?;
+            if let Some(ret) = function.ret {
+                write!(f, "-> {} ", ret)?;
+            }
+            write_block(f, &function.body, depth)?;
+        }
+        StmtKind::Return(None) => write!(f, "return;")?,
+        StmtKind::Return(Some(expr)) => write!(f, "return {};", expr)?,
+        StmtKind::Become(expr) => write!(f, "become {};", expr)?,
+        StmtKind::Expr(expr) => write!(f, "{};", expr)?,
+    }
+    writeln!(f)
 }
 
 pub struct Parser {
-    tokens: Peekable<std::vec::IntoIter<Token>>,
+    tokens: Peekable<std::vec::IntoIter<(Token, Span)>>,
+    prev_span: Span,
 }
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
+        Self::with_spans(
+            tokens
+                .into_iter()
+                .map(|token| (token, Span::default()))
+                .collect(),
+        )
+    }
+    pub fn with_spans(tokens: Vec<(Token, Span)>) -> Self {
         Self {
             tokens: tokens.into_iter().peekable(),
+            prev_span: Span::default(),
         }
     }
+    fn peek(&mut self) -> Option<&Token> {
+        self.tokens.peek().map(|(token, _)| token)
+    }
+    /// Span of the next token, or of the last one at end of input.
+    fn peek_span(&mut self) -> Span {
+        let prev = self.prev_span;
+        self.tokens.peek().map_or(prev, |(_, span)| *span)
+    }
+    fn advance(&mut self) -> Option<Token> {
+        let (token, span) = self.tokens.next()?;
+        self.prev_span = span;
+        Some(token)
+    }
     fn expect(&mut self, expected: Token) {
-        match self.tokens.next() {
+        match self.advance() {
             Some(t) => {
                 if std::mem::discriminant(&t) != std::mem::discriminant(&expected) {
                     panic!("expected {:?}, found {:?}", expected, t);
@@ -51,43 +290,66 @@ impl Parser {
             }
         }
     }
+    fn expect_ident(&mut self) -> Name {
+        match self.advance() {
+            Some(Token::Ident(id)) => Name::new(id, self.prev_span),
+            other => {
+                panic!("expected Identifier, got {:?}", other);
+            }
+        }
+    }
     pub fn parse(&mut self) -> Vec<Stmt> {
         let mut stmts = Vec::new();
-        while let Some(t) = self.tokens.peek().cloned() {
-            match t {
+        while let Some(t) = self.peek().cloned() {
+            let start = self.peek_span();
+            let kind = match t {
                 Token::Let => {
-                    self.tokens.next();
-                    let ident = match self.tokens.next() {
-                        Some(Token::Ident(id)) => id,
-                        other => {
-                            panic!("expected Identifier, got {:?}", other);
-                        }
-                    };
+                    self.advance();
+                    let mut mutable = false;
+                    if let Some(Token::Mut) = self.peek() {
+                        self.advance();
+                        mutable = true;
+                    }
+                    let mut ident = self.expect_ident();
+                    ident.mutable = mutable;
+                    let mut ty = None;
+                    if let Some(Token::Colon) = self.peek() {
+                        self.advance();
+                        ty = Some(self.parse_type());
+                    }
                     self.expect(Token::Equal);
                     let expr = self.parse_expr();
                     self.expect(Token::Semicolon);
-                    stmts.push(Stmt::Let(ident, expr));
+                    StmtKind::Let(ident, ty, expr)
                 }
                 Token::Exit => {
-                    self.tokens.next();
+                    self.advance();
                     self.expect(Token::LParen);
                     let expr = self.parse_expr();
                     self.expect(Token::RParen);
                     self.expect(Token::Semicolon);
-                    stmts.push(Stmt::Exit(expr));
+                    StmtKind::Exit(expr)
+                }
+                Token::Print => {
+                    self.advance();
+                    self.expect(Token::LParen);
+                    let expr = self.parse_expr();
+                    self.expect(Token::RParen);
+                    self.expect(Token::Semicolon);
+                    StmtKind::Print(expr)
                 }
                 Token::While => {
-                    self.tokens.next();
+                    self.advance();
                     self.expect(Token::LParen);
                     let cond = self.parse_expr();
                     self.expect(Token::RParen);
                     self.expect(Token::LBrace);
                     let block_stmts = self.parse();
                     self.expect(Token::RBrace);
-                    stmts.push(Stmt::While(cond, block_stmts));
+                    StmtKind::While(cond, block_stmts)
                 }
                 Token::If => {
-                    self.tokens.next();
+                    self.advance();
                     self.expect(Token::LParen);
                     let cond = self.parse_expr();
                     self.expect(Token::RParen);
@@ -96,8 +358,8 @@ impl Parser {
                     self.expect(Token::RBrace);
 
                     let mut elifs = Vec::new();
-                    while let Some(_elif @ Token::Elif) = self.tokens.peek() {
-                        self.tokens.next();
+                    while let Some(_elif @ Token::Elif) = self.peek() {
+                        self.advance();
                         self.expect(Token::LParen);
                         let elif_cond = self.parse_expr();
                         self.expect(Token::RParen);
@@ -108,13 +370,48 @@ impl Parser {
                         elifs.push((elif_cond, elif_block_stmts));
                     }
                     let mut else_block_stmts = None;
-                    if let Some(_els @ Token::Else) = self.tokens.peek() {
-                        self.tokens.next();
+                    if let Some(_els @ Token::Else) = self.peek() {
+                        self.advance();
                         self.expect(Token::LBrace);
                         else_block_stmts = Some(self.parse());
                         self.expect(Token::RBrace);
                     }
-                    stmts.push(Stmt::If(cond, block_stmts, elifs, else_block_stmts));
+                    StmtKind::If(cond, block_stmts, elifs, else_block_stmts)
+                }
+                Token::Fn => StmtKind::Fn(self.parse_function(Inline::Auto)),
+                Token::Hash => {
+                    let inline = self.parse_inline_attribute();
+                    if self.peek() != Some(&Token::Fn) {
+                        panic!("expected Fn after attribute, found {:?}", self.peek());
+                    }
+                    StmtKind::Fn(self.parse_function(inline))
+                }
+                Token::Return => {
+                    self.advance();
+                    let mut expr = None;
+                    if self.peek() != Some(&Token::Semicolon) {
+                        expr = Some(self.parse_expr());
+                    }
+                    self.expect(Token::Semicolon);
+                    StmtKind::Return(expr)
+                }
+                Token::Become => {
+                    self.advance();
+                    let expr = self.parse_expr();
+                    self.expect(Token::Semicolon);
+                    StmtKind::Become(expr)
+                }
+                Token::Ident(_) => {
+                    let expr = self.parse_expr();
+                    let kind = match (expr.kind, self.peek()) {
+                        (ExprKind::Ident(name), Some(Token::Equal)) => {
+                            self.advance();
+                            StmtKind::Assign(name, self.parse_expr())
+                        }
+                        (kind, _) => StmtKind::Expr(Expr { kind, ..expr }),
+                    };
+                    self.expect(Token::Semicolon);
+                    kind
                 }
                 Token::RBrace => {
                     return stmts;
@@ -122,11 +419,77 @@ impl Parser {
                 tok => {
                     panic!("unexpected token {:?}", tok);
                 }
-            }
+            };
+            stmts.push(Stmt {
+                kind,
+                span: start.to(self.prev_span),
+            });
             println!("{:?}", stmts);
         }
         stmts
     }
+    fn parse_function(&mut self, inline: Inline) -> Function {
+        self.expect(Token::Fn);
+        let name = self.expect_ident().name;
+        self.expect(Token::LParen);
+        let mut params = Vec::new();
+        while self.peek() != Some(&Token::RParen) {
+            if !params.is_empty() {
+                self.expect(Token::Comma);
+            }
+            let mut mutable = false;
+            if let Some(Token::Mut) = self.peek() {
+                self.advance();
+                mutable = true;
+            }
+            let mut param = self.expect_ident();
+            param.mutable = mutable;
+            self.expect(Token::Colon);
+            params.push((param, self.parse_type()));
+        }
+        self.expect(Token::RParen);
+        let mut ret = None;
+        if let Some(Token::Arrow) = self.peek() {
+            self.advance();
+            ret = Some(self.parse_type());
+        }
+        self.expect(Token::LBrace);
+        let body = self.parse();
+        self.expect(Token::RBrace);
+        Function {
+            name,
+            params,
+            ret,
+            body,
+            inline,
+        }
+    }
+    /// Parses `#[inline(always)]` or `#[inline(never)]`.
+    fn parse_inline_attribute(&mut self) -> Inline {
+        self.expect(Token::Hash);
+        self.expect(Token::LBracket);
+        let attribute = self.expect_ident();
+        if attribute.name != "inline" {
+            panic!("unknown attribute: {}", attribute.name);
+        }
+        self.expect(Token::LParen);
+        let inline = match self.expect_ident().name.as_str() {
+            "always" => Inline::Always,
+            "never" => Inline::Never,
+            other => panic!("expected always or never, found {}", other),
+        };
+        self.expect(Token::RParen);
+        self.expect(Token::RBracket);
+        inline
+    }
+    fn parse_type(&mut self) -> Type {
+        match self.advance() {
+            Some(Token::Ident(name)) => {
+                Type::from_name(&name).unwrap_or_else(|| panic!("unknown type: {}", name))
+            }
+            other => panic!("expected type, found {:?}", other),
+        }
+    }
     fn parse_expr(&mut self) -> Expr {
         self.parse_comparison()
     }
@@ -139,7 +502,7 @@ impl Parser {
             | Token::GreaterEqual
             | Token::Less
             | Token::LessEqual),
-        ) = self.tokens.peek().cloned()
+        ) = self.peek().cloned()
         {
             let op = match t {
                 Token::EqualEqual => Op::Eq,
@@ -150,52 +513,65 @@ impl Parser {
                 Token::LessEqual => Op::Lte,
                 _ => unreachable!(),
             };
-            self.tokens.next();
+            self.advance();
             let right = self.parse_add();
-            left = Expr::BinOp(Box::new(left), op, Box::new(right));
+            left = binop(left, op, right);
         }
         left
     }
     fn parse_add(&mut self) -> Expr {
         let mut left = self.parse_mul();
-        while let Some(t @ (Token::Plus | Token::Minus)) = self.tokens.peek().cloned() {
+        while let Some(t @ (Token::Plus | Token::Minus)) = self.peek().cloned() {
             let op = match t {
                 Token::Plus => Op::Add,
                 Token::Minus => Op::Sub,
                 _ => unreachable!(),
             };
-            self.tokens.next();
+            self.advance();
             let right = self.parse_mul();
-            left = Expr::BinOp(Box::new(left), op, Box::new(right));
+            left = binop(left, op, right);
         }
         left
     }
     fn parse_mul(&mut self) -> Expr {
-        let mut left = self.parse_unary();
-        while let Some(t @ (Token::Asterisk | Token::Slash)) = self.tokens.peek().cloned() {
+        let mut left = self.parse_cast();
+        while let Some(t @ (Token::Asterisk | Token::Slash)) = self.peek().cloned() {
             let op = match t {
                 Token::Asterisk => Op::Mul,
                 Token::Slash => Op::Div,
                 _ => unreachable!(),
             };
-            self.tokens.next();
-            let right = self.parse_unary();
-            left = Expr::BinOp(Box::new(left), op, Box::new(right));
+            self.advance();
+            let right = self.parse_cast();
+            left = binop(left, op, right);
         }
         left
     }
+    fn parse_cast(&mut self) -> Expr {
+        let mut expr = self.parse_unary();
+        while let Some(Token::As) = self.peek() {
+            self.advance();
+            let ty = self.parse_type();
+            let span = expr.span.to(self.prev_span);
+            expr = Expr::new(ExprKind::Cast(Box::new(expr), ty), span);
+        }
+        expr
+    }
     fn parse_unary(&mut self) -> Expr {
-        if let Some(t) = self.tokens.peek().cloned() {
+        let start = self.peek_span();
+        if let Some(t) = self.peek().cloned() {
             match t {
                 Token::Plus => {
-                    self.tokens.next();
+                    self.advance();
                     let expr = self.parse_primary();
-                    Expr::UnaryOp(Op::Add, Box::new(expr))
+                    let span = start.to(expr.span);
+                    Expr::new(ExprKind::UnaryOp(Op::Add, Box::new(expr)), span)
                 }
                 Token::Minus => {
-                    self.tokens.next();
+                    self.advance();
                     let expr = self.parse_primary();
-                    Expr::UnaryOp(Op::Sub, Box::new(expr))
+                    let span = start.to(expr.span);
+                    Expr::new(ExprKind::UnaryOp(Op::Sub, Box::new(expr)), span)
                 }
                 _ => self.parse_primary(),
             }
@@ -204,24 +580,96 @@ impl Parser {
         }
     }
     fn parse_primary(&mut self) -> Expr {
-        if let Some(t) = self.tokens.next() {
-            let tok = match t {
-                Token::Number(n) => Expr::Num(n),
-                Token::Ident(x) => Expr::Ident(x),
+        if let Some(t) = self.advance() {
+            let start = self.prev_span;
+            let kind = match t {
+                Token::Number(n) => ExprKind::Num(n),
+                Token::Float(f) => ExprKind::Float(f),
+                Token::True => ExprKind::Bool(true),
+                Token::False => ExprKind::Bool(false),
+                Token::Ident(x) if self.peek() == Some(&Token::LParen) => {
+                    self.advance();
+                    let mut args = Vec::new();
+                    while self.peek() != Some(&Token::RParen) {
+                        if !args.is_empty() {
+                            self.expect(Token::Comma);
+                        }
+                        args.push(self.parse_expr());
+                    }
+                    self.expect(Token::RParen);
+                    ExprKind::Call(x, args)
+                }
+                Token::Ident(x) => ExprKind::Ident(Name::new(x, start)),
                 Token::LParen => {
-                    let expr = self.parse_expr();
+                    let mut expr = self.parse_expr();
                     self.expect(Token::RParen);
-                    expr
+                    expr.span = start.to(self.prev_span);
+                    return expr;
                 }
                 t => panic!("unexpected token in expression: {:?}", t),
             };
-            tok
+            Expr::new(kind, start.to(self.prev_span))
         } else {
             panic!("unexpected behaviour");
         }
     }
 }
 
+fn binop(left: Expr, op: Op, right: Expr) -> Expr {
+    let span = left.span.to(right.span);
+    Expr::new(ExprKind::BinOp(Box::new(left), op, Box::new(right)), span)
+}
+
+/// Calls `f` on every expression in `stmts`, children before parents and
+/// statements in source order.
+pub fn walk_exprs_mut(stmts: &mut [Stmt], f: &mut impl FnMut(&mut Expr)) {
+    fn walk_expr(expr: &mut Expr, f: &mut impl FnMut(&mut Expr)) {
+        match &mut expr.kind {
+            ExprKind::BinOp(left, _, right) => {
+                walk_expr(left, f);
+                walk_expr(right, f);
+            }
+            ExprKind::UnaryOp(_, inner) | ExprKind::Cast(inner, _) => walk_expr(inner, f),
+            ExprKind::Call(_, args) => {
+                for arg in args {
+                    walk_expr(arg, f);
+                }
+            }
+            ExprKind::Ident(_) | ExprKind::Num(_) | ExprKind::Float(_) | ExprKind::Bool(_) => {}
+        }
+        f(expr);
+    }
+
+    for stmt in stmts {
+        match &mut stmt.kind {
+            StmtKind::Let(_, _, expr)
+            | StmtKind::Exit(expr)
+            | StmtKind::Print(expr)
+            | StmtKind::Expr(expr)
+            | StmtKind::Assign(_, expr)
+            | StmtKind::Return(Some(expr))
+            | StmtKind::Become(expr) => walk_expr(expr, f),
+            StmtKind::Return(None) => {}
+            StmtKind::While(cond, body) => {
+                walk_expr(cond, f);
+                walk_exprs_mut(body, f);
+            }
+            StmtKind::If(cond, then_body, elif_branches, else_body) => {
+                walk_expr(cond, f);
+                walk_exprs_mut(then_body, f);
+                for (elif_cond, elif_body) in elif_branches {
+                    walk_expr(elif_cond, f);
+                    walk_exprs_mut(elif_body, f);
+                }
+                if let Some(else_stmts) = else_body {
+                    walk_exprs_mut(else_stmts, f);
+                }
+            }
+            StmtKind::Fn(function) => walk_exprs_mut(&mut function.body, f),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -233,20 +681,20 @@ mod tests {
         let tokens = lexer.tokenize();
         let mut parser = Parser::new(tokens);
         let expr = parser.parse_expr();
-        match expr {
-            Expr::BinOp(left, Op::Add, right) => {
-                match *left {
-                    Expr::Num(1) => {}
+        match expr.kind {
+            ExprKind::BinOp(left, Op::Add, right) => {
+                match left.kind {
+                    ExprKind::Num(1) => {}
                     _ => panic!("expected Num(1)"),
                 }
-                match *right {
-                    Expr::BinOp(inner_left, Op::Mul, inner_right) => {
-                        match *inner_left {
-                            Expr::Num(2) => {}
+                match right.kind {
+                    ExprKind::BinOp(inner_left, Op::Mul, inner_right) => {
+                        match inner_left.kind {
+                            ExprKind::Num(2) => {}
                             _ => panic!("expected Num(2)"),
                         }
-                        match *inner_right {
-                            Expr::Num(3) => {}
+                        match inner_right.kind {
+                            ExprKind::Num(3) => {}
                             _ => panic!("expected Num(3)"),
                         }
                     }
@@ -256,4 +704,116 @@ mod tests {
             _ => panic!("expected BinOp with Add"),
         }
     }
+
+    #[test]
+    fn test_parse_let_type_and_cast() {
+        use crate::lexer::Lexer;
+        let tokens = Lexer::new("let x: u8 = 1 + 2 as u8 * 3;").tokenize();
+        let stmts = Parser::new(tokens).parse();
+        match &stmts[0].kind {
+            StmtKind::Let(name, Some(Type::U8), expr) => {
+                assert_eq!(name.name, "x");
+                match &expr.kind {
+                    ExprKind::BinOp(_, Op::Add, right) => match &right.kind {
+                        ExprKind::BinOp(left, Op::Mul, _) => {
+                            assert!(matches!(left.kind, ExprKind::Cast(_, Type::U8)));
+                        }
+                        _ => panic!("expected BinOp with Mul"),
+                    },
+                    _ => panic!("expected BinOp with Add"),
+                }
+            }
+            _ => panic!("expected annotated let"),
+        }
+    }
+
+    #[test]
+    fn test_parse_function_and_spans() {
+        use crate::lexer::Lexer;
+        let source = "fn add(a: i64, b: i64) -> i64 { return a + b; } exit(add(1, 2));";
+        let tokens = Lexer::new(source).tokenize_spanned();
+        let stmts = Parser::with_spans(tokens).parse();
+        match &stmts[0].kind {
+            StmtKind::Fn(f) => {
+                assert_eq!(f.name, "add");
+                let params: Vec<_> = f
+                    .params
+                    .iter()
+                    .map(|(p, ty)| (p.name.as_str(), *ty))
+                    .collect();
+                assert_eq!(params, vec![("a", Type::I64), ("b", Type::I64)]);
+                assert_eq!(f.ret, Some(Type::I64));
+                assert_eq!(
+                    &source[f.body[0].span.start..f.body[0].span.end],
+                    "return a + b;"
+                );
+            }
+            _ => panic!("expected fn"),
+        }
+        match &stmts[1].kind {
+            StmtKind::Exit(expr) => {
+                assert!(
+                    matches!(&expr.kind, ExprKind::Call(name, args) if name == "add" && args.len() == 2)
+                );
+                assert_eq!(&source[expr.span.start..expr.span.end], "add(1, 2)");
+            }
+            _ => panic!("expected exit"),
+        }
+    }
+
+    #[test]
+    fn test_parse_inline_attributes() {
+        use crate::lexer::Lexer;
+        let source = "#[inline(never)] fn a() {} fn b() {} #[inline(always)] fn c() {}";
+        let stmts = Parser::with_spans(Lexer::new(source).tokenize_spanned()).parse();
+        let inline: Vec<Inline> = stmts
+            .iter()
+            .map(|stmt| match &stmt.kind {
+                StmtKind::Fn(f) => f.inline,
+                _ => panic!("expected fn"),
+            })
+            .collect();
+        assert_eq!(inline, [Inline::Never, Inline::Auto, Inline::Always]);
+    }
+
+    #[test]
+    fn test_parse_mut_and_assign() {
+        use crate::lexer::Lexer;
+        let tokens = Lexer::new("let mut x = 0; x = x + 1; f(x);").tokenize();
+        let stmts = Parser::new(tokens).parse();
+        assert!(matches!(&stmts[0].kind, StmtKind::Let(name, None, _) if name.mutable));
+        assert!(matches!(&stmts[1].kind, StmtKind::Assign(name, _) if name.name == "x"));
+        assert!(matches!(&stmts[2].kind, StmtKind::Expr(_)));
+    }
+
+    #[test]
+    fn test_print_round_trips() {
+        use crate::lexer::Lexer;
+        let source = "#[inline(never)] fn f(mut a: u8, b: f64) -> bool { a = a * (2 + 3); \
+                      return -(b as i64) as f64 > 1.5; } \
+                      let x = 1 - (2 - 3); while (x < 4) { if (f(1, 2.0)) { print(x); } \
+                      elif (x == 2) { exit(0); } else { return; } }";
+        let print = |stmts: &[Stmt]| stmts.iter().map(Stmt::to_string).collect::<String>();
+        let printed = print(&Parser::new(Lexer::new(source).tokenize()).parse());
+        assert_eq!(
+            printed,
+            "#[inline(never)]\n\
+             fn f(mut a: u8, b: f64) -> bool {\n    \
+                 a = a * (2 + 3);\n    \
+                 return (-(b as i64)) as f64 > 1.5;\n\
+             }\n\
+             let x = 1 - (2 - 3);\n\
+             while (x < 4) {\n    \
+                 if (f(1, 2.0)) {\n        \
+                     print(x);\n    \
+                 } elif (x == 2) {\n        \
+                     exit(0);\n    \
+                 } else {\n        \
+                     return;\n    \
+                 }\n\
+             }\n"
+        );
+        let reparsed = print(&Parser::new(Lexer::new(&printed).tokenize()).parse());
+        assert_eq!(reparsed, printed);
+    }
 }
@@ -0,0 +1,734 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use crate::{
+    diagnostic::Diagnostic,
+    fold::{division_trap, fold_binop, fold_cast},
+    lexer::Span,
+    parser::{DeclId, Expr, ExprKind, Function, Name, Op, Stmt, StmtKind},
+    types::Type,
+};
+
+/// How many steps the partial evaluator takes by default before giving up.
+pub const DEFAULT_BUDGET: usize = 1_000_000;
+
+/// How deeply calls may nest while evaluating. Each call recurses in the
+/// evaluator itself, so this keeps it well within the compiler's own stack.
+const MAX_DEPTH: usize = 128;
+
+/// Runs the program at compile time, leaving only what is left to do.
+///
+/// A program takes no input, so everything it computes is known once it has
+/// run. The top-level statements are executed with the same wrapping,
+/// signedness and float semantics as the generated code, and each `print`
+/// they make becomes a `print` of a literal. If the program exits, or ends,
+/// within the step budget, the residual program is only those prints and
+/// its exit code. Otherwise evaluation stops at the statement that ran out
+/// of budget, or whose calls nested too deeply: the variables in scope
+/// there are declared with their current values, followed by the statements
+/// still to run. A statement inside a top-level loop or `if` can be where
+/// evaluation stops, but calls are evaluated whole or not at all. Integer
+/// division that would trap is reported as an error.
+pub struct PartialEvaluator {
+    functions: HashMap<String, Arc<Function>>,
+    /// Steps left before evaluation gives up. Every statement and
+    /// expression evaluated takes one.
+    steps: usize,
+    depth: usize,
+    /// The prints made so far, as statements printing literals.
+    output: Vec<Stmt>,
+}
+
+/// A variable in scope, with its current value as a literal.
+struct Binding {
+    name: Name,
+    ty: Type,
+    value: ExprKind,
+}
+
+/// Why evaluation stopped before the end of a statement.
+enum Stop {
+    /// The program exited with this code.
+    Exit(Box<Expr>),
+    /// The program would trap here.
+    Trap(Box<Diagnostic>),
+    /// The budget ran out, or calls nested too deeply, so the rest has to
+    /// run at run time.
+    GaveUp(&'static str),
+}
+
+/// Where control goes after a statement that ran to its end.
+enum Flow {
+    Next,
+    Return(Option<ExprKind>),
+    Become(String, Vec<ExprKind>),
+}
+
+impl PartialEvaluator {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            functions: HashMap::new(),
+            steps: budget,
+            depth: 0,
+            output: Vec::new(),
+        }
+    }
+
+    /// Replaces `stmts` with their residual program, returning the errors
+    /// found and a warning if the program could not be run to its end.
+    /// Expects the program to be resolved and type checked.
+    pub fn evaluate(mut self, stmts: &mut Vec<Stmt>) -> Vec<Diagnostic> {
+        for stmt in stmts.iter() {
+            if let StmtKind::Fn(f) = &stmt.kind {
+                self.functions.insert(f.name.clone(), Arc::new(f.clone()));
+            }
+        }
+        let mut env = Vec::new();
+        let (stop, rest) = match self.run_block(&mut env, stmts) {
+            Ok(()) => {
+                *stmts = self.output;
+                return Vec::new();
+            }
+            Err(stopped) => stopped,
+        };
+        let span = rest.first().map(|stmt| stmt.span);
+        match stop {
+            Stop::Exit(code) => {
+                let span = code.span;
+                self.output.push(Stmt {
+                    kind: StmtKind::Exit(*code),
+                    span,
+                });
+                *stmts = self.output;
+                Vec::new()
+            }
+            Stop::Trap(diagnostic) => vec![*diagnostic],
+            Stop::GaveUp(reason) => {
+                let functions = stmts
+                    .drain(..)
+                    .filter(|stmt| matches!(stmt.kind, StmtKind::Fn(_)));
+                let bindings = env.into_iter().map(|binding| {
+                    let span = binding.name.span;
+                    let value = literal(binding.value, binding.ty, span);
+                    Stmt {
+                        kind: StmtKind::Let(binding.name, Some(binding.ty), value),
+                        span,
+                    }
+                });
+                let mut residual: Vec<Stmt> = self
+                    .output
+                    .into_iter()
+                    .chain(bindings)
+                    .chain(
+                        rest.into_iter()
+                            .filter(|stmt| !matches!(stmt.kind, StmtKind::Fn(_))),
+                    )
+                    .chain(functions)
+                    .collect();
+                Redeclare {
+                    declared: HashSet::new(),
+                    renamed: HashMap::new(),
+                    next: decls_end(&residual),
+                }
+                .block(&mut residual);
+                *stmts = residual;
+                let span = span.expect("evaluation stops at a statement");
+                vec![Diagnostic::warning(
+                    span,
+                    format!("partial evaluation stopped here: {}", reason),
+                )]
+            }
+        }
+    }
+
+    /// Runs the top-level block `stmts`. When a statement inside it cannot
+    /// be run, returns why, along with the statements still to run from
+    /// there on; the variables in scope at that point are left in `env`.
+    fn run_block(
+        &mut self,
+        env: &mut Vec<Binding>,
+        stmts: &[Stmt],
+    ) -> Result<(), (Stop, Vec<Stmt>)> {
+        let scope = env.len();
+        for (i, stmt) in stmts.iter().enumerate() {
+            let resume = |mut rest: Vec<Stmt>| {
+                rest.extend(stmts[i + 1..].iter().cloned());
+                rest
+            };
+            let printed = self.output.len();
+            match &stmt.kind {
+                StmtKind::While(cond, body) => loop {
+                    let printed = self.output.len();
+                    match self.condition(env, cond) {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        Err(stop) => {
+                            self.rewind(&stop, printed);
+                            return Err((stop, resume(vec![stmt.clone()])));
+                        }
+                    }
+                    if let Err((stop, mut rest)) = self.run_block(env, body) {
+                        rest.push(stmt.clone());
+                        return Err((stop, resume(rest)));
+                    }
+                },
+                StmtKind::If(cond, then_body, elif_branches, else_body) => {
+                    let branches = std::iter::once((cond, then_body))
+                        .chain(elif_branches.iter().map(|(cond, body)| (cond, body)));
+                    let mut taken = else_body.as_ref();
+                    for (cond, body) in branches {
+                        match self.condition(env, cond) {
+                            Ok(true) => {
+                                taken = Some(body);
+                                break;
+                            }
+                            Ok(false) => {}
+                            Err(stop) => {
+                                self.rewind(&stop, printed);
+                                return Err((stop, resume(vec![stmt.clone()])));
+                            }
+                        }
+                    }
+                    if let Some(body) = taken {
+                        self.run_block(env, body)
+                            .map_err(|(stop, rest)| (stop, resume(rest)))?;
+                    }
+                }
+                _ => {
+                    if let Err(stop) = self.exec_stmt(env, stmt) {
+                        self.rewind(&stop, printed);
+                        return Err((stop, resume(vec![stmt.clone()])));
+                    }
+                }
+            }
+        }
+        env.truncate(scope);
+        Ok(())
+    }
+
+    fn exec_block(&mut self, env: &mut Vec<Binding>, stmts: &[Stmt]) -> Result<Flow, Stop> {
+        let scope = env.len();
+        for stmt in stmts {
+            match self.exec_stmt(env, stmt)? {
+                Flow::Next => {}
+                flow => return Ok(flow),
+            }
+        }
+        env.truncate(scope);
+        Ok(Flow::Next)
+    }
+
+    fn exec_stmt(&mut self, env: &mut Vec<Binding>, stmt: &Stmt) -> Result<Flow, Stop> {
+        self.step()?;
+        match &stmt.kind {
+            StmtKind::Let(name, ty, expr) => {
+                let value = self.eval(env, expr)?;
+                env.push(Binding {
+                    name: name.clone(),
+                    ty: ty.or(expr.ty).unwrap_or(Type::I64),
+                    value,
+                });
+            }
+            StmtKind::Assign(name, expr) => {
+                let value = self.eval(env, expr)?;
+                let binding = env
+                    .iter_mut()
+                    .rev()
+                    .find(|binding| binding.name.decl == name.decl)
+                    .expect("assigned variables are in scope");
+                binding.value = value;
+            }
+            StmtKind::Exit(expr) => {
+                let code = self.eval(env, expr)?;
+                return Err(Stop::Exit(Box::new(literal(
+                    code,
+                    expr.checked_ty(),
+                    expr.span,
+                ))));
+            }
+            StmtKind::Print(expr) => {
+                let value = self.eval(env, expr)?;
+                self.output.push(Stmt {
+                    kind: StmtKind::Print(literal(value, expr.checked_ty(), expr.span)),
+                    span: stmt.span,
+                });
+            }
+            StmtKind::While(cond, body) => {
+                while self.condition(env, cond)? {
+                    match self.exec_block(env, body)? {
+                        Flow::Next => {}
+                        flow => return Ok(flow),
+                    }
+                }
+            }
+            StmtKind::If(cond, then_body, elif_branches, else_body) => {
+                let branches = std::iter::once((cond, then_body))
+                    .chain(elif_branches.iter().map(|(cond, body)| (cond, body)));
+                for (cond, body) in branches {
+                    if self.condition(env, cond)? {
+                        return self.exec_block(env, body);
+                    }
+                }
+                if let Some(body) = else_body {
+                    return self.exec_block(env, body);
+                }
+            }
+            StmtKind::Fn(_) => {}
+            StmtKind::Return(expr) => {
+                let value = expr.as_ref().map(|expr| self.eval(env, expr)).transpose()?;
+                return Ok(Flow::Return(value));
+            }
+            StmtKind::Become(expr) => {
+                let ExprKind::Call(name, args) = &expr.kind else {
+                    unreachable!("`become` is checked to take a call");
+                };
+                let args = self.eval_args(env, args)?;
+                return Ok(Flow::Become(name.clone(), args));
+            }
+            StmtKind::Expr(expr) => {
+                self.eval(env, expr)?;
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    fn condition(&mut self, env: &[Binding], cond: &Expr) -> Result<bool, Stop> {
+        match self.eval(env, cond)? {
+            ExprKind::Bool(b) => Ok(b),
+            other => unreachable!("conditions are checked to be bool, found {:?}", other),
+        }
+    }
+
+    /// The value of `expr`, as a literal.
+    fn eval(&mut self, env: &[Binding], expr: &Expr) -> Result<ExprKind, Stop> {
+        self.step()?;
+        let value = match &expr.kind {
+            ExprKind::Ident(name) => env
+                .iter()
+                .rev()
+                .find(|binding| binding.name.decl == name.decl)
+                .map(|binding| binding.value.clone())
+                .expect("variables are in scope where they are used"),
+            ExprKind::Num(_) | ExprKind::Float(_) | ExprKind::Bool(_) => expr.kind.clone(),
+            ExprKind::BinOp(left, op, right) => {
+                self.eval_binop(env, expr.span, left, *op, right)?
+            }
+            ExprKind::UnaryOp(op, inner) => match (op, self.eval(env, inner)?) {
+                (Op::Sub, ExprKind::Num(n)) => {
+                    ExprKind::Num(inner.checked_ty().wrap(n.wrapping_neg()))
+                }
+                (Op::Sub, ExprKind::Float(f)) => ExprKind::Float(-f),
+                (op, _) => panic!("unsupported unary operator `{}`", op),
+            },
+            ExprKind::Cast(inner, to) => {
+                let value = self.eval(env, inner)?;
+                fold_cast(&value, *to).expect("casts are checked to apply")
+            }
+            ExprKind::Call(name, args) => self.eval_call(env, name, args, expr.checked_ty())?,
+        };
+        Ok(value)
+    }
+
+    // The operators and calls are evaluated out of line, to keep the stack
+    // frame of `eval` small; nested calls recurse through it.
+
+    fn eval_binop(
+        &mut self,
+        env: &[Binding],
+        span: Span,
+        left: &Expr,
+        op: Op,
+        right: &Expr,
+    ) -> Result<ExprKind, Stop> {
+        let l = self.eval(env, left)?;
+        let r = self.eval(env, right)?;
+        let ty = left.checked_ty();
+        if let Some(message) = division_trap(&l, op, &r, ty) {
+            return Err(Stop::Trap(Box::new(Diagnostic::error(span, message))));
+        }
+        Ok(fold_binop(&l, op, &r, ty).expect("operands are checked to match"))
+    }
+
+    fn eval_call(
+        &mut self,
+        env: &[Binding],
+        name: &str,
+        args: &[Expr],
+        ty: Type,
+    ) -> Result<ExprKind, Stop> {
+        let args = self.eval_args(env, args)?;
+        // The same choices as the selects the intrinsics lower to
+        let value = match (name, &args[..]) {
+            ("min", [a, b]) if holds(a, Op::Lt, b, ty) => a.clone(),
+            ("max", [a, b]) if holds(a, Op::Gt, b, ty) => a.clone(),
+            ("min" | "max", [_, b]) => b.clone(),
+            ("abs", [ExprKind::Num(n)]) if holds(&args[0], Op::Lt, &ExprKind::Num(0), ty) => {
+                ExprKind::Num(ty.wrap(n.wrapping_neg()))
+            }
+            ("abs", [a]) => a.clone(),
+            _ => self.call(name, args)?.unwrap_or(ExprKind::Num(0)),
+        };
+        Ok(value)
+    }
+
+    fn eval_args(&mut self, env: &[Binding], args: &[Expr]) -> Result<Vec<ExprKind>, Stop> {
+        args.iter().map(|arg| self.eval(env, arg)).collect()
+    }
+
+    /// Runs the function `name`, following the calls it makes with `become`
+    /// without nesting deeper.
+    fn call(&mut self, name: &str, mut args: Vec<ExprKind>) -> Result<Option<ExprKind>, Stop> {
+        if self.depth == MAX_DEPTH {
+            return Err(Stop::GaveUp("calls nest too deeply"));
+        }
+        self.depth += 1;
+        let mut f = Arc::clone(&self.functions[name]);
+        let result = loop {
+            let mut env = f
+                .params
+                .iter()
+                .zip(args)
+                .map(|((name, ty), value)| Binding {
+                    name: name.clone(),
+                    ty: *ty,
+                    value,
+                })
+                .collect();
+            match self.exec_block(&mut env, &f.body) {
+                Ok(Flow::Next) => break Ok(None),
+                Ok(Flow::Return(value)) => break Ok(value),
+                Ok(Flow::Become(callee, callee_args)) => {
+                    f = Arc::clone(&self.functions[&callee]);
+                    args = callee_args;
+                }
+                Err(stop) => break Err(stop),
+            }
+        };
+        self.depth -= 1;
+        result
+    }
+
+    /// Drops the prints made since `printed` when evaluation gave up, as the
+    /// statement that made them runs again at run time.
+    fn rewind(&mut self, stop: &Stop, printed: usize) {
+        if let Stop::GaveUp(_) = stop {
+            self.output.truncate(printed);
+        }
+    }
+
+    fn step(&mut self) -> Result<(), Stop> {
+        if self.steps == 0 {
+            return Err(Stop::GaveUp("the step budget ran out"));
+        }
+        self.steps -= 1;
+        Ok(())
+    }
+}
+
+/// Gives a new `DeclId` to each `let` that declares a variable already
+/// declared before it, and to the uses that `let` reaches. The residual
+/// program can repeat a `let`, as the rest of a loop's body is followed by
+/// the whole loop, and later passes expect one declaration per `DeclId`.
+struct Redeclare {
+    declared: HashSet<DeclId>,
+    /// The new id of each variable redeclared in the enclosing blocks.
+    renamed: HashMap<DeclId, DeclId>,
+    next: usize,
+}
+
+impl Redeclare {
+    fn block(&mut self, stmts: &mut [Stmt]) {
+        let outer = self.renamed.clone();
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+        self.renamed = outer;
+    }
+
+    fn stmt(&mut self, stmt: &mut Stmt) {
+        match &mut stmt.kind {
+            StmtKind::Let(name, _, expr) => {
+                self.expr(expr);
+                if let Some(decl) = name.decl {
+                    if !self.declared.insert(decl) {
+                        let fresh = DeclId(self.next);
+                        self.next += 1;
+                        self.renamed.insert(decl, fresh);
+                        name.decl = Some(fresh);
+                    }
+                }
+            }
+            StmtKind::Assign(name, expr) => {
+                self.expr(expr);
+                self.rename(name);
+            }
+            StmtKind::Exit(expr)
+            | StmtKind::Print(expr)
+            | StmtKind::Expr(expr)
+            | StmtKind::Return(Some(expr))
+            | StmtKind::Become(expr) => self.expr(expr),
+            StmtKind::Return(None) | StmtKind::Fn(_) => {}
+            StmtKind::While(cond, body) => {
+                self.expr(cond);
+                self.block(body);
+            }
+            StmtKind::If(cond, then_body, elif_branches, else_body) => {
+                self.expr(cond);
+                self.block(then_body);
+                for (elif_cond, elif_body) in elif_branches {
+                    self.expr(elif_cond);
+                    self.block(elif_body);
+                }
+                if let Some(else_stmts) = else_body {
+                    self.block(else_stmts);
+                }
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::Ident(name) => self.rename(name),
+            ExprKind::Num(_) | ExprKind::Float(_) | ExprKind::Bool(_) => {}
+            ExprKind::BinOp(left, _, right) => {
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::UnaryOp(_, inner) | ExprKind::Cast(inner, _) => self.expr(inner),
+            ExprKind::Call(_, args) => {
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+        }
+    }
+
+    fn rename(&self, name: &mut Name) {
+        if let Some(fresh) = name.decl.and_then(|decl| self.renamed.get(&decl)) {
+            name.decl = Some(*fresh);
+        }
+    }
+}
+
+/// One past the largest `DeclId` declared in `stmts`.
+fn decls_end(stmts: &[Stmt]) -> usize {
+    let end = |name: &Name| name.decl.map_or(0, |decl| decl.0 + 1);
+    stmts
+        .iter()
+        .map(|stmt| match &stmt.kind {
+            StmtKind::Let(name, ..) => end(name),
+            StmtKind::While(_, body) => decls_end(body),
+            StmtKind::If(_, then_body, elif_branches, else_body) => elif_branches
+                .iter()
+                .map(|(_, body)| decls_end(body))
+                .chain([decls_end(then_body)])
+                .chain(else_body.as_deref().map(decls_end))
+                .max()
+                .unwrap_or(0),
+            StmtKind::Fn(f) => f
+                .params
+                .iter()
+                .map(|(name, _)| end(name))
+                .chain([decls_end(&f.body)])
+                .max()
+                .unwrap_or(0),
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn holds(a: &ExprKind, op: Op, b: &ExprKind, ty: Type) -> bool {
+    fold_binop(a, op, b, ty) == Some(ExprKind::Bool(true))
+}
+
+fn literal(kind: ExprKind, ty: Type, span: Span) -> Expr {
+    Expr {
+        kind,
+        span,
+        ty: Some(ty),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lower::lower,
+        passes::PassManager,
+        ssa,
+        test_util::{checked, render},
+        verify::verify_module,
+    };
+
+    fn evaluate(source: &str, budget: usize) -> (Vec<Stmt>, Vec<String>) {
+        let mut stmts = checked(source);
+        let diagnostics = render(&PartialEvaluator::new(budget).evaluate(&mut stmts), source);
+        (stmts, diagnostics)
+    }
+
+    /// Calls `f` with the name each `let` in `stmts` declares.
+    fn walk_lets(stmts: &[Stmt], f: &mut impl FnMut(&Name)) {
+        for stmt in stmts {
+            match &stmt.kind {
+                StmtKind::Let(name, ..) => f(name),
+                StmtKind::While(_, body) => walk_lets(body, f),
+                StmtKind::If(_, then_body, elif_branches, else_body) => {
+                    walk_lets(then_body, f);
+                    for (_, body) in elif_branches {
+                        walk_lets(body, f);
+                    }
+                    walk_lets(else_body.as_deref().unwrap_or_default(), f);
+                }
+                StmtKind::Fn(function) => walk_lets(&function.body, f),
+                _ => {}
+            }
+        }
+    }
+
+    /// The literal each top-level `print` and `exit` of `stmts` takes.
+    fn effects(stmts: &[Stmt]) -> Vec<String> {
+        stmts
+            .iter()
+            .filter_map(|stmt| match &stmt.kind {
+                StmtKind::Print(expr) => Some(format!("print {:?}", expr.kind)),
+                StmtKind::Exit(expr) => Some(format!("exit {:?}", expr.kind)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_programs_run_to_their_end() {
+        let (stmts, diagnostics) = evaluate(
+            "let x = 10; let y = 15; if (x == y) { exit(x); } elif (x > y) { exit(x - y); } else { exit(y - x); }",
+            DEFAULT_BUDGET,
+        );
+        assert!(diagnostics.is_empty());
+        assert_eq!(effects(&stmts), ["exit Num(5)"]);
+        assert_eq!(stmts.len(), 1);
+
+        let (stmts, _) = evaluate(
+            "fn fib(n: u64) -> u64 { if (n < 2) { return n; } return fib(n - 1) + fib(n - 2); } \
+             fn count(n: i64, acc: i64) -> i64 { if (n == 0) { return acc; } become count(n - 1, acc + 1); } \
+             fn quit(code: u8) { print(code); exit(code); } \
+             let mut i: u8 = 254; while (i != 1) { i = i + 1; print(i); } \
+             print(fib(20)); print(count(50000, 0)); print(abs(-4) + min(2, 3)); print(1.0 / 4.0); \
+             quit(7); print(8);",
+            DEFAULT_BUDGET,
+        );
+        assert_eq!(
+            effects(&stmts),
+            [
+                "print Num(255)",
+                "print Num(0)",
+                "print Num(1)",
+                "print Num(6765)",
+                "print Num(50000)",
+                "print Num(6)",
+                "print Float(0.25)",
+                "print Num(7)",
+                "exit Num(7)",
+            ]
+        );
+        // Functions are gone with nothing left to call them
+        assert_eq!(stmts.len(), 9);
+    }
+
+    #[test]
+    fn test_budget_leaves_a_residual_program() {
+        let source = "fn sq(x: i64) -> i64 { return x * x; } \
+             let mut s = 0; let mut i = 0; \
+             while (i < 100) { let t = sq(i); print(t); s = s + t; i = i + 1; } exit(s as u8);";
+        let (mut stmts, diagnostics) = evaluate(source, 50);
+        assert_eq!(
+            diagnostics,
+            ["partial evaluation stopped here: the step budget ran out @ let t = sq(i);"]
+        );
+        // Two iterations ran, and the third stopped at its first statement,
+        // with the variables then in scope declared with their values
+        let value = |expr: &Expr| match expr.kind {
+            ExprKind::Num(n) => n.to_string(),
+            _ => "..".to_string(),
+        };
+        let residual: Vec<String> = stmts
+            .iter()
+            .map(|stmt| match &stmt.kind {
+                StmtKind::Let(name, _, expr) => format!("let {} = {}", name, value(expr)),
+                StmtKind::Print(expr) => format!("print {}", value(expr)),
+                StmtKind::While(..) => "while".to_string(),
+                StmtKind::Fn(f) => format!("fn {}", f.name),
+                _ => "..".to_string(),
+            })
+            .collect();
+        assert_eq!(
+            residual,
+            [
+                "print 0",
+                "print 1",
+                "let s = 1",
+                "let i = 2",
+                // The rest of the body, then the loop again
+                "let t = ..",
+                "print ..",
+                "..",
+                "..",
+                "while",
+                "..",
+                "fn sq",
+            ]
+        );
+
+        // Each variable is declared once, though `let t` is repeated
+        let mut decls = Vec::new();
+        walk_lets(&stmts, &mut |name| decls.push(name.decl.unwrap()));
+        let unique: HashSet<_> = decls.iter().collect();
+        assert_eq!(unique.len(), decls.len());
+
+        // What is left still compiles
+        let mut passes = PassManager::for_level(2);
+        assert!(passes.run_ast(&mut stmts).is_empty());
+        let mut module = lower(&stmts);
+        ssa::construct_module(&mut module);
+        passes.run_ir(&mut module);
+        verify_module(&module).unwrap();
+    }
+
+    #[test]
+    fn test_traps_are_errors() {
+        let (_, diagnostics) = evaluate(
+            "let mut i = 3; while (i > 0) { i = i - 1; } print(10 / i);",
+            DEFAULT_BUDGET,
+        );
+        assert_eq!(diagnostics, ["attempt to divide by zero @ 10 / i"]);
+        let (_, diagnostics) = evaluate(
+            "fn d(a: i64, b: i64) -> i64 { return a / b; } let m = -9223372036854775807 - 1; print(d(m, -1));",
+            DEFAULT_BUDGET,
+        );
+        assert_eq!(
+            diagnostics,
+            ["attempt to compute `i64::MIN / -1`, which would overflow @ a / b"]
+        );
+    }
+
+    #[test]
+    fn test_deep_calls_are_left_for_run_time() {
+        let source =
+            "fn down(n: i64) -> i64 { if (n == 0) { return 0; } return down(n - 1) + 1; } \
+             print(down(100)); print(down(1000)); exit(0);";
+        let (stmts, diagnostics) = evaluate(source, DEFAULT_BUDGET);
+        assert_eq!(
+            diagnostics,
+            ["partial evaluation stopped here: calls nest too deeply @ print(down(1000));"]
+        );
+        assert_eq!(effects(&stmts)[0], "print Num(100)");
+        let kinds: Vec<String> = stmts
+            .iter()
+            .map(|stmt| stmt.to_string().trim_end().to_string())
+            .collect();
+        assert_eq!(kinds[1..3], ["print(down(1000));", "exit(0);"]);
+        assert_eq!(stmts.len(), 4);
+    }
+}
@@ -0,0 +1,278 @@
+//! The optimisation passes, and which of them run at each level.
+//!
+//! AST passes run on the checked program before it is lowered, IR passes on
+//! the module once it is in SSA form, so every AST pass comes before every
+//! IR pass. A level picks a list of passes; `--passes=` names one outright.
+//! The IR is verified after every IR pass, so a broken pass is caught by name.
+
+use std::{
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    dce::DeadCodeEliminator, diagnostic::Diagnostic, fold::ConstFolder, gvn, inline, ir::Module,
+    loops, parser::Stmt, select, unroll, verify::verify_module,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    Fold,
+    Dce,
+    Inline,
+    Gvn,
+    Select,
+    Loops,
+    Unroll,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 7] = [
+        Pass::Fold,
+        Pass::Dce,
+        Pass::Inline,
+        Pass::Gvn,
+        Pass::Select,
+        Pass::Loops,
+        Pass::Unroll,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Fold => "fold",
+            Pass::Dce => "dce",
+            Pass::Inline => "inline",
+            Pass::Gvn => "gvn",
+            Pass::Select => "select",
+            Pass::Loops => "loops",
+            Pass::Unroll => "unroll",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Pass> {
+        Pass::ALL.into_iter().find(|pass| pass.name() == name)
+    }
+
+    fn on_ast(self) -> bool {
+        matches!(self, Pass::Fold | Pass::Dce)
+    }
+}
+
+pub struct PassManager {
+    passes: Vec<Pass>,
+    pub inline_threshold: usize,
+    /// How many copies of a counted loop's body partial unrolling makes.
+    pub unroll_factor: usize,
+    /// Passes whose result is printed to stderr once they have run.
+    print_after: Vec<Pass>,
+    /// How long each pass run took, in the order they ran.
+    timings: Vec<(Pass, Duration)>,
+}
+
+impl PassManager {
+    /// The passes for `-O<level>`. `-O0` runs none, so even constant
+    /// arithmetic that would trap is left for run time. `-O3` inlines larger
+    /// functions and numbers values again once loops are simplified and
+    /// unrolled.
+    pub fn for_level(level: u8) -> Self {
+        use Pass::*;
+        let (passes, inline_threshold) = match level {
+            0 => (vec![], inline::DEFAULT_THRESHOLD),
+            1 => (vec![Fold, Dce, Gvn, Select], inline::DEFAULT_THRESHOLD),
+            2 => (
+                vec![Fold, Dce, Inline, Gvn, Select, Loops, Unroll],
+                inline::DEFAULT_THRESHOLD,
+            ),
+            _ => (
+                vec![Fold, Dce, Inline, Gvn, Select, Loops, Unroll, Gvn],
+                4 * inline::DEFAULT_THRESHOLD,
+            ),
+        };
+        Self {
+            passes,
+            inline_threshold,
+            unroll_factor: unroll::DEFAULT_FACTOR,
+            print_after: Vec::new(),
+            timings: Vec::new(),
+        }
+    }
+
+    /// Runs exactly `passes`, in order. Fails when an AST pass is named
+    /// after an IR pass, as the IR is lowered from the AST between them.
+    pub fn with_passes(passes: Vec<Pass>) -> Result<Self, String> {
+        if let Some(i) = passes.iter().position(|pass| !pass.on_ast()) {
+            if let Some(late) = passes[i..].iter().find(|pass| pass.on_ast()) {
+                return Err(format!(
+                    "`{}` runs on the AST, so it must come before `{}`",
+                    late.name(),
+                    passes[i].name()
+                ));
+            }
+        }
+        Ok(Self {
+            passes,
+            ..Self::for_level(0)
+        })
+    }
+
+    pub fn passes(&self) -> &[Pass] {
+        &self.passes
+    }
+
+    /// Prints the program after `pass` runs. Fails when `pass` is not one
+    /// that runs, as nothing would ever be printed.
+    pub fn print_after(&mut self, pass: Pass) -> Result<(), String> {
+        if !self.passes.contains(&pass) {
+            return Err(format!(
+                "cannot print after `{}`, as it is not one of the passes that run",
+                pass.name()
+            ));
+        }
+        self.print_after.push(pass);
+        Ok(())
+    }
+
+    /// Runs the AST passes over `stmts`, returning their diagnostics. Stops
+    /// after the first pass that reports an error.
+    pub fn run_ast(&mut self, stmts: &mut Vec<Stmt>) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for pass in self.passes.clone() {
+            let start = Instant::now();
+            let found = match pass {
+                Pass::Fold => ConstFolder::new().fold(stmts),
+                Pass::Dce => DeadCodeEliminator::new().eliminate(stmts),
+                _ => continue,
+            };
+            self.timings.push((pass, start.elapsed()));
+            let failed = found.iter().any(Diagnostic::is_error);
+            diagnostics.extend(found);
+            if failed {
+                break;
+            }
+            if self.print_after.contains(&pass) {
+                eprintln!("; after {}", pass.name());
+                for stmt in stmts.iter() {
+                    eprint!("{}", stmt);
+                }
+            }
+        }
+        diagnostics
+    }
+
+    /// Runs the IR passes over `module`, which must be in SSA form.
+    pub fn run_ir(&mut self, module: &mut Module) {
+        for pass in self.passes.clone() {
+            let start = Instant::now();
+            match pass {
+                Pass::Inline => inline::inline_module(module, self.inline_threshold),
+                Pass::Gvn => gvn::optimize_module(module),
+                Pass::Select => select::optimize_module(module),
+                Pass::Loops => loops::optimize_module(module),
+                Pass::Unroll => unroll::optimize_module(module, self.unroll_factor),
+                Pass::Fold | Pass::Dce => continue,
+            }
+            self.timings.push((pass, start.elapsed()));
+            if let Err(errors) = verify_module(module) {
+                panic!("invalid IR after {}:\n{}", pass.name(), errors.join("\n"));
+            }
+            if self.print_after.contains(&pass) {
+                eprint!("; after {}\n{}", pass.name(), module);
+            }
+        }
+    }
+
+    pub fn timings(&self) -> &[(Pass, Duration)] {
+        &self.timings
+    }
+
+    /// A table of the time each pass took, with the total at the bottom.
+    pub fn report_timings(&self) -> String {
+        let mut out = String::new();
+        for (pass, time) in &self.timings {
+            writeln!(out, "{:<8}{:>12.3?}", pass.name(), time).unwrap();
+        }
+        let total: Duration = self.timings.iter().map(|(_, time)| *time).sum();
+        writeln!(out, "{:<8}{:>12.3?}", "total", total).unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ir::Instr, lower::lower, ssa, test_util::checked};
+
+    fn compile(source: &str, manager: &mut PassManager) -> Module {
+        let mut stmts = checked(source);
+        assert!(manager.run_ast(&mut stmts).is_empty());
+        let mut module = lower(&stmts);
+        ssa::construct_module(&mut module);
+        manager.run_ir(&mut module);
+        module
+    }
+
+    fn instrs(module: &Module) -> usize {
+        module
+            .functions
+            .iter()
+            .flat_map(|f| &f.blocks)
+            .map(|block| block.instrs.len())
+            .sum()
+    }
+
+    #[test]
+    fn test_levels() {
+        assert!(PassManager::for_level(0).passes().is_empty());
+        let o2 = PassManager::for_level(2);
+        let o3 = PassManager::for_level(3);
+        assert!(o2.passes().iter().all(|pass| o3.passes().contains(pass)));
+        assert!(o3.inline_threshold > o2.inline_threshold);
+    }
+
+    #[test]
+    fn test_passes_by_hand() {
+        let mut manager = PassManager::with_passes(vec![Pass::Fold, Pass::Gvn, Pass::Gvn]).unwrap();
+        assert!(manager.print_after(Pass::Gvn).is_ok());
+        assert_eq!(
+            manager.print_after(Pass::Dce).err().unwrap(),
+            "cannot print after `dce`, as it is not one of the passes that run"
+        );
+        assert_eq!(manager.passes(), [Pass::Fold, Pass::Gvn, Pass::Gvn]);
+        assert_eq!(
+            PassManager::with_passes(vec![Pass::Gvn, Pass::Dce])
+                .err()
+                .unwrap(),
+            "`dce` runs on the AST, so it must come before `gvn`"
+        );
+        assert_eq!(Pass::from_name("loops"), Some(Pass::Loops));
+        assert_eq!(Pass::from_name("unroll"), Some(Pass::Unroll));
+        assert_eq!(Pass::from_name("vectorize"), None);
+    }
+
+    #[test]
+    fn test_higher_levels_do_more() {
+        let source = "fn sq(x: i64) -> i64 { return x * x; } \
+             let a = 2 + 3; let mut s = 0; let mut i = 0; \
+             while (i < 10) { s = s + sq(i) + a * 4; i = i + 1; } exit(s as u8);";
+        let mut o0 = PassManager::for_level(0);
+        let mut o2 = PassManager::for_level(2);
+        let unoptimized = compile(source, &mut o0);
+        let optimized = compile(source, &mut o2);
+        assert!(o0.timings().is_empty());
+        let ran: Vec<Pass> = o2.timings().iter().map(|(pass, _)| *pass).collect();
+        assert_eq!(ran, o2.passes());
+        assert!(instrs(&optimized) < instrs(&unoptimized));
+        let start = &optimized.functions[0];
+        assert!(!start
+            .blocks
+            .iter()
+            .flat_map(|block| &block.instrs)
+            .any(|instr| matches!(instr, Instr::Call { .. })));
+        assert!(o2
+            .report_timings()
+            .lines()
+            .last()
+            .unwrap()
+            .starts_with("total"));
+    }
+}
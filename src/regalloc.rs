@@ -0,0 +1,288 @@
+//! Linear-scan register allocation over phi-free IR.
+//!
+//! Every value gets a live interval, the span of instruction positions from
+//! its first definition to its last use, widened to cover every block it is
+//! live through. Intervals are visited by start position and each takes a
+//! free register, or the register of the active interval that ends last,
+//! which is then spilled to the stack instead. A value live across a call
+//! only gets a callee-saved register, since calls clobber the others.
+
+use std::collections::BTreeSet;
+
+use crate::{
+    codegen::ARG_REGS,
+    ir::{Function, Instr, Operand, Value},
+    liveness::Liveness,
+};
+
+/// Registers the generated code keeps for itself: rax and rdx for results
+/// and division, r11 for operands that need a register for a moment.
+pub const SCRATCH_REGS: [&str; 3] = ["rax", "rdx", "r11"];
+
+/// Registers a call may clobber that are free for allocation.
+const CALLER_SAVED: [&str; 6] = ["rcx", "rsi", "rdi", "r8", "r9", "r10"];
+
+/// Registers a call preserves; a function that uses one must restore it.
+pub const CALLEE_SAVED: [&str; 5] = ["rbx", "r12", "r13", "r14", "r15"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Reg(&'static str),
+    /// Offset of an 8-byte slot from rbp.
+    Stack(i64),
+}
+
+pub struct Allocation {
+    /// Where each value lives; `None` for values the function never assigns.
+    pub locations: Vec<Option<Location>>,
+    /// Callee-saved registers the function uses, in a fixed order.
+    pub callee_saved: Vec<&'static str>,
+    /// Number of stack slots used for spilled values.
+    pub spill_slots: usize,
+}
+
+impl Allocation {
+    /// Gives `value` a stack slot of its own.
+    fn spill(&mut self, value: Value) {
+        self.spill_slots += 1;
+        self.locations[value.0] = Some(Location::Stack(-8 * self.spill_slots as i64));
+    }
+}
+
+pub fn allocate(f: &Function) -> Allocation {
+    let liveness = Liveness::compute(f);
+
+    // Number the instructions, leaving 0 for the parameters
+    let mut intervals: Vec<Option<(usize, usize)>> = vec![None; f.values.len()];
+    let mut extend = |value: Value, pos: usize| {
+        let interval = intervals[value.0].get_or_insert((pos, pos));
+        interval.0 = interval.0.min(pos);
+        interval.1 = interval.1.max(pos);
+    };
+    for &param in &f.params {
+        extend(param, 0);
+    }
+    let mut calls = Vec::new();
+    // The operand whose register an instruction's destination would like to
+    // share, so a copy or an update in place needs no move
+    let mut hints: Vec<Option<Value>> = vec![None; f.values.len()];
+    let mut pos = 1;
+    for (id, block) in f.blocks.iter().enumerate() {
+        let start = pos;
+        for instr in &block.instrs {
+            for value in instr.operands().into_iter().filter_map(Operand::as_value) {
+                extend(value, pos);
+            }
+            if let Some(dst) = instr.dst() {
+                extend(dst, pos);
+            }
+            match instr {
+                Instr::Call { .. } | Instr::Print { .. } => calls.push(pos),
+                Instr::Copy {
+                    dst,
+                    src: Operand::Value(src),
+                }
+                | Instr::Binary {
+                    dst,
+                    lhs: Operand::Value(src),
+                    ..
+                }
+                | Instr::Neg {
+                    dst,
+                    src: Operand::Value(src),
+                    ..
+                }
+                | Instr::Cast {
+                    dst,
+                    src: Operand::Value(src),
+                    ..
+                } => hints[dst.0] = Some(*src),
+                _ => {}
+            }
+            pos += 1;
+        }
+        for value in block
+            .term
+            .operands()
+            .into_iter()
+            .filter_map(Operand::as_value)
+        {
+            extend(value, pos);
+        }
+        let end = pos;
+        pos += 1;
+        for &value in &liveness.live_in[id] {
+            extend(value, start);
+        }
+        for &value in &liveness.live_out[id] {
+            extend(value, end);
+        }
+    }
+
+    let mut order: Vec<(usize, usize, Value)> = intervals
+        .iter()
+        .enumerate()
+        .filter_map(|(value, interval)| interval.map(|(start, end)| (start, end, Value(value))))
+        .collect();
+    order.sort();
+
+    let mut allocation = Allocation {
+        locations: vec![None; f.values.len()],
+        callee_saved: Vec::new(),
+        spill_slots: 0,
+    };
+    let mut used_callee_saved = BTreeSet::new();
+    // Intervals holding a register: (end, value, register)
+    let mut active: Vec<(usize, Value, &'static str)> = Vec::new();
+    for (start, end, value) in order {
+        // A value that dies where another is defined can hand over its register
+        active.retain(|&(active_end, _, _)| active_end > start);
+
+        let crosses_call = calls.iter().any(|&call| start < call && call < end);
+        let candidates: Vec<&'static str> = if crosses_call {
+            CALLEE_SAVED.to_vec()
+        } else {
+            CALLER_SAVED.iter().chain(&CALLEE_SAVED).copied().collect()
+        };
+        let is_free = |reg: &&'static str| active.iter().all(|&(_, _, used)| used != *reg);
+        let preferred = match (f.params.iter().position(|&p| p == value), hints[value.0]) {
+            (Some(i), _) => Some(ARG_REGS[i]),
+            (None, Some(src)) => match allocation.locations[src.0] {
+                Some(Location::Reg(reg)) => Some(reg),
+                _ => None,
+            },
+            (None, None) => None,
+        };
+        let reg = preferred
+            .filter(|reg| candidates.contains(reg) && is_free(reg))
+            .or_else(|| candidates.iter().copied().find(is_free));
+
+        let reg = match reg {
+            Some(reg) => reg,
+            None => {
+                // Spill whichever candidate holder lives longest, which may be
+                // this value itself
+                let victim = active
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (_, _, reg))| candidates.contains(reg))
+                    .max_by_key(|(_, (active_end, _, _))| *active_end)
+                    .map(|(i, &(active_end, victim, reg))| (i, active_end, victim, reg));
+                match victim {
+                    Some((i, victim_end, victim, reg)) if victim_end > end => {
+                        allocation.spill(victim);
+                        active.remove(i);
+                        reg
+                    }
+                    _ => {
+                        allocation.spill(value);
+                        continue;
+                    }
+                }
+            }
+        };
+        if CALLEE_SAVED.contains(&reg) {
+            used_callee_saved.insert(reg);
+        }
+        allocation.locations[value.0] = Some(Location::Reg(reg));
+        active.push((end, value, reg));
+    }
+    allocation.callee_saved = CALLEE_SAVED
+        .iter()
+        .copied()
+        .filter(|reg| used_callee_saved.contains(reg))
+        .collect();
+    allocation
+}
+
+/// The name of the low `size` bytes of the 64-bit register `reg`.
+pub fn sub_register(reg: &str, size: usize) -> String {
+    if let Some(number) = reg.strip_prefix('r').filter(|n| n.parse::<u8>().is_ok()) {
+        let suffix = match size {
+            1 => "b",
+            2 => "w",
+            4 => "d",
+            _ => "",
+        };
+        return format!("r{}{}", number, suffix);
+    }
+    let base = &reg[1..];
+    match size {
+        // rax..rdx have al..dl; rsi, rdi, rbp and rsp have sil, dil, bpl and spl
+        1 if base.ends_with('x') => format!("{}l", &base[..1]),
+        1 => format!("{}l", base),
+        2 => base.to_string(),
+        4 => format!("e{}", base),
+        _ => reg.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ssa, test_util::ssa_module};
+
+    fn allocate_source(source: &str) -> (Function, Allocation) {
+        let mut module = ssa_module(source);
+        ssa::destruct_module(&mut module);
+        let f = module.functions.remove(0);
+        let allocation = allocate(&f);
+        (f, allocation)
+    }
+
+    #[test]
+    fn test_loop_lives_in_registers() {
+        let (_, allocation) = allocate_source(
+            "let mut i = 0; let mut s = 0; while (i < 100) { s = s + i * 2; i = i + 1; } exit(s);",
+        );
+        assert_eq!(allocation.spill_slots, 0);
+        assert!(allocation.callee_saved.is_empty());
+    }
+
+    #[test]
+    fn test_values_live_across_calls_are_callee_saved() {
+        let (f, allocation) = allocate_source("let a = 1; let b = 2; print(a); exit(a + b);");
+        for (value, info) in f.values.iter().enumerate() {
+            if let (Some(name), Some(Location::Reg(reg))) =
+                (&info.name, allocation.locations[value])
+            {
+                assert!(CALLEE_SAVED.contains(&reg), "{} in {}", name, reg);
+            }
+        }
+        assert_eq!(allocation.callee_saved, ["rbx", "r12"]);
+    }
+
+    #[test]
+    fn test_spills_under_pressure() {
+        let names: Vec<String> = (0..14).map(|i| format!("v{}", i)).collect();
+        let lets: String = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| format!("let {} = {}; ", name, i))
+            .collect();
+        let (_, allocation) = allocate_source(&format!("{}exit({});", lets, names.join(" + ")));
+        // Eleven registers are available; the sum keeps every value live
+        assert_eq!(allocation.spill_slots, 3);
+        let mut regs: Vec<&str> = allocation
+            .locations
+            .iter()
+            .filter_map(|location| match location {
+                Some(Location::Reg(reg)) => Some(*reg),
+                _ => None,
+            })
+            .collect();
+        regs.sort();
+        regs.dedup();
+        assert!(regs.iter().all(|reg| !SCRATCH_REGS.contains(reg)));
+    }
+
+    #[test]
+    fn test_sub_registers() {
+        assert_eq!(sub_register("rax", 1), "al");
+        assert_eq!(sub_register("rsi", 1), "sil");
+        assert_eq!(sub_register("rbx", 4), "ebx");
+        assert_eq!(sub_register("r12", 2), "r12w");
+        assert_eq!(sub_register("r9", 1), "r9b");
+        assert_eq!(sub_register("rcx", 8), "rcx");
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+
+use crate::{
+    diagnostic::Diagnostic,
+    lexer::Span,
+    parser::{DeclId, Expr, ExprKind, Name, Stmt, StmtKind},
+    types::Type,
+};
+
+struct Decl {
+    name: String,
+    span: Span,
+    mutable: bool,
+    used: bool,
+    assigned: bool,
+}
+
+/// Binds every variable use to the declaration it refers to.
+///
+/// Variables are lexically scoped: a `let` is visible from the statement
+/// after it to the end of the enclosing block, and each function body only
+/// sees its own parameters and locals. Names that don't resolve are reported
+/// as used before their definition, possibly uninitialized (declared only
+/// inside an earlier branch or loop body) or undefined. A parameter list
+/// may bind each name only once.
+///
+/// Bindings are immutable unless declared `let mut`, so assigning to any
+/// other binding is an error. Declarations that are never read, and `mut`
+/// declarations that are never assigned, produce warnings.
+pub struct Resolver {
+    decls: Vec<Decl>,
+    scopes: Vec<HashMap<String, DeclId>>,
+    /// Declarations of the function being resolved, in source order.
+    function_decls: Vec<DeclId>,
+    /// Uses that found no declaration in scope, judged at the end of the function.
+    unresolved: Vec<(String, Span)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Self {
+            decls: Vec::new(),
+            scopes: Vec::new(),
+            function_decls: Vec::new(),
+            unresolved: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Resolves `stmts` in place, returning the errors and warnings found.
+    pub fn resolve(mut self, stmts: &mut [Stmt]) -> Vec<Diagnostic> {
+        // The top-level statements form the body of the program's entry point
+        self.resolve_body(&mut [], |this| {
+            for stmt in stmts.iter_mut() {
+                if !matches!(stmt.kind, StmtKind::Fn(_)) {
+                    this.resolve_stmt(stmt);
+                }
+            }
+        });
+        for stmt in stmts.iter_mut() {
+            if let StmtKind::Fn(f) = &mut stmt.kind {
+                let body = &mut f.body;
+                self.resolve_body(&mut f.params, |this| this.resolve_block(body));
+            }
+        }
+        self.diagnostics
+    }
+
+    fn resolve_body(&mut self, params: &mut [(Name, Type)], body: impl FnOnce(&mut Self)) {
+        self.scopes.push(HashMap::new());
+        for (param, _) in params {
+            let scope = self
+                .scopes
+                .last()
+                .expect("parameters are declared in a scope");
+            if let Some(&first) = scope.get(&param.name) {
+                // Don't also warn that the first binding is unused
+                let first = &mut self.decls[first.0];
+                first.used = true;
+                self.diagnostics.push(
+                    Diagnostic::error(
+                        param.span,
+                        format!(
+                            "identifier `{}` is bound more than once in this parameter list",
+                            param.name
+                        ),
+                    )
+                    .with_note(first.span, format!("`{}` is first bound here", param.name)),
+                );
+            }
+            self.declare(param);
+        }
+        body(self);
+        self.scopes.pop();
+
+        for (name, span) in std::mem::take(&mut self.unresolved) {
+            let candidates: Vec<DeclId> = self
+                .function_decls
+                .iter()
+                .copied()
+                .filter(|id| self.decls[id.0].name == name)
+                .collect();
+            let later = candidates
+                .iter()
+                .find(|id| self.decls[id.0].span.start > span.start);
+            let diagnostic = if let Some(later) = later {
+                // Don't also warn that the declaration is unused
+                let decl = &mut self.decls[later.0];
+                decl.used = true;
+                Diagnostic::error(span, format!("use of `{}` before its definition", name))
+                    .with_note(decl.span, format!("`{}` is defined here", name))
+            } else if let Some(earlier) = candidates.last() {
+                let decl = &mut self.decls[earlier.0];
+                decl.used = true;
+                Diagnostic::error(span, format!("`{}` is possibly uninitialized here", name))
+                    .with_note(
+                        decl.span,
+                        format!(
+                            "`{}` is only defined inside a block that may not have run",
+                            name
+                        ),
+                    )
+            } else {
+                Diagnostic::error(
+                    span,
+                    format!("cannot find variable `{}` in this scope", name),
+                )
+            };
+            self.diagnostics.push(diagnostic);
+        }
+
+        for id in std::mem::take(&mut self.function_decls) {
+            let decl = &self.decls[id.0];
+            if !decl.used && !decl.name.starts_with('_') {
+                self.diagnostics.push(Diagnostic::warning(
+                    decl.span,
+                    format!("unused variable `{}`", decl.name),
+                ));
+            }
+            if decl.mutable && !decl.assigned {
+                self.diagnostics.push(Diagnostic::warning(
+                    decl.span,
+                    format!("variable `{}` does not need to be mutable", decl.name),
+                ));
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &mut Name) {
+        let id = DeclId(self.decls.len());
+        self.decls.push(Decl {
+            name: name.name.clone(),
+            span: name.span,
+            mutable: name.mutable,
+            used: false,
+            assigned: false,
+        });
+        self.function_decls.push(id);
+        self.scopes
+            .last_mut()
+            .expect("declarations happen inside a scope")
+            .insert(name.name.clone(), id);
+        name.decl = Some(id);
+    }
+
+    /// Binds `name` to the innermost declaration in scope, if there is one.
+    fn lookup(&mut self, name: &mut Name) -> Option<DeclId> {
+        let found = self
+            .scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name.name).copied());
+        match found {
+            Some(id) => name.decl = Some(id),
+            None => self.unresolved.push((name.name.clone(), name.span)),
+        }
+        found
+    }
+
+    fn resolve_block(&mut self, stmts: &mut [Stmt]) {
+        self.scopes.push(HashMap::new());
+        for stmt in stmts {
+            self.resolve_stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn resolve_stmt(&mut self, stmt: &mut Stmt) {
+        match &mut stmt.kind {
+            StmtKind::Let(name, _, expr) => {
+                // The initializer still sees any outer variable of the same name
+                self.resolve_expr(expr);
+                self.declare(name);
+            }
+            StmtKind::Assign(name, expr) => {
+                self.resolve_expr(expr);
+                let Some(id) = self.lookup(name) else {
+                    return;
+                };
+                let decl = &mut self.decls[id.0];
+                decl.assigned = true;
+                if !decl.mutable {
+                    let diagnostic = Diagnostic::error(
+                        stmt.span,
+                        format!("cannot assign to immutable variable `{}`", name),
+                    )
+                    .with_note(
+                        decl.span,
+                        format!(
+                            "`{}` is declared here; declare it with `let mut` to allow assignment",
+                            name
+                        ),
+                    );
+                    self.diagnostics.push(diagnostic);
+                }
+            }
+            StmtKind::Exit(expr)
+            | StmtKind::Print(expr)
+            | StmtKind::Expr(expr)
+            | StmtKind::Become(expr) => {
+                self.resolve_expr(expr);
+            }
+            StmtKind::Return(value) => {
+                if let Some(expr) = value {
+                    self.resolve_expr(expr);
+                }
+            }
+            StmtKind::While(cond, body) => {
+                self.resolve_expr(cond);
+                self.resolve_block(body);
+            }
+            StmtKind::If(cond, then_body, elif_branches, else_body) => {
+                self.resolve_expr(cond);
+                self.resolve_block(then_body);
+                for (elif_cond, elif_body) in elif_branches {
+                    self.resolve_expr(elif_cond);
+                    self.resolve_block(elif_body);
+                }
+                if let Some(else_stmts) = else_body {
+                    self.resolve_block(else_stmts);
+                }
+            }
+            // Nested functions are rejected by the type checker
+            StmtKind::Fn(_) => {}
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::Ident(name) => {
+                if let Some(id) = self.lookup(name) {
+                    self.decls[id.0].used = true;
+                }
+            }
+            ExprKind::BinOp(left, _, right) => {
+                self.resolve_expr(left);
+                self.resolve_expr(right);
+            }
+            ExprKind::UnaryOp(_, inner) | ExprKind::Cast(inner, _) => self.resolve_expr(inner),
+            ExprKind::Call(_, args) => {
+                for arg in args {
+                    self.resolve_expr(arg);
+                }
+            }
+            ExprKind::Num(_) | ExprKind::Float(_) | ExprKind::Bool(_) => {}
+        }
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::test_util::render;
+
+    fn resolve(source: &str) -> (Vec<Stmt>, Vec<String>) {
+        let tokens = Lexer::new(source).tokenize_spanned();
+        let mut stmts = Parser::with_spans(tokens).parse();
+        let messages = render(&Resolver::new().resolve(&mut stmts), source);
+        (stmts, messages)
+    }
+
+    #[test]
+    fn test_shadowing_gets_distinct_ids() {
+        let (stmts, messages) =
+            resolve("let x = 1; if (true) { let x = x + 1; exit(x); } exit(x);");
+        assert!(messages.is_empty(), "{:?}", messages);
+        let decl_of = |expr: &Expr| match &expr.kind {
+            ExprKind::Ident(name) => name.decl,
+            _ => None,
+        };
+        let outer = match &stmts[0].kind {
+            StmtKind::Let(name, _, _) => name.decl,
+            _ => unreachable!(),
+        };
+        match &stmts[1].kind {
+            StmtKind::If(_, body, _, _) => match (&body[0].kind, &body[1].kind) {
+                (StmtKind::Let(inner, _, init), StmtKind::Exit(use_inner)) => {
+                    let ExprKind::BinOp(left, _, _) = &init.kind else {
+                        unreachable!()
+                    };
+                    assert_eq!(decl_of(left), outer);
+                    assert_eq!(decl_of(use_inner), inner.decl);
+                    assert_ne!(inner.decl, outer);
+                }
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+        match &stmts[2].kind {
+            StmtKind::Exit(expr) => assert_eq!(decl_of(expr), outer),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_use_before_definition() {
+        let (_, messages) = resolve("exit(y); let y = 1;");
+        assert_eq!(
+            messages,
+            vec!["use of `y` before its definition @ y (`y` is defined here @ y)"]
+        );
+    }
+
+    #[test]
+    fn test_possibly_uninitialized() {
+        let (_, messages) = resolve("let c = true; if (c) { let y = 1; } exit(y);");
+        assert_eq!(
+            messages,
+            vec!["`y` is possibly uninitialized here @ y (`y` is only defined inside a block that may not have run @ y)"]
+        );
+    }
+
+    #[test]
+    fn test_unused_and_undefined() {
+        let (_, messages) = resolve("fn f(a: i64, _b: i64) { let t = 1; print(z); } let x = 2;");
+        assert_eq!(
+            messages,
+            vec![
+                "unused variable `x` @ x",
+                "cannot find variable `z` in this scope @ z",
+                "unused variable `a` @ a",
+                "unused variable `t` @ t",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_parameters() {
+        let source = "fn f(a: i64, b: i64, a: i64) -> i64 { return a + b; } print(f(1, 2, 3));";
+        let (_, messages) = resolve(source);
+        assert_eq!(
+            messages,
+            vec!["identifier `a` is bound more than once in this parameter list @ a (`a` is first bound here @ a)"]
+        );
+    }
+
+    #[test]
+    fn test_mutability() {
+        let (_, messages) = resolve(
+            "let mut i = 0; let n = 3; let mut spare = 1; while (i < n) { i = i + 1; } n = spare; exit(i);",
+        );
+        assert_eq!(
+            messages,
+            vec![
+                "cannot assign to immutable variable `n` @ n = spare; (`n` is declared here; declare it with `let mut` to allow assignment @ n)",
+                "variable `spare` does not need to be mutable @ spare",
+            ]
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Support routines linked into every program that prints.
+//!
+//! Each `_crab_print_*` routine takes its argument in `rdi` (or `xmm0` for
+//! floats), writes it to stdout followed by a newline, and may clobber any
+//! caller-saved register.
+
+pub const RUNTIME: &str = r#"
+section .data
+_crab_true: db "true", 10
+_crab_false: db "false", 10
+_crab_nan: db "NaN", 10
+_crab_inf: db "inf", 10
+
+section .text
+; writes the unsigned value in rdi, zero-padded to at least rsi digits
+_crab_write_u64:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 32
+    lea r8, [rbp-1]
+    mov rax, rdi
+    mov rcx, 10
+.digit:
+    xor rdx, rdx
+    div rcx
+    add dl, '0'
+    mov [r8], dl
+    dec r8
+    dec rsi
+    test rax, rax
+    jnz .digit
+    cmp rsi, 0
+    jg .digit
+    lea rsi, [r8+1]
+    mov rdx, rbp
+    sub rdx, rsi
+    mov rax, 1
+    mov rdi, 1
+    syscall
+    leave
+    ret
+
+; writes the byte in rdi
+_crab_write_char:
+    push rdi
+    mov rax, 1
+    mov rdi, 1
+    mov rsi, rsp
+    mov rdx, 1
+    syscall
+    pop rdi
+    ret
+
+_crab_print_u64:
+    mov rsi, 1
+    call _crab_write_u64
+    mov rdi, 10
+    jmp _crab_write_char
+
+_crab_print_i64:
+    push rbx
+    mov rbx, rdi
+    test rbx, rbx
+    jns .positive
+    mov rdi, '-'
+    call _crab_write_char
+    neg rbx
+.positive:
+    mov rdi, rbx
+    pop rbx
+    jmp _crab_print_u64
+
+_crab_print_bool:
+    mov rsi, _crab_false
+    mov rdx, 6
+    test rdi, rdi
+    jz .write
+    mov rsi, _crab_true
+    mov rdx, 5
+.write:
+    mov rax, 1
+    mov rdi, 1
+    syscall
+    ret
+
+; prints xmm0 rounded to six decimal places, or from 1e12 upwards as a
+; mantissa rounded to six decimal places and a power of ten
+_crab_print_f64:
+    push rbx
+    push r12
+    ucomisd xmm0, xmm0
+    jp .nan
+    movq rax, xmm0
+    test rax, rax
+    jns .positive
+    btr rax, 63
+    movq xmm0, rax
+    mov rdi, '-'
+    call _crab_write_char
+.positive:
+    mov rax, 0x7ff0000000000000
+    movq xmm1, rax
+    ucomisd xmm0, xmm1
+    je .inf
+    ; rbx counts the powers of ten divided out
+    xor rbx, rbx
+    mov rax, 1000000000000
+    cvtsi2sd xmm1, rax
+    ucomisd xmm0, xmm1
+    jb .fixed
+    mov rax, 10
+    cvtsi2sd xmm1, rax
+.scale:
+    divsd xmm0, xmm1
+    inc rbx
+    ucomisd xmm0, xmm1
+    jae .scale
+.fixed:
+    mov rax, 1000000
+    cvtsi2sd xmm1, rax
+    mulsd xmm0, xmm1
+    cvtsd2si rax, xmm0
+    test rbx, rbx
+    jz .split
+    ; a mantissa that rounds up to 10 becomes 1 with one more power
+    cmp rax, 10000000
+    jb .split
+    mov rax, 1000000
+    inc rbx
+.split:
+    xor rdx, rdx
+    mov rcx, 1000000
+    div rcx
+    mov r12, rdx
+    mov rdi, rax
+    mov rsi, 1
+    call _crab_write_u64
+    mov rdi, '.'
+    call _crab_write_char
+    mov rdi, r12
+    mov rsi, 6
+    call _crab_write_u64
+    test rbx, rbx
+    jz .newline
+    mov rdi, 'e'
+    call _crab_write_char
+    mov rdi, rbx
+    mov rsi, 1
+    call _crab_write_u64
+.newline:
+    mov rdi, 10
+    call _crab_write_char
+    pop r12
+    pop rbx
+    ret
+.nan:
+    mov rsi, _crab_nan
+    jmp .word
+.inf:
+    mov rsi, _crab_inf
+.word:
+    mov rax, 1
+    mov rdi, 1
+    mov rdx, 4
+    syscall
+    pop r12
+    pop rbx
+    ret
+"#;
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command};
+
+    use crate::{codegen::CodeGen, test_util::checked};
+
+    /// What `source` prints once assembled, linked and run, or `None` when
+    /// `nasm` is not installed to assemble it.
+    fn run(name: &str, source: &str) -> Option<String> {
+        let dir = std::env::temp_dir().join(format!("crab_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (asm, obj, exe) = (dir.join("out.asm"), dir.join("out.o"), dir.join("out"));
+        fs::write(&asm, CodeGen::new().generate(&checked(source))).unwrap();
+        let assembled = Command::new("nasm")
+            .args(["-f", "elf64", "-o"])
+            .arg(&obj)
+            .arg(&asm)
+            .status()
+            .ok()?;
+        assert!(assembled.success());
+        assert!(Command::new("ld")
+            .arg("-o")
+            .arg(&exe)
+            .arg(&obj)
+            .status()
+            .unwrap()
+            .success());
+        let output = Command::new(&exe).output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        Some(String::from_utf8(output.stdout).unwrap())
+    }
+
+    #[test]
+    fn test_print_f64() {
+        let source = "print(-2.5); print(0.1); print(999999.9999999); print(1e12); \
+             print(1e20); print(-1.7976931348623157e308); print(9.9999999999999e12); \
+             print(1.0 / 0.0); print(-1.0 / 0.0); print(0.0 / 0.0);";
+        let Some(printed) = run("print_f64", source) else {
+            return;
+        };
+        assert_eq!(
+            printed.lines().collect::<Vec<_>>(),
+            [
+                "-2.500000",
+                "0.100000",
+                "1000000.000000",
+                "1.000000e12",
+                "1.000000e20",
+                "-1.797693e308",
+                "1.000000e13",
+                "inf",
+                "-inf",
+                "NaN",
+            ]
+        );
+    }
+}
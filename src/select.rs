@@ -0,0 +1,219 @@
+//! If-conversion of small branches into selects, over IR in SSA form.
+//!
+//! A branch whose arms only compute a few values without side effects, and
+//! then meet again, is replaced with straight-line code: both arms run, and
+//! each phi where they meet becomes a select on the branch condition, which
+//! the code generator emits as `cmov`. An arm may also be empty, when the
+//! branch jumps straight to where the arms meet.
+
+use crate::{
+    ir::{BlockId, Function, Instr, Module, Operand, Terminator},
+    loops::is_hoistable,
+};
+
+/// How many instructions an arm may have. Both arms always run once
+/// converted, so a longer one would cost more than the branch it replaces.
+const MAX_ARM_LEN: usize = 2;
+
+pub fn optimize_module(module: &mut Module) {
+    for f in &mut module.functions {
+        optimize(f);
+    }
+}
+
+pub fn optimize(f: &mut Function) {
+    // Converting an inner branch can leave an outer one small enough
+    while let Some((block, arms)) = (0..f.blocks.len())
+        .map(BlockId)
+        .find_map(|block| Some((block, arms(f, block)?)))
+    {
+        convert(f, block, arms);
+        f.remove_unreachable_blocks();
+    }
+}
+
+/// Where control goes from `block` along an edge to `target`: the block the
+/// arms meet at, and the arm block passed on the way, if any.
+fn arm(
+    f: &Function,
+    preds: &[Vec<BlockId>],
+    block: BlockId,
+    target: BlockId,
+) -> (BlockId, Option<BlockId>) {
+    let arm = &f.blocks[target.0];
+    match arm.term {
+        Terminator::Jump(join)
+            if preds[target.0] == [block]
+                && arm.instrs.len() <= MAX_ARM_LEN
+                && arm.instrs.iter().all(is_hoistable) =>
+        {
+            (join, Some(target))
+        }
+        _ => (target, None),
+    }
+}
+
+/// Where the arms of a branch meet, and the blocks they pass through.
+type Arms = (BlockId, Option<BlockId>, Option<BlockId>);
+
+/// The arms of the branch ending `block`, when it can be converted.
+fn arms(f: &Function, block: BlockId) -> Option<Arms> {
+    let Terminator::Branch {
+        cond: Operand::Value(_),
+        then_block,
+        else_block,
+    } = f.blocks[block.0].term
+    else {
+        return None;
+    };
+    let preds = f.predecessors();
+    let (then_join, then_arm) = arm(f, &preds, block, then_block);
+    let (else_join, else_arm) = arm(f, &preds, block, else_block);
+    let join = then_join;
+    // Only the two arms may lead to the join, and at least one of them has
+    // to pass through a block, or there are two edges from `block` to tell
+    // apart
+    let valid = then_join == else_join
+        && join != block
+        && (then_arm.is_some() || else_arm.is_some())
+        && preds[join.0].len() == 2;
+    valid.then_some((join, then_arm, else_arm))
+}
+
+/// Moves both arms of the branch ending `block` into it, and turns the phis
+/// where they meet into selects. The arms are left unreachable.
+fn convert(f: &mut Function, block: BlockId, (join, then_arm, else_arm): Arms) {
+    let Terminator::Branch { cond, .. } = f.blocks[block.0].term else {
+        unreachable!("convertible blocks end in a branch");
+    };
+    let moved: Vec<Instr> = [then_arm, else_arm]
+        .into_iter()
+        .flatten()
+        .flat_map(|arm| std::mem::take(&mut f.blocks[arm.0].instrs))
+        .collect();
+    // Keep the comparison next to the selects, so that they can share the
+    // flags it sets
+    let instrs = &mut f.blocks[block.0].instrs;
+    let at = match instrs.last() {
+        Some(last)
+            if last.dst() == cond.as_value()
+                && !moved.iter().any(|instr| instr.operands().contains(&&cond)) =>
+        {
+            instrs.len() - 1
+        }
+        _ => instrs.len(),
+    };
+    instrs.splice(at..at, moved);
+    let then_pred = then_arm.unwrap_or(block);
+    let else_pred = else_arm.unwrap_or(block);
+    let join_instrs = std::mem::take(&mut f.blocks[join.0].instrs);
+    let mut rest = Vec::new();
+    for instr in join_instrs {
+        match instr {
+            Instr::Phi { dst, args } => {
+                let incoming = |pred: BlockId| {
+                    args.iter()
+                        .find(|(from, _)| *from == pred)
+                        .map(|(_, arg)| *arg)
+                        .expect("phis have an operand for every predecessor")
+                };
+                let (then_value, else_value) = (incoming(then_pred), incoming(else_pred));
+                let select = if then_value == else_value {
+                    Instr::Copy {
+                        dst,
+                        src: then_value,
+                    }
+                } else {
+                    Instr::Select {
+                        dst,
+                        cond,
+                        then_value,
+                        else_value,
+                    }
+                };
+                f.blocks[block.0].instrs.push(select);
+            }
+            instr => rest.push(instr),
+        }
+    }
+    f.blocks[join.0].instrs = rest;
+    f.blocks[block.0].term = Terminator::Jump(join);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gvn, parser::Op, test_util::ssa_module, verify::verify};
+
+    fn converted(source: &str) -> Function {
+        let mut module = ssa_module(source);
+        gvn::optimize_module(&mut module);
+        optimize_module(&mut module);
+        let f = module
+            .functions
+            .into_iter()
+            .find(|f| f.name == "f")
+            .unwrap();
+        verify(&f).unwrap();
+        f
+    }
+
+    #[test]
+    fn test_diamond_becomes_select() {
+        let f = converted(
+            "fn f(a: i64, b: i64) -> i64 { let mut m = 0; if (a > b) { m = a; } else { m = b; } return m; } exit(f(1, 2) as u8);",
+        );
+        assert_eq!(
+            f.to_string(),
+            "fn f(%a.0: i64, %b.1: i64) -> i64 {\n\
+             bb0: ; entry\n    %3 = gt i64 %a.0, %b.1\n    %m.7 = select %3, %a.0, %b.1\n    \
+             jmp bb1\n\
+             bb1: ; if_end\n    ret %m.7\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_triangle_and_computed_arms() {
+        let f = converted(
+            "fn f(x: i64, y: i64) -> i64 { let mut r = x; if (x < 0) { r = y * 2 + 1; } return r; } exit(f(1, 2) as u8);",
+        );
+        assert_eq!(f.blocks.len(), 2);
+        let entry = &f.blocks[0].instrs;
+        assert_eq!(entry.len(), 4);
+        assert!(matches!(entry[2], Instr::Binary { op: Op::Lt, .. }));
+        assert!(matches!(
+            entry[3],
+            Instr::Select {
+                else_value: Operand::Value(_),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_arms_with_effects_keep_branching() {
+        // A call, a print or a division that may trap must not run on the
+        // path that did not ask for it
+        for arm in [
+            "m = g();",
+            "print(a); m = a;",
+            "m = b / a;",
+            "m = a + 1; m = m * 2; m = m - b;",
+        ] {
+            let source = format!(
+                "fn g() -> i64 {{ return 7; }} fn f(a: i64, b: i64) -> i64 {{ let mut m = b; if (a > b) {{ {} }} return m; }} exit(f(1, 2) as u8);",
+                arm
+            );
+            let f = converted(&source);
+            assert!(
+                f.blocks
+                    .iter()
+                    .flat_map(|block| &block.instrs)
+                    .all(|instr| !matches!(instr, Instr::Select { .. })),
+                "{}",
+                arm
+            );
+        }
+    }
+}
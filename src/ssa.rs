@@ -0,0 +1,424 @@
+//! Conversion of IR functions into and out of static single assignment form.
+//!
+//! Lowering gives each source variable one value that every assignment
+//! writes. `construct` splits those into one value per assignment, joined by
+//! phis where control flow merges; `destruct` turns the phis back into copies
+//! before code generation.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    dom::DomTree,
+    ir::{BlockId, Function, Instr, Module, Operand, Terminator, Value},
+    liveness::Liveness,
+};
+
+pub fn construct_module(module: &mut Module) {
+    for f in &mut module.functions {
+        construct(f);
+    }
+}
+
+pub fn destruct_module(module: &mut Module) {
+    for f in &mut module.functions {
+        destruct(f);
+    }
+}
+
+/// Puts `f` into SSA form. Blocks that cannot be reached are dropped first,
+/// as they have no place in the dominator tree.
+pub fn construct(f: &mut Function) {
+    f.remove_unreachable_blocks();
+    let dom = DomTree::new(f);
+    let frontiers = dom.frontiers(f);
+    let liveness = Liveness::compute(f);
+
+    // Blocks that assign each source variable; parameters arrive in the entry
+    let mut def_blocks: HashMap<Value, Vec<BlockId>> = HashMap::new();
+    for &param in &f.params {
+        def_blocks.entry(param).or_default().push(BlockId(0));
+    }
+    for (id, block) in f.blocks.iter().enumerate() {
+        for dst in block.instrs.iter().filter_map(Instr::dst) {
+            if f.values[dst.0].name.is_some() {
+                def_blocks.entry(dst).or_default().push(BlockId(id));
+            }
+        }
+    }
+
+    // Place a phi wherever two definitions meet, but only if the variable is
+    // still needed there
+    let mut vars: Vec<Value> = def_blocks.keys().copied().collect();
+    vars.sort();
+    let mut phi_vars: Vec<Vec<Value>> = vec![Vec::new(); f.blocks.len()];
+    for &var in &vars {
+        let mut worklist = def_blocks[&var].clone();
+        let mut has_phi = HashSet::new();
+        while let Some(block) = worklist.pop() {
+            for &join in &frontiers[block.0] {
+                if liveness.live_in[join.0].contains(&var) && has_phi.insert(join) {
+                    phi_vars[join.0].push(var);
+                    worklist.push(join);
+                }
+            }
+        }
+    }
+    let preds = f.predecessors();
+    for (id, vars) in phi_vars.iter().enumerate() {
+        let phis = vars.iter().map(|&var| Instr::Phi {
+            dst: var,
+            args: preds[id]
+                .iter()
+                .map(|&pred| (pred, Operand::Value(var)))
+                .collect(),
+        });
+        f.blocks[id].instrs.splice(0..0, phis);
+    }
+
+    let mut renamer = Renamer {
+        stacks: vars.iter().map(|&var| (var, Vec::new())).collect(),
+        phi_vars,
+    };
+    for &param in &f.params {
+        renamer.stacks.insert(param, vec![param]);
+    }
+    renamer.rename(f, &dom, BlockId(0));
+}
+
+struct Renamer {
+    /// The current version of each source variable, innermost last.
+    stacks: HashMap<Value, Vec<Value>>,
+    /// The variable each block's leading phis were placed for.
+    phi_vars: Vec<Vec<Value>>,
+}
+
+impl Renamer {
+    fn current(&self, var: Value) -> Value {
+        match self.stacks.get(&var) {
+            Some(stack) => *stack
+                .last()
+                .unwrap_or_else(|| panic!("{:?} is used before it is assigned", var)),
+            // Temporaries are already assigned only once
+            None => var,
+        }
+    }
+
+    fn rename_operand(&self, operand: &mut Operand) {
+        if let Operand::Value(value) = operand {
+            *value = self.current(*value);
+        }
+    }
+
+    fn new_version(&mut self, f: &mut Function, var: Value) -> Value {
+        let info = f.values[var.0].clone();
+        let version = f.new_value(info.ty, info.name);
+        self.stacks
+            .get_mut(&var)
+            .expect("only source variables get new versions")
+            .push(version);
+        version
+    }
+
+    fn rename(&mut self, f: &mut Function, dom: &DomTree, block: BlockId) {
+        let mut pushed = Vec::new();
+        let mut instrs = std::mem::take(&mut f.blocks[block.0].instrs);
+        for instr in &mut instrs {
+            if !matches!(instr, Instr::Phi { .. }) {
+                for operand in instr.operands_mut() {
+                    self.rename_operand(operand);
+                }
+            }
+            let var = match instr {
+                Instr::Copy { dst, .. }
+                | Instr::Binary { dst, .. }
+                | Instr::Neg { dst, .. }
+                | Instr::Cast { dst, .. }
+                | Instr::Select { dst, .. }
+                | Instr::Phi { dst, .. }
+                | Instr::Call { dst: Some(dst), .. } => dst,
+                Instr::Call { dst: None, .. } | Instr::Print { .. } => continue,
+            };
+            if self.stacks.contains_key(var) {
+                pushed.push(*var);
+                *var = self.new_version(f, *var);
+            }
+        }
+        f.blocks[block.0].instrs = instrs;
+        let mut term = std::mem::replace(&mut f.blocks[block.0].term, Terminator::Pending);
+        for operand in term.operands_mut() {
+            self.rename_operand(operand);
+        }
+        let succs = term.successors();
+        f.blocks[block.0].term = term;
+
+        // Fill in this block's operand of each successor's phis
+        for succ in succs {
+            let vars = self.phi_vars[succ.0].clone();
+            for (instr, var) in f.blocks[succ.0].instrs.iter_mut().zip(vars) {
+                if let Instr::Phi { args, .. } = instr {
+                    for (pred, arg) in args {
+                        if *pred == block {
+                            *arg = Operand::Value(self.current(var));
+                        }
+                    }
+                }
+            }
+        }
+
+        for &child in dom.children(block) {
+            self.rename(f, dom, child);
+        }
+        for var in pushed {
+            self.stacks.get_mut(&var).unwrap().pop();
+        }
+    }
+}
+
+/// Replaces the phis in `f` with copies at the end of each predecessor.
+///
+/// A predecessor with several successors gets a new block on the edge, so
+/// the copies only run when control actually takes that edge.
+pub fn destruct(f: &mut Function) {
+    let preds = f.predecessors();
+    // New edge blocks, keyed by the block they leave from
+    let mut edge_blocks: HashMap<BlockId, Vec<BlockId>> = HashMap::new();
+    for (id, block_preds) in preds.iter().enumerate() {
+        let phis: Vec<(Value, Vec<(BlockId, Operand)>)> = f.blocks[id]
+            .instrs
+            .iter()
+            .filter_map(|instr| match instr {
+                Instr::Phi { dst, args } => Some((*dst, args.clone())),
+                _ => None,
+            })
+            .collect();
+        if phis.is_empty() {
+            continue;
+        }
+        f.blocks[id]
+            .instrs
+            .retain(|instr| !matches!(instr, Instr::Phi { .. }));
+
+        for &pred in block_preds {
+            let copy_block = if f.blocks[pred.0].term.successors().len() > 1 {
+                let edge = f.new_block("phi_edge");
+                f.blocks[edge.0].term = Terminator::Jump(BlockId(id));
+                for target in f.blocks[pred.0].term.successors_mut() {
+                    if *target == BlockId(id) {
+                        *target = edge;
+                    }
+                }
+                edge_blocks.entry(pred).or_default().push(edge);
+                edge
+            } else {
+                pred
+            };
+
+            let incoming: Vec<(Value, Operand)> = phis
+                .iter()
+                .map(|(dst, args)| {
+                    let arg = args
+                        .iter()
+                        .find(|(from, _)| *from == pred)
+                        .map(|(_, arg)| *arg)
+                        .expect("a phi has an operand for every predecessor");
+                    (*dst, arg)
+                })
+                .collect();
+            let copies = &mut f.blocks[copy_block.0].instrs;
+            if let [(dst, src)] = incoming[..] {
+                copies.push(Instr::Copy { dst, src });
+                continue;
+            }
+            // The phis all read their operands before any of them is
+            // written, so go through temporaries in case one reads another
+            let temps: Vec<Value> = incoming
+                .iter()
+                .map(|(dst, _)| {
+                    let ty = f.values[dst.0].ty;
+                    f.new_value(ty, None)
+                })
+                .collect();
+            let copies = &mut f.blocks[copy_block.0].instrs;
+            for (temp, (_, src)) in temps.iter().zip(&incoming) {
+                copies.push(Instr::Copy {
+                    dst: *temp,
+                    src: *src,
+                });
+            }
+            for (temp, (dst, _)) in temps.iter().zip(&incoming) {
+                copies.push(Instr::Copy {
+                    dst: *dst,
+                    src: Operand::Value(*temp),
+                });
+            }
+        }
+    }
+
+    // Lay each edge block out right after the block it leaves from
+    let original = preds.len();
+    let mut order = Vec::new();
+    for id in 0..original {
+        order.push(BlockId(id));
+        if let Some(edges) = edge_blocks.get(&BlockId(id)) {
+            order.extend(edges);
+        }
+    }
+    f.reorder_blocks(&order);
+    coalesce_copies(f);
+}
+
+/// Folds each copy of a value computed earlier in the same block, and read
+/// nowhere else, into that value's definition. A loop counter's update then
+/// writes the counter itself instead of a temporary copied back into it.
+fn coalesce_copies(f: &mut Function) {
+    let mut uses = vec![0; f.values.len()];
+    for block in &f.blocks {
+        let operands = block.instrs.iter().flat_map(Instr::operands);
+        for value in operands
+            .chain(block.term.operands())
+            .filter_map(Operand::as_value)
+        {
+            uses[value.0] += 1;
+        }
+    }
+    for block in &mut f.blocks {
+        let mut i = 0;
+        while i < block.instrs.len() {
+            let Instr::Copy {
+                dst,
+                src: Operand::Value(src),
+            } = block.instrs[i]
+            else {
+                i += 1;
+                continue;
+            };
+            let def = block.instrs[..i]
+                .iter()
+                .position(|instr| instr.dst() == Some(src));
+            let touches_dst = |instr: &Instr| {
+                instr.dst() == Some(dst) || instr.operands().contains(&&Operand::Value(dst))
+            };
+            match def {
+                Some(def)
+                    if uses[src.0] == 1
+                        && f.values[src.0].ty == f.values[dst.0].ty
+                        && !block.instrs[def + 1..i].iter().any(touches_dst) =>
+                {
+                    *block.instrs[def].dst_mut().expect("it defines src") = dst;
+                    block.instrs.remove(i);
+                }
+                _ => i += 1,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::ssa_module, verify::verify};
+
+    #[test]
+    fn test_loop_gets_phis() {
+        let module = ssa_module("let mut x = 0; let y = 2; while (x < 5) { x = x + y; } exit(x);");
+        let f = &module.functions[0];
+        verify(f).unwrap();
+        assert_eq!(
+            f.to_string(),
+            "fn _start() -> () {\n\
+             bb0: ; entry\n    %x.4 = 0\n    %y.5 = 2\n    jmp bb1\n\
+             bb1: ; while_start\n    %x.6 = phi [bb0: %x.4], [bb2: %x.7]\n    \
+             %2 = lt i64 %x.6, 5\n    br %2, bb2, bb3\n\
+             bb2: ; while_body\n    %3 = add i64 %x.6, %y.5\n    %x.7 = %3\n    jmp bb1\n\
+             bb3: ; while_end\n    exit %x.6\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_phis_are_pruned_to_live_variables() {
+        // `t` is reassigned in both arms but dead after the `if`, so only
+        // `x` needs a phi at the join
+        let module = ssa_module(
+            "let c = 1; let mut x = 0; let mut t = 0; if (c == 1) { t = 5; x = t; } else { t = 6; x = t + 1; } exit(x);",
+        );
+        let f = &module.functions[0];
+        verify(f).unwrap();
+        let phis: Vec<&Instr> = f
+            .blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .filter(|i| matches!(i, Instr::Phi { .. }))
+            .collect();
+        assert_eq!(phis.len(), 1);
+        assert_eq!(
+            f.values[phis[0].dst().unwrap().0].name.as_deref(),
+            Some("x")
+        );
+    }
+
+    #[test]
+    fn test_destruct_splits_critical_edges() {
+        let mut module = ssa_module("let c = 2; let mut x = 0; if (c > 1) { x = 7; } exit(x);");
+        let f = &mut module.functions[0];
+        verify(f).unwrap();
+        destruct(f);
+        let names: Vec<&str> = f.blocks.iter().map(|b| b.name.as_str()).collect();
+        assert_eq!(names, ["entry", "phi_edge", "if_then", "if_end"]);
+        // The entry branches two ways, so its copy into `x` sits on the edge
+        assert!(matches!(
+            f.blocks[0].term,
+            Terminator::Branch {
+                else_block: BlockId(1),
+                ..
+            }
+        ));
+        assert!(matches!(f.blocks[1].instrs[..], [Instr::Copy { .. }]));
+        assert!(matches!(
+            f.blocks[2].instrs.last(),
+            Some(Instr::Copy { .. })
+        ));
+        assert!(f
+            .blocks
+            .iter()
+            .flat_map(|b| &b.instrs)
+            .all(|i| !matches!(i, Instr::Phi { .. })));
+    }
+
+    #[test]
+    fn test_destruct_swaps_through_temporaries() {
+        let mut module = ssa_module(
+            "let mut a = 1; let mut b = 2; let mut i = 0; while (i < 3) { let t = a; a = b; b = t; i = i + 1; } exit(a);",
+        );
+        let f = &mut module.functions[0];
+        destruct(f);
+        // Three phis on the back edge read their operands before any of them
+        // is written. Coalescing folds the counter's update into place, but
+        // `a` still has to be saved in a temporary before `b` overwrites it
+        let body = f
+            .blocks
+            .iter()
+            .position(|b| b.name == "while_body")
+            .unwrap();
+        let instrs = &f.blocks[body].instrs;
+        let temps: Vec<usize> = (0..instrs.len())
+            .filter(|&i| f.values[instrs[i].dst().unwrap().0].name.is_none())
+            .collect();
+        let [saved_at] = temps[..] else {
+            panic!("expected one temporary, got {:?}", temps);
+        };
+        let Instr::Copy {
+            dst: temp,
+            src: Operand::Value(saved),
+        } = instrs[saved_at]
+        else {
+            panic!("expected a copy into the temporary");
+        };
+        let overwritten = instrs.iter().position(|i| i.dst() == Some(saved)).unwrap();
+        assert!(saved_at < overwritten);
+        // ... and the saved value is what `b` gets
+        assert!(instrs[overwritten..]
+            .iter()
+            .any(|i| matches!(i, Instr::Copy { src: Operand::Value(v), .. } if *v == temp)));
+    }
+}
@@ -0,0 +1,184 @@
+//! Magic numbers for dividing by a constant with a multiplication.
+//!
+//! Dividing `n` by a constant `d` is the same as taking the high 64 bits of
+//! `n * m` for a suitable `m` close to `2^(64 + s) / d`, then shifting right
+//! by `s`. The constants here are computed as in Granlund and Montgomery's
+//! "Division by Invariant Integers using Multiplication", following the
+//! formulation in Hacker's Delight, chapter 10.
+
+/// How to divide a signed 64-bit value by a constant with a multiplication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignedMagic {
+    pub multiplier: i64,
+    pub shift: u32,
+}
+
+/// How to divide an unsigned 64-bit value by a constant with a
+/// multiplication. When `add` is set the multiplier needs 65 bits; its top
+/// bit is added back in with a fix-up sequence before the final shift.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsignedMagic {
+    pub multiplier: u64,
+    pub add: bool,
+    pub shift: u32,
+}
+
+/// The magic number for signed division by `d`, which must not be -1, 0
+/// or 1.
+pub fn signed_magic(d: i64) -> SignedMagic {
+    const TWO63: u64 = 1 << 63;
+    let ad = d.unsigned_abs();
+    let t = TWO63 + ((d as u64) >> 63);
+    // Largest dividend magnitude whose remainder is d - 1
+    let anc = t - 1 - t % ad;
+    let mut p = 63;
+    let (mut q1, mut r1) = (TWO63 / anc, TWO63 % anc);
+    let (mut q2, mut r2) = (TWO63 / ad, TWO63 % ad);
+    loop {
+        p += 1;
+        q1 = q1.wrapping_mul(2);
+        r1 = r1.wrapping_mul(2);
+        if r1 >= anc {
+            q1 = q1.wrapping_add(1);
+            r1 = r1.wrapping_sub(anc);
+        }
+        q2 = q2.wrapping_mul(2);
+        r2 = r2.wrapping_mul(2);
+        if r2 >= ad {
+            q2 = q2.wrapping_add(1);
+            r2 = r2.wrapping_sub(ad);
+        }
+        let delta = ad - r2;
+        if !(q1 < delta || (q1 == delta && r1 == 0)) {
+            break;
+        }
+    }
+    let multiplier = q2.wrapping_add(1) as i64;
+    SignedMagic {
+        multiplier: if d < 0 {
+            multiplier.wrapping_neg()
+        } else {
+            multiplier
+        },
+        shift: p - 64,
+    }
+}
+
+/// The magic number for unsigned division by `d`, which must be at least 2.
+pub fn unsigned_magic(d: u64) -> UnsignedMagic {
+    const TWO63: u64 = 1 << 63;
+    let mut add = false;
+    let nc = u64::MAX - d.wrapping_neg() % d;
+    let mut p = 63;
+    let (mut q1, mut r1) = (TWO63 / nc, TWO63 % nc);
+    let (mut q2, mut r2) = ((TWO63 - 1) / d, (TWO63 - 1) % d);
+    loop {
+        p += 1;
+        if r1 >= nc - r1 {
+            q1 = q1.wrapping_mul(2).wrapping_add(1);
+            r1 = r1.wrapping_mul(2).wrapping_sub(nc);
+        } else {
+            q1 = q1.wrapping_mul(2);
+            r1 = r1.wrapping_mul(2);
+        }
+        if r2 + 1 >= d - r2 {
+            if q2 >= TWO63 - 1 {
+                add = true;
+            }
+            q2 = q2.wrapping_mul(2).wrapping_add(1);
+            r2 = r2.wrapping_mul(2).wrapping_add(1).wrapping_sub(d);
+        } else {
+            if q2 >= TWO63 {
+                add = true;
+            }
+            q2 = q2.wrapping_mul(2);
+            r2 = r2.wrapping_mul(2).wrapping_add(1);
+        }
+        let delta = d - 1 - r2;
+        if !(p < 128 && (q1 < delta || (q1 == delta && r1 == 0))) {
+            break;
+        }
+    }
+    UnsignedMagic {
+        multiplier: q2.wrapping_add(1),
+        add,
+        shift: p - 64,
+    }
+}
+
+/// The `k` with `n == 2^k`, if `n` is a power of two.
+pub fn log2_exact(n: u64) -> Option<u32> {
+    n.is_power_of_two().then(|| n.trailing_zeros())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the generated code computes for `n / d`.
+    fn signed_divide(n: i64, d: i64) -> i64 {
+        let magic = signed_magic(d);
+        let mut q = ((magic.multiplier as i128 * n as i128) >> 64) as i64;
+        if d > 0 && magic.multiplier < 0 {
+            q = q.wrapping_add(n);
+        } else if d < 0 && magic.multiplier > 0 {
+            q = q.wrapping_sub(n);
+        }
+        q >>= magic.shift;
+        // Round towards zero for negative dividends
+        q + ((q as u64) >> 63) as i64
+    }
+
+    fn unsigned_divide(n: u64, d: u64) -> u64 {
+        let magic = unsigned_magic(d);
+        let t = ((magic.multiplier as u128 * n as u128) >> 64) as u64;
+        if magic.add {
+            (((n - t) >> 1) + t) >> (magic.shift - 1)
+        } else {
+            t >> magic.shift
+        }
+    }
+
+    const DIVIDENDS: [i64; 12] = [
+        0,
+        1,
+        -1,
+        6,
+        -6,
+        7,
+        -7,
+        1_000_000_007,
+        -999_999_999_999,
+        i64::MAX,
+        i64::MIN,
+        i64::MIN + 1,
+    ];
+
+    #[test]
+    fn test_signed_division() {
+        let mut divisors: Vec<i64> = (2..300).chain(-300..-1).collect();
+        divisors.extend([1 << 40, -(1 << 40) - 3, i64::MAX, i64::MIN + 1, 641, -641]);
+        for d in divisors {
+            for n in DIVIDENDS
+                .into_iter()
+                .chain((-50..50).map(|k| d.wrapping_add(k * 37)))
+            {
+                assert_eq!(signed_divide(n, d), n.wrapping_div(d), "{} / {}", n, d);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unsigned_division() {
+        let mut divisors: Vec<u64> = (2..300).collect();
+        divisors.extend([7 << 40, (1 << 63) - 1, u64::MAX / 3, 641, 1_000_000_007]);
+        for d in divisors {
+            let extra = (0..100).map(|k| (k * 4099 + d).wrapping_mul(d));
+            for n in DIVIDENDS.map(|n| n as u64).into_iter().chain(extra) {
+                assert_eq!(unsigned_divide(n, d), n / d, "{} / {}", n, d);
+            }
+        }
+        assert!(unsigned_magic(7).add);
+        assert!(!unsigned_magic(3).add);
+    }
+}
@@ -0,0 +1,140 @@
+//! The CPU the generated code may assume, chosen with `--target-cpu=` and
+//! `--target-feature=`.
+//!
+//! Only the SIMD extensions are modelled, and each implies the ones before
+//! it, so a target is the newest one it has. Code generation uses nothing
+//! past SSE2 yet, which every x86-64 CPU has; once the language has arrays,
+//! vectorized loops use AVX2 when it is enabled and SSE2 otherwise.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Feature {
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse41,
+    Sse42,
+    Avx,
+    Avx2,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 7] = [
+        Feature::Sse2,
+        Feature::Sse3,
+        Feature::Ssse3,
+        Feature::Sse41,
+        Feature::Sse42,
+        Feature::Avx,
+        Feature::Avx2,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Sse2 => "sse2",
+            Feature::Sse3 => "sse3",
+            Feature::Ssse3 => "ssse3",
+            Feature::Sse41 => "sse4.1",
+            Feature::Sse42 => "sse4.2",
+            Feature::Avx => "avx",
+            Feature::Avx2 => "avx2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Feature> {
+        Feature::ALL
+            .into_iter()
+            .find(|feature| feature.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    cpu: &'static str,
+    /// The newest SIMD extension that may be used.
+    simd: Feature,
+}
+
+impl Target {
+    /// The CPUs `--target-cpu=` accepts: the x86-64 microarchitecture levels.
+    pub const CPUS: [(&'static str, Feature); 3] = [
+        ("x86-64", Feature::Sse2),
+        ("x86-64-v2", Feature::Sse42),
+        ("x86-64-v3", Feature::Avx2),
+    ];
+
+    pub fn from_cpu(name: &str) -> Option<Target> {
+        Target::CPUS
+            .into_iter()
+            .find(|(cpu, _)| *cpu == name)
+            .map(|(cpu, simd)| Target { cpu, simd })
+    }
+
+    /// Applies `+feature` or `-feature`. Enabling a feature enables the ones
+    /// it builds on, and disabling one disables those built on it.
+    pub fn set_feature(&mut self, spec: &str) -> Result<(), String> {
+        let (enable, name) = match spec.split_at_checked(1) {
+            Some(("+", name)) => (true, name),
+            Some(("-", name)) => (false, name),
+            _ => return Err(format!("target feature `{}` must start with + or -", spec)),
+        };
+        let feature =
+            Feature::from_name(name).ok_or_else(|| format!("unknown target feature `{}`", name))?;
+        if enable {
+            self.simd = self.simd.max(feature);
+        } else if feature == Feature::Sse2 {
+            return Err("`sse2` cannot be disabled, as floats are computed with it".to_string());
+        } else if self.simd >= feature {
+            self.simd = Feature::ALL[feature as usize - 1];
+        }
+        Ok(())
+    }
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::from_cpu("x86-64").unwrap()
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, up to {}", self.cpu, self.simd.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpus() {
+        assert_eq!(Target::default().to_string(), "x86-64, up to sse2");
+        let v3 = Target::from_cpu("x86-64-v3").unwrap();
+        assert_eq!(v3.to_string(), "x86-64-v3, up to avx2");
+        assert_eq!(Target::from_cpu("pentium"), None);
+    }
+
+    #[test]
+    fn test_features() {
+        let mut target = Target::default();
+        target.set_feature("+avx2").unwrap();
+        assert_eq!(target.to_string(), "x86-64, up to avx2");
+        target.set_feature("-sse4.1").unwrap();
+        assert_eq!(target.to_string(), "x86-64, up to ssse3");
+        // Disabling a feature that is already off changes nothing
+        target.set_feature("-avx").unwrap();
+        assert_eq!(target.to_string(), "x86-64, up to ssse3");
+
+        assert_eq!(
+            target.set_feature("+avx512f").unwrap_err(),
+            "unknown target feature `avx512f`"
+        );
+        assert_eq!(
+            target.set_feature("avx2").unwrap_err(),
+            "target feature `avx2` must start with + or -"
+        );
+        assert!(target.set_feature("-sse2").is_err());
+    }
+}
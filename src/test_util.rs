@@ -0,0 +1,49 @@
+//! Fixtures shared by the unit tests.
+
+use crate::{
+    diagnostic::Diagnostic,
+    ir::Module,
+    lexer::Lexer,
+    lower::lower,
+    parser::{Parser, Stmt},
+    resolve::Resolver,
+    ssa,
+    typeck::TypeChecker,
+};
+
+/// Parses, resolves and type checks `source`, which must type check.
+pub fn checked(source: &str) -> Vec<Stmt> {
+    let tokens = Lexer::new(source).tokenize_spanned();
+    let mut stmts = Parser::with_spans(tokens).parse();
+    Resolver::new().resolve(&mut stmts);
+    TypeChecker::new().check(&mut stmts).unwrap();
+    stmts
+}
+
+/// `source`, checked and lowered to SSA form.
+pub fn ssa_module(source: &str) -> Module {
+    let mut module = lower(&checked(source));
+    ssa::construct_module(&mut module);
+    module
+}
+
+/// Each diagnostic as `message @ text`, where `text` is the source it spans,
+/// followed by its notes as ` (note @ text)`.
+pub fn render(diagnostics: &[Diagnostic], source: &str) -> Vec<String> {
+    diagnostics
+        .iter()
+        .map(|d| {
+            let notes: String = d
+                .notes
+                .iter()
+                .map(|(span, note)| format!(" ({} @ {})", note, &source[span.start..span.end]))
+                .collect();
+            format!(
+                "{} @ {}{}",
+                d.message,
+                &source[d.span.start..d.span.end],
+                notes
+            )
+        })
+        .collect()
+}
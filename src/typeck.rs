@@ -0,0 +1,927 @@
+use std::collections::HashMap;
+
+use crate::{
+    codegen::ARG_REGS,
+    diagnostic::Diagnostic,
+    lexer::Span,
+    parser::{walk_exprs_mut, DeclId, Expr, ExprKind, Function, Op, Stmt, StmtKind},
+    types::Type,
+};
+
+/// Functions built into the language, and how many arguments each takes.
+/// They compile to conditional moves rather than calls, and cannot be
+/// redefined.
+pub const INTRINSICS: [(&str, usize); 3] = [("min", 2), ("max", 2), ("abs", 1)];
+
+/// A type during inference: either known, an inference variable, or the
+/// type of an expression that already produced an error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty {
+    Known(Type),
+    Var(usize),
+    Error,
+}
+
+struct VarSlot {
+    binding: Option<Ty>,
+    /// Set for the type of an integer literal, which may only unify with
+    /// integer types and defaults to i64.
+    integer: bool,
+}
+
+struct Signature {
+    params: Vec<Type>,
+    ret: Ty,
+}
+
+/// A check that can only be judged once inference is finished, so that
+/// messages show the final types (`found i64` rather than `found {integer}`).
+enum Check {
+    Report(Diagnostic),
+    Mismatch {
+        span: Span,
+        expected: Ty,
+        found: Ty,
+    },
+    Cond {
+        span: Span,
+        keyword: &'static str,
+        found: Ty,
+    },
+    BinOp {
+        span: Span,
+        op: Op,
+        left: Ty,
+        right: Ty,
+    },
+    Unary {
+        span: Span,
+        op: Op,
+        ty: Ty,
+    },
+    Cast {
+        span: Span,
+        from: Ty,
+        to: Type,
+    },
+    ExitCode {
+        span: Span,
+        ty: Ty,
+    },
+    NotUnit {
+        span: Span,
+        ty: Ty,
+        what: &'static str,
+    },
+    Fits {
+        span: Span,
+        ty: Ty,
+        value: i128,
+    },
+    Intrinsic {
+        span: Span,
+        name: &'static str,
+        ty: Ty,
+    },
+    Returns {
+        span: Span,
+        name: String,
+        ret: Ty,
+        returns: bool,
+    },
+}
+
+/// Checks the program and records the type of every expression in its `ty`
+/// field, so that later stages can pick width- and sign-aware instructions.
+///
+/// Types are inferred by unification: `let` bindings take the type of their
+/// value, integer literals take whatever integer type their uses demand
+/// (defaulting to i64), and a function without `->` returns the type of its
+/// `return` statements.
+///
+/// Runs after name resolution, and looks variables up by their declaration.
+pub struct TypeChecker {
+    locals: HashMap<DeclId, Ty>,
+    functions: HashMap<String, Signature>,
+    /// Return type of the function being checked, `None` at the top level.
+    ret: Option<Ty>,
+    table: Vec<VarSlot>,
+    checks: Vec<Check>,
+    /// The inferred type of every expression, in the order of `walk_exprs_mut`.
+    expr_types: Vec<Ty>,
+}
+
+impl TypeChecker {
+    pub fn new() -> Self {
+        Self {
+            locals: HashMap::new(),
+            functions: HashMap::new(),
+            ret: None,
+            table: Vec::new(),
+            checks: Vec::new(),
+            expr_types: Vec::new(),
+        }
+    }
+
+    pub fn check(mut self, stmts: &mut [Stmt]) -> Result<(), Vec<Diagnostic>> {
+        // Functions may be called before they are declared
+        for stmt in stmts.iter() {
+            if let StmtKind::Fn(f) = &stmt.kind {
+                self.declare_function(f, stmt.span);
+            }
+        }
+
+        for stmt in stmts.iter_mut() {
+            if let StmtKind::Fn(f) = &mut stmt.kind {
+                self.check_function(f, stmt.span);
+            } else {
+                self.check_stmt(stmt);
+            }
+        }
+
+        let diagnostics = self.solve();
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+
+        let mut types = self.expr_types.iter();
+        walk_exprs_mut(stmts, &mut |expr| {
+            let ty = types.next().expect("every expression was checked");
+            expr.ty = Some(self.known(*ty));
+        });
+        for stmt in stmts.iter_mut() {
+            if let StmtKind::Fn(f) = &mut stmt.kind {
+                f.ret = Some(self.known(self.functions[&f.name].ret));
+            }
+        }
+        Ok(())
+    }
+
+    fn declare_function(&mut self, f: &Function, span: Span) {
+        if f.params.len() > ARG_REGS.len() {
+            self.error(
+                span,
+                format!(
+                    "function `{}` has more than {} parameters",
+                    f.name,
+                    ARG_REGS.len()
+                ),
+            );
+        }
+        if INTRINSICS.iter().any(|(name, _)| *name == f.name) {
+            self.error(
+                span,
+                format!(
+                    "`{}` is a built-in function and cannot be redefined",
+                    f.name
+                ),
+            );
+        }
+        let ret = match f.ret {
+            Some(ty) => Ty::Known(ty),
+            None if returns_value(&f.body) => self.fresh(false),
+            None => Ty::Known(Type::Unit),
+        };
+        let signature = Signature {
+            params: f.params.iter().map(|(_, ty)| *ty).collect(),
+            ret,
+        };
+        if self.functions.insert(f.name.clone(), signature).is_some() {
+            self.error(
+                span,
+                format!("function `{}` is defined more than once", f.name),
+            );
+        }
+    }
+
+    fn error(&mut self, span: Span, message: impl Into<String>) {
+        self.checks
+            .push(Check::Report(Diagnostic::error(span, message)));
+    }
+
+    fn fresh(&mut self, integer: bool) -> Ty {
+        self.table.push(VarSlot {
+            binding: None,
+            integer,
+        });
+        Ty::Var(self.table.len() - 1)
+    }
+
+    fn resolve(&self, mut ty: Ty) -> Ty {
+        while let Ty::Var(var) = ty {
+            match self.table[var].binding {
+                Some(bound) => ty = bound,
+                None => break,
+            }
+        }
+        ty
+    }
+
+    /// The final type of `ty`, once inference has succeeded.
+    fn known(&self, ty: Ty) -> Type {
+        match self.resolve(ty) {
+            Ty::Known(ty) => ty,
+            _ => unreachable!("inference left a type undetermined"),
+        }
+    }
+
+    /// Makes `a` and `b` the same type, returning false if they cannot be.
+    fn unify(&mut self, a: Ty, b: Ty) -> bool {
+        match (self.resolve(a), self.resolve(b)) {
+            (Ty::Error, _) | (_, Ty::Error) => true,
+            (Ty::Known(a), Ty::Known(b)) => a == b,
+            (Ty::Var(a), Ty::Var(b)) => {
+                if a != b {
+                    self.table[b].integer |= self.table[a].integer;
+                    self.table[a].binding = Some(Ty::Var(b));
+                }
+                true
+            }
+            (Ty::Var(var), Ty::Known(ty)) | (Ty::Known(ty), Ty::Var(var)) => {
+                if self.table[var].integer && !ty.is_integer() {
+                    return false;
+                }
+                self.table[var].binding = Some(Ty::Known(ty));
+                true
+            }
+        }
+    }
+
+    fn expect(&mut self, span: Span, expected: Ty, found: Ty) {
+        if !self.unify(expected, found) {
+            self.checks.push(Check::Mismatch {
+                span,
+                expected,
+                found,
+            });
+        }
+    }
+
+    fn check_function(&mut self, f: &mut Function, span: Span) {
+        let outer_locals = std::mem::take(&mut self.locals);
+        for (name, ty) in &f.params {
+            if let Some(decl) = name.decl {
+                self.locals.insert(decl, Ty::Known(*ty));
+            }
+        }
+        let ret = match self.functions.get(&f.name) {
+            Some(signature) => signature.ret,
+            None => Ty::Error,
+        };
+        let outer_ret = self.ret.replace(ret);
+        self.check_block(&mut f.body);
+        self.checks.push(Check::Returns {
+            span,
+            name: f.name.clone(),
+            ret,
+            returns: block_returns(&f.body),
+        });
+        self.ret = outer_ret;
+        self.locals = outer_locals;
+    }
+
+    fn check_block(&mut self, stmts: &mut [Stmt]) {
+        for stmt in stmts {
+            self.check_stmt(stmt);
+        }
+    }
+
+    fn check_stmt(&mut self, stmt: &mut Stmt) {
+        match &mut stmt.kind {
+            StmtKind::Let(name, annotation, expr) => {
+                let ty = self.check_expr(expr);
+                self.checks.push(Check::NotUnit {
+                    span: expr.span,
+                    ty,
+                    what: "cannot bind a value of type ()",
+                });
+                let ty = match annotation {
+                    Some(expected) => {
+                        self.expect(expr.span, Ty::Known(*expected), ty);
+                        Ty::Known(*expected)
+                    }
+                    None => ty,
+                };
+                if let Some(decl) = name.decl {
+                    self.locals.insert(decl, ty);
+                }
+            }
+            StmtKind::Assign(name, expr) => {
+                let found = self.check_expr(expr);
+                if let Some(ty) = name.decl.and_then(|decl| self.locals.get(&decl)) {
+                    self.expect(expr.span, *ty, found);
+                }
+            }
+            StmtKind::Exit(expr) => {
+                let ty = self.check_expr(expr);
+                self.checks.push(Check::ExitCode {
+                    span: expr.span,
+                    ty,
+                });
+            }
+            StmtKind::Print(expr) => {
+                let ty = self.check_expr(expr);
+                self.checks.push(Check::NotUnit {
+                    span: expr.span,
+                    ty,
+                    what: "cannot print a value of type ()",
+                });
+            }
+            StmtKind::While(cond, body) => {
+                self.check_cond(cond, "while");
+                self.check_block(body);
+            }
+            StmtKind::If(cond, then_body, elif_branches, else_body) => {
+                self.check_cond(cond, "if");
+                self.check_block(then_body);
+                for (elif_cond, elif_body) in elif_branches {
+                    self.check_cond(elif_cond, "elif");
+                    self.check_block(elif_body);
+                }
+                if let Some(else_stmts) = else_body {
+                    self.check_block(else_stmts);
+                }
+            }
+            StmtKind::Fn(f) => {
+                let message = format!("function `{}` must be declared at the top level", f.name);
+                self.error(stmt.span, message);
+                self.check_function(f, stmt.span);
+            }
+            StmtKind::Return(value) => {
+                let found = match value {
+                    Some(expr) => self.check_expr(expr),
+                    None => Ty::Known(Type::Unit),
+                };
+                let span = value.as_ref().map_or(stmt.span, |expr| expr.span);
+                match self.ret {
+                    Some(ret) => self.expect(span, ret, found),
+                    None => self.error(stmt.span, "`return` outside of a function"),
+                }
+            }
+            StmtKind::Become(expr) => {
+                let found = self.check_expr(expr);
+                match self.ret {
+                    Some(ret) => self.expect(expr.span, ret, found),
+                    None => self.error(stmt.span, "`become` outside of a function"),
+                }
+                // Only a call can be a tail call, and intrinsics are not
+                // really called
+                match &expr.kind {
+                    ExprKind::Call(name, _) if INTRINSICS.iter().any(|(n, _)| n == name) => {
+                        let message = format!("`become` cannot call built-in function `{}`", name);
+                        self.error(expr.span, message);
+                    }
+                    ExprKind::Call(..) => {}
+                    _ => self.error(expr.span, "`become` needs a function call"),
+                }
+            }
+            StmtKind::Expr(expr) => {
+                self.check_expr(expr);
+            }
+        }
+    }
+
+    fn check_cond(&mut self, cond: &mut Expr, keyword: &'static str) {
+        let found = self.check_expr(cond);
+        if !self.unify(Ty::Known(Type::Bool), found) {
+            self.checks.push(Check::Cond {
+                span: cond.span,
+                keyword,
+                found,
+            });
+        }
+    }
+
+    /// Infers the type of `expr` and its subexpressions, visiting them in the
+    /// same order as `walk_exprs_mut` so the results can be written back.
+    fn check_expr(&mut self, expr: &mut Expr) -> Ty {
+        let span = expr.span;
+        let ty = match &mut expr.kind {
+            ExprKind::Num(n) => {
+                let ty = self.fresh(true);
+                self.checks.push(Check::Fits {
+                    span,
+                    ty,
+                    // Literals are never negative, and those above
+                    // `i64::MAX` are lexed to their bits
+                    value: *n as u64 as i128,
+                });
+                ty
+            }
+            ExprKind::Float(_) => Ty::Known(Type::F64),
+            ExprKind::Bool(_) => Ty::Known(Type::Bool),
+            ExprKind::Ident(name) => match name.decl.and_then(|decl| self.locals.get(&decl)) {
+                Some(ty) => *ty,
+                None => {
+                    let message = format!("cannot find variable `{}` in this scope", name);
+                    self.error(span, message);
+                    Ty::Error
+                }
+            },
+            ExprKind::BinOp(left, op, right) => {
+                let left_ty = self.check_expr(left);
+                let right_ty = self.check_expr(right);
+                let same = self.unify(left_ty, right_ty);
+                self.checks.push(Check::BinOp {
+                    span,
+                    op: *op,
+                    left: left_ty,
+                    right: right_ty,
+                });
+                match op {
+                    Op::Add | Op::Sub | Op::Mul | Op::Div if same => left_ty,
+                    Op::Add | Op::Sub | Op::Mul | Op::Div => Ty::Error,
+                    _ => Ty::Known(Type::Bool),
+                }
+            }
+            ExprKind::UnaryOp(op, inner) => {
+                let ty = self.check_expr(inner);
+                match (&op, &inner.kind) {
+                    // `-128` is a valid i8 even though `128` is not.
+                    (Op::Sub, ExprKind::Num(n)) => {
+                        if let Some(Check::Fits {
+                            span: fits_span,
+                            value,
+                            ..
+                        }) = self.checks.last_mut()
+                        {
+                            *fits_span = span;
+                            *value = -(*n as u64 as i128);
+                        }
+                    }
+                    _ => self.checks.push(Check::Unary { span, op: *op, ty }),
+                }
+                ty
+            }
+            ExprKind::Cast(inner, target) => {
+                let from = self.check_expr(inner);
+                self.checks.push(Check::Cast {
+                    span,
+                    from,
+                    to: *target,
+                });
+                Ty::Known(*target)
+            }
+            ExprKind::Call(name, args) => {
+                let arg_types: Vec<(Span, Ty)> = args
+                    .iter_mut()
+                    .map(|arg| (arg.span, self.check_expr(arg)))
+                    .collect();
+                if let Some(&(name, arity)) = INTRINSICS.iter().find(|(n, _)| n == name) {
+                    if arity != args.len() {
+                        self.error(span, arity_message(name, arity, args.len()));
+                    }
+                    // Every argument, and the result, share one type
+                    let mut arg_types = arg_types.into_iter();
+                    match arg_types.next() {
+                        Some((_, ty)) => {
+                            for (arg_span, arg_ty) in arg_types {
+                                self.expect(arg_span, ty, arg_ty);
+                            }
+                            self.checks.push(Check::Intrinsic { span, name, ty });
+                            ty
+                        }
+                        None => Ty::Error,
+                    }
+                } else {
+                    match self.functions.get(name) {
+                        Some(signature) => {
+                            let params = signature.params.clone();
+                            let ret = signature.ret;
+                            if params.len() != args.len() {
+                                self.error(span, arity_message(name, params.len(), args.len()));
+                            }
+                            for ((arg_span, arg_ty), param) in arg_types.into_iter().zip(params) {
+                                self.expect(arg_span, Ty::Known(param), arg_ty);
+                            }
+                            ret
+                        }
+                        None => {
+                            let message = format!("cannot find function `{}`", name);
+                            self.error(span, message);
+                            Ty::Error
+                        }
+                    }
+                }
+            }
+        };
+        self.expr_types.push(ty);
+        ty
+    }
+
+    /// Defaults unconstrained integer literals to i64 and turns the deferred
+    /// checks into diagnostics.
+    fn solve(&mut self) -> Vec<Diagnostic> {
+        for var in 0..self.table.len() {
+            if self.table[var].integer {
+                if let Ty::Var(root) = self.resolve(Ty::Var(var)) {
+                    self.table[root].binding = Some(Ty::Known(Type::I64));
+                }
+            }
+        }
+
+        let checks = std::mem::take(&mut self.checks);
+        checks
+            .into_iter()
+            .filter_map(|check| self.judge(check))
+            .collect()
+    }
+
+    fn judge(&self, check: Check) -> Option<Diagnostic> {
+        // Checks that involve an earlier error or an undetermined type stay
+        // quiet; the root cause is reported on its own.
+        let known = |ty: Ty| match self.resolve(ty) {
+            Ty::Known(ty) => Some(ty),
+            _ => None,
+        };
+        let (span, message) = match check {
+            Check::Report(diagnostic) => return Some(diagnostic),
+            Check::Mismatch {
+                span,
+                expected,
+                found,
+            } => {
+                let (expected, found) = (known(expected)?, known(found)?);
+                (
+                    span,
+                    format!("mismatched types: expected {}, found {}", expected, found),
+                )
+            }
+            Check::Cond {
+                span,
+                keyword,
+                found,
+            } => (
+                span,
+                format!(
+                    "`{}` condition must be bool, found {}",
+                    keyword,
+                    known(found)?
+                ),
+            ),
+            Check::BinOp {
+                span,
+                op,
+                left,
+                right,
+            } => {
+                let (left, right) = (known(left)?, known(right)?);
+                let valid = match op {
+                    Op::Add | Op::Sub | Op::Mul | Op::Div => left.is_numeric(),
+                    Op::Gt | Op::Gte | Op::Lt | Op::Lte => left.is_numeric(),
+                    Op::Eq | Op::NotEq => left != Type::Unit,
+                };
+                if valid && left == right {
+                    return None;
+                }
+                (
+                    span,
+                    format!("cannot apply `{}` to {} and {}", op, left, right),
+                )
+            }
+            Check::Unary { span, op, ty } => {
+                let ty = known(ty)?;
+                let negatable = ty.is_signed() || ty.is_float();
+                if ty.is_numeric() && (op != Op::Sub || negatable) {
+                    return None;
+                }
+                (span, format!("cannot apply unary `{}` to {}", op, ty))
+            }
+            Check::Cast { span, from, to } => {
+                let from = known(from)?;
+                let valid = match (from, to) {
+                    (_, Type::Bool | Type::Unit) => from == to,
+                    (Type::Bool, to) => to.is_integer(),
+                    (from, _) => from.is_numeric(),
+                };
+                if valid {
+                    return None;
+                }
+                (span, format!("cannot cast {} to {}", from, to))
+            }
+            Check::ExitCode { span, ty } => {
+                let ty = known(ty)?;
+                if ty.is_integer() {
+                    return None;
+                }
+                (span, format!("exit code must be an integer, found {}", ty))
+            }
+            Check::NotUnit { span, ty, what } => {
+                if known(ty)? != Type::Unit {
+                    return None;
+                }
+                (span, what.to_string())
+            }
+            Check::Intrinsic { span, name, ty } => {
+                let ty = known(ty)?;
+                let valid = match name {
+                    "abs" => ty.is_signed(),
+                    _ => ty.is_numeric(),
+                };
+                if valid {
+                    return None;
+                }
+                (span, format!("cannot apply `{}` to {}", name, ty))
+            }
+            Check::Fits { span, ty, value } => {
+                let ty = known(ty)?;
+                if ty.fits(value) {
+                    return None;
+                }
+                (span, format!("literal {} does not fit in {}", value, ty))
+            }
+            Check::Returns {
+                span,
+                name,
+                ret,
+                returns,
+            } => match self.resolve(ret) {
+                Ty::Var(_) => (
+                    span,
+                    format!(
+                        "type annotations needed: cannot infer the return type of `{}`",
+                        name
+                    ),
+                ),
+                Ty::Known(ret) if ret != Type::Unit && !returns => (
+                    span,
+                    format!(
+                        "function `{}` does not return a value of type {} on every path",
+                        name, ret
+                    ),
+                ),
+                _ => return None,
+            },
+        };
+        Some(Diagnostic::error(span, message))
+    }
+}
+
+impl Default for TypeChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn arity_message(name: &str, params: usize, args: usize) -> String {
+    format!(
+        "function `{}` takes {} argument{} but {} {} supplied",
+        name,
+        params,
+        if params == 1 { "" } else { "s" },
+        args,
+        if args == 1 { "was" } else { "were" }
+    )
+}
+
+/// Whether some `return` in `stmts` carries a value.
+fn returns_value(stmts: &[Stmt]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.kind {
+        StmtKind::Return(value) => value.is_some(),
+        StmtKind::Become(_) => true,
+        StmtKind::While(_, body) => returns_value(body),
+        StmtKind::If(_, then_body, elif_branches, else_body) => {
+            returns_value(then_body)
+                || elif_branches.iter().any(|(_, body)| returns_value(body))
+                || else_body.as_deref().is_some_and(returns_value)
+        }
+        _ => false,
+    })
+}
+
+/// Whether every path through `stmts` ends in `return` or `exit`.
+fn block_returns(stmts: &[Stmt]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.kind {
+        StmtKind::Return(_) | StmtKind::Become(_) | StmtKind::Exit(_) => true,
+        StmtKind::If(_, then_body, elif_branches, Some(else_body)) => {
+            block_returns(then_body)
+                && elif_branches.iter().all(|(_, body)| block_returns(body))
+                && block_returns(else_body)
+        }
+        _ => false,
+    })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::resolve::Resolver;
+    use crate::test_util::render;
+
+    fn check(source: &str) -> Result<Vec<Stmt>, Vec<String>> {
+        let tokens = Lexer::new(source).tokenize_spanned();
+        let mut stmts = Parser::with_spans(tokens).parse();
+        let mut diagnostics = Resolver::new().resolve(&mut stmts);
+        diagnostics.retain(Diagnostic::is_error);
+        if diagnostics.is_empty() {
+            if let Err(errors) = TypeChecker::new().check(&mut stmts) {
+                diagnostics = errors;
+            }
+        }
+        if diagnostics.is_empty() {
+            return Ok(stmts);
+        }
+        Err(render(&diagnostics, source))
+    }
+
+    #[test]
+    fn test_bool_condition() {
+        check("let b = true; let x = 1; if (b) { exit(x); } while (x == 2) { exit(0); }").unwrap();
+    }
+
+    #[test]
+    fn test_int_condition_rejected() {
+        assert_eq!(
+            check("if (5) { exit(1); }").unwrap_err(),
+            vec!["`if` condition must be bool, found i64 @ 5"]
+        );
+    }
+
+    #[test]
+    fn test_bool_arithmetic_rejected() {
+        assert_eq!(
+            check("let x = 1; let y = 2; let b = x == y; exit(b + 3);").unwrap_err(),
+            vec!["cannot apply `+` to bool and i64 @ b + 3"]
+        );
+    }
+
+    #[test]
+    fn test_sized_literals_and_casts() {
+        let stmts =
+            check("let x: u8 = 200; let y = 1 + x; let z: i8 = -128; exit(y as i64);").unwrap();
+        match &stmts[1].kind {
+            StmtKind::Let(_, _, expr) => assert_eq!(expr.ty, Some(Type::U8)),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_literal_out_of_range() {
+        assert_eq!(
+            check("let x: u8 = 256;").unwrap_err(),
+            vec!["literal 256 does not fit in u8 @ 256"]
+        );
+        assert_eq!(
+            check("let x: i8 = -129;").unwrap_err(),
+            vec!["literal -129 does not fit in i8 @ -129"]
+        );
+        assert_eq!(
+            check("let x = 9223372036854775808;").unwrap_err(),
+            vec!["literal 9223372036854775808 does not fit in i64 @ 9223372036854775808"]
+        );
+        check("let x = -9223372036854775808; let y: u64 = 18446744073709551615;").unwrap();
+    }
+
+    #[test]
+    fn test_mixed_widths_rejected() {
+        assert_eq!(
+            check("let x: u8 = 1; let y: i64 = 2; exit(x + y);").unwrap_err(),
+            vec!["cannot apply `+` to u8 and i64 @ x + y"]
+        );
+    }
+
+    #[test]
+    fn test_float_arithmetic_and_casts() {
+        let stmts =
+            check("let x = 1.5 * 2.0; let n = x as i64; let y = n as f64 / -3.0; print(y > x);")
+                .unwrap();
+        match &stmts[2].kind {
+            StmtKind::Let(_, _, expr) => assert_eq!(expr.ty, Some(Type::F64)),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_float_int_mix_rejected() {
+        assert_eq!(
+            check("let x = 1.5; exit(x + 1);").unwrap_err(),
+            vec!["cannot apply `+` to f64 and i64 @ x + 1"]
+        );
+    }
+
+    #[test]
+    fn test_reports_all_errors_with_spans() {
+        let errors = check(
+            "fn add(a: i64, b: i64) -> i64 { return a + b; }
+             let x: bool = add(1);
+             while (add(x, 2)) { }",
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "function `add` takes 2 arguments but 1 was supplied @ add(1)",
+                "mismatched types: expected bool, found i64 @ add(1)",
+                "mismatched types: expected i64, found bool @ x",
+                "`while` condition must be bool, found i64 @ add(x, 2)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_function_must_return() {
+        let errors = check("fn f(a: i64) -> i64 { if (a > 0) { return 1; } }").unwrap_err();
+        assert_eq!(
+            errors,
+            vec!["function `f` does not return a value of type i64 on every path @ fn f(a: i64) -> i64 { if (a > 0) { return 1; } }"]
+        );
+        check("fn f(a: i64) -> i64 { if (a > 0) { return 1; } else { exit(2); } }").unwrap();
+    }
+
+    #[test]
+    fn test_become() {
+        check("fn f(n: i64) -> i64 { if (n == 0) { return 0; } become f(n - 1); }").unwrap();
+        let errors = check(
+            "fn g() -> u8 { return 1; } fn f(n: i64) -> i64 { if (n == 0) { become n + 1; } become g(); } become f(1);",
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "`become` needs a function call @ n + 1",
+                "mismatched types: expected i64, found u8 @ g()",
+                "`become` outside of a function @ become f(1);",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_intrinsics() {
+        check("let a: u8 = max(1, 2); let b = min(1.5, 2.5); let c = abs(-3 as i16);").unwrap();
+        let errors = check(
+            "let a = max(1); let b = min(1, 2.0); let c = abs(3 as u32); let d = max(true, false); fn min(a: i64) -> i64 { return a; }",
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "`min` is a built-in function and cannot be redefined @ fn min(a: i64) -> i64 { return a; }",
+                "function `max` takes 2 arguments but 1 was supplied @ max(1)",
+                "mismatched types: expected i64, found f64 @ 2.0",
+                "cannot apply `abs` to u32 @ abs(3 as u32)",
+                "cannot apply `max` to bool @ max(true, false)",
+            ]
+        );
+    }
+
+    fn let_types(stmts: &[Stmt]) -> Vec<Type> {
+        stmts
+            .iter()
+            .filter_map(|stmt| match &stmt.kind {
+                StmtKind::Let(_, _, expr) => expr.ty,
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_let_inference() {
+        let stmts =
+            check("let x = 5; let y = x > 3; let a = 1; let b: u8 = a; let c = 2.5;").unwrap();
+        assert_eq!(
+            let_types(&stmts),
+            vec![Type::I64, Type::Bool, Type::U8, Type::U8, Type::F64]
+        );
+    }
+
+    #[test]
+    fn test_return_type_inference() {
+        let stmts = check(
+            "fn half(x: f64) { return x / 2.0; }
+             fn fact(n: u32) { if (n < 2) { return 1; } return n * fact(n - 1); }
+             fn noop() { print(1); }
+             let h = half(3.0);
+             let f = fact(5);
+             noop();",
+        )
+        .unwrap();
+        let returns: Vec<_> = stmts
+            .iter()
+            .filter_map(|stmt| match &stmt.kind {
+                StmtKind::Fn(f) => f.ret,
+                _ => None,
+            })
+            .collect();
+        assert_eq!(returns, vec![Type::F64, Type::U32, Type::Unit]);
+        assert_eq!(let_types(&stmts), vec![Type::F64, Type::U32]);
+    }
+
+    #[test]
+    fn test_ambiguous_return_type() {
+        let errors = check("fn f(n: i64) { return f(n); } let x = f(1);").unwrap_err();
+        assert_eq!(
+            errors,
+            vec!["type annotations needed: cannot infer the return type of `f` @ fn f(n: i64) { return f(n); }"]
+        );
+    }
+
+    #[test]
+    fn test_assignment_types() {
+        check("let mut x: u8 = 1; x = 200; exit(x);").unwrap();
+        assert_eq!(
+            check("let mut x = 1; x = true; exit(x);").unwrap_err(),
+            vec!["mismatched types: expected i64, found bool @ true"]
+        );
+    }
+}
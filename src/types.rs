@@ -0,0 +1,108 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Type {
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    F64,
+    Bool,
+    /// The type of functions that return nothing.
+    Unit,
+}
+
+impl Type {
+    pub fn from_name(name: &str) -> Option<Type> {
+        Some(match name {
+            "i8" => Type::I8,
+            "i16" => Type::I16,
+            "i32" => Type::I32,
+            "i64" => Type::I64,
+            "u8" => Type::U8,
+            "u16" => Type::U16,
+            "u32" => Type::U32,
+            "u64" => Type::U64,
+            "f64" => Type::F64,
+            "bool" => Type::Bool,
+            _ => return None,
+        })
+    }
+
+    pub fn is_integer(self) -> bool {
+        !matches!(self, Type::Bool | Type::F64 | Type::Unit)
+    }
+
+    pub fn is_float(self) -> bool {
+        matches!(self, Type::F64)
+    }
+
+    /// Whether arithmetic and ordering operators apply to this type.
+    pub fn is_numeric(self) -> bool {
+        self.is_integer() || self.is_float()
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(self, Type::I8 | Type::I16 | Type::I32 | Type::I64)
+    }
+
+    /// Size in bytes of a value of this type when stored in memory.
+    pub fn size(self) -> usize {
+        match self {
+            Type::I8 | Type::U8 | Type::Bool => 1,
+            Type::I16 | Type::U16 => 2,
+            Type::I32 | Type::U32 => 4,
+            Type::I64 | Type::U64 | Type::F64 => 8,
+            Type::Unit => 0,
+        }
+    }
+
+    /// Whether the integer literal `n` is representable in this type.
+    pub fn fits(self, n: i128) -> bool {
+        let bits = self.size() as u32 * 8;
+        match self {
+            Type::Bool | Type::F64 | Type::Unit => false,
+            _ if self.is_signed() => {
+                let max = (1i128 << (bits - 1)) - 1;
+                (-max - 1..=max).contains(&n)
+            }
+            _ => (0..(1i128 << bits)).contains(&n),
+        }
+    }
+
+    /// Reduces `n` to this integer type's range, the way storing it in a
+    /// value of the type does, and extends it back to 64 bits.
+    pub fn wrap(self, n: i64) -> i64 {
+        match self {
+            Type::I8 => n as i8 as i64,
+            Type::I16 => n as i16 as i64,
+            Type::I32 => n as i32 as i64,
+            Type::U8 | Type::Bool => n as u8 as i64,
+            Type::U16 => n as u16 as i64,
+            Type::U32 => n as u32 as i64,
+            Type::I64 | Type::U64 | Type::F64 | Type::Unit => n,
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::I8 => write!(f, "i8"),
+            Type::I16 => write!(f, "i16"),
+            Type::I32 => write!(f, "i32"),
+            Type::I64 => write!(f, "i64"),
+            Type::U8 => write!(f, "u8"),
+            Type::U16 => write!(f, "u16"),
+            Type::U32 => write!(f, "u32"),
+            Type::U64 => write!(f, "u64"),
+            Type::F64 => write!(f, "f64"),
+            Type::Bool => write!(f, "bool"),
+            Type::Unit => write!(f, "()"),
+        }
+    }
+}
@@ -0,0 +1,597 @@
+//! Unrolling of counted loops, over IR in SSA form.
+//!
+//! A counted loop is one whose header only picks its values with phis and
+//! compares a counter against a bound fixed before the loop, and whose body
+//! is a single block that steps the counter by a constant and jumps back.
+//! When the counter's start and bound are constants and every iteration fits
+//! the size budget, the loop is unrolled completely into straight-line code.
+//! Otherwise the body is repeated in a new loop placed in front, which runs
+//! while enough iterations remain for all of its copies, and the original
+//! loop stays behind it for the iterations left over.
+
+use std::collections::HashMap;
+
+use crate::{
+    dom::DomTree,
+    ir::{BlockId, Function, Instr, Module, Operand, Terminator, Value},
+    loops::{find_loops, induction_variables, loop_defs, Loop},
+    parser::Op,
+    types::Type,
+};
+
+/// How many copies of a loop's body partial unrolling makes by default.
+pub const DEFAULT_FACTOR: usize = 4;
+
+/// How many instructions the copies of one loop's body may add up to.
+const BUDGET: usize = 64;
+
+/// A loop that runs while `counter op bound` holds.
+struct CountedLoop {
+    preheader: BlockId,
+    header: BlockId,
+    /// The loop's other block, which jumps back to the header.
+    body: BlockId,
+    exit: BlockId,
+    /// Each header phi, with its value on entry and the value the body
+    /// gives it for the next iteration.
+    phis: Vec<(Value, Operand, Operand)>,
+    ty: Type,
+    counter: Value,
+    init: Operand,
+    step: i64,
+    op: Op,
+    bound: Operand,
+}
+
+pub fn optimize_module(module: &mut Module, factor: usize) {
+    for f in &mut module.functions {
+        optimize(f, factor);
+    }
+}
+
+pub fn optimize(f: &mut Function, factor: usize) {
+    let dom = DomTree::new(f);
+    let mut layout: Vec<BlockId> = (0..f.blocks.len()).map(BlockId).collect();
+    let mut changed = false;
+    for l in find_loops(f, &dom) {
+        let Some(counted) = counted_loop(f, &l) else {
+            continue;
+        };
+        let body_len = f.blocks[counted.body.0].instrs.len().max(1);
+        let new_blocks = match trip_count(&counted) {
+            Some(trips) if trips <= BUDGET / body_len => vec![unroll_fully(f, &counted, trips)],
+            _ => match unroll_partially(f, &counted, factor.min(BUDGET / body_len)) {
+                Some(blocks) => blocks,
+                None => continue,
+            },
+        };
+        // Lay the new blocks out in front of the loop they replace or lead
+        let at = layout.iter().position(|&b| b == counted.header).unwrap();
+        layout.splice(at..at, new_blocks);
+        changed = true;
+    }
+    if changed {
+        f.reorder_blocks(&layout);
+        f.remove_unreachable_blocks();
+    }
+}
+
+/// `l` as a counted loop, if it is one.
+fn counted_loop(f: &Function, l: &Loop) -> Option<CountedLoop> {
+    let (header, &[body]) = (l.header, &l.latches[..]) else {
+        return None;
+    };
+    if l.blocks.len() != 2 || f.blocks[body.0].term != Terminator::Jump(header) {
+        return None;
+    }
+    let preds = &f.predecessors()[header.0];
+    let preheader = *preds.iter().find(|&&pred| pred != body)?;
+    if preds.len() != 2 || f.blocks[preheader.0].term != Terminator::Jump(header) {
+        return None;
+    }
+    let Terminator::Branch {
+        cond: Operand::Value(cond),
+        then_block,
+        else_block: exit,
+    } = f.blocks[header.0].term
+    else {
+        return None;
+    };
+    if then_block != body {
+        return None;
+    }
+
+    // The header holds nothing but its phis and the comparison
+    let (compare, phis) = f.blocks[header.0].instrs.split_last()?;
+    let Instr::Binary {
+        dst,
+        op,
+        ty,
+        lhs: Operand::Value(counter),
+        rhs: bound,
+    } = *compare
+    else {
+        return None;
+    };
+    let compares = matches!(op, Op::Lt | Op::Lte | Op::Gt | Op::Gte);
+    if dst != cond || !compares || !ty.is_integer() || uses(f, cond) != 1 {
+        return None;
+    }
+    let phis = phis
+        .iter()
+        .map(|instr| match instr {
+            Instr::Phi { dst, args } if args.len() == 2 => {
+                let arg = |pred| {
+                    args.iter()
+                        .find(|(from, _)| *from == pred)
+                        .map(|(_, arg)| *arg)
+                };
+                Some((*dst, arg(preheader)?, arg(body)?))
+            }
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    if f.blocks[body.0]
+        .instrs
+        .iter()
+        .any(|instr| matches!(instr, Instr::Phi { .. }))
+    {
+        return None;
+    }
+
+    let defined = loop_defs(f, l);
+    let invariant = match bound {
+        Operand::Value(value) => !defined[value.0],
+        Operand::Int(_) => true,
+        Operand::Float(_) => false,
+    };
+    let iv = induction_variables(f, l, &defined)
+        .into_iter()
+        .find(|iv| iv.phi == counter)?;
+    let Operand::Int(step) = iv.step else {
+        return None;
+    };
+    // Counting down an unsigned counter adds a large number that wraps
+    let step = signed(ty, step);
+    let upward = matches!(op, Op::Lt | Op::Lte);
+    if !invariant || step == 0 || (step > 0) != upward {
+        return None;
+    }
+    Some(CountedLoop {
+        preheader,
+        header,
+        body,
+        exit,
+        phis,
+        ty,
+        counter,
+        init: iv.init,
+        step,
+        op,
+        bound,
+    })
+}
+
+/// How many times the body of `l` runs, when that is known now. The counter
+/// must not wrap around on the way, which would change the answer.
+fn trip_count(l: &CountedLoop) -> Option<usize> {
+    let (Operand::Int(init), Operand::Int(bound)) = (l.init, l.bound) else {
+        return None;
+    };
+    let (init, bound, step) = (value(l.ty, init), value(l.ty, bound), l.step as i128);
+    let distance = if step > 0 { bound - init } else { init - bound };
+    let stride = step.abs();
+    let trips = match l.op {
+        Op::Lte | Op::Gte if distance >= 0 => distance / stride + 1,
+        Op::Lt | Op::Gt if distance > 0 => (distance + stride - 1) / stride,
+        _ => 0,
+    };
+    let last = init + trips * step;
+    if !l.ty.fits(last) {
+        return None;
+    }
+    usize::try_from(trips).ok()
+}
+
+/// Replaces `l` with `trips` copies of its body in a new block, and returns
+/// that block.
+fn unroll_fully(f: &mut Function, l: &CountedLoop, trips: usize) -> BlockId {
+    let block = f.new_block("unrolled");
+    let mut values: Vec<Operand> = l.phis.iter().map(|(_, init, _)| *init).collect();
+    let mut instrs = Vec::new();
+    for _ in 0..trips {
+        values = copy_body(f, l, &values, &mut instrs);
+    }
+    f.blocks[block.0].instrs = instrs;
+    f.blocks[block.0].term = Terminator::Jump(l.exit);
+    f.blocks[l.preheader.0].term = Terminator::Jump(block);
+
+    // Code after the loop reads what the last iteration left
+    let finals: HashMap<Value, Operand> =
+        l.phis.iter().map(|(dst, _, _)| *dst).zip(values).collect();
+    for (id, other) in f.blocks.iter_mut().enumerate() {
+        if id == l.header.0 || id == l.body.0 {
+            continue;
+        }
+        for instr in &mut other.instrs {
+            if let Instr::Phi { args, .. } = instr {
+                for (pred, _) in args {
+                    if *pred == l.header {
+                        *pred = block;
+                    }
+                }
+            }
+        }
+        let operands = other
+            .instrs
+            .iter_mut()
+            .flat_map(Instr::operands_mut)
+            .chain(other.term.operands_mut());
+        for operand in operands {
+            if let Some(value) = operand.as_value().and_then(|value| finals.get(&value)) {
+                *operand = *value;
+            }
+        }
+    }
+    block
+}
+
+/// Puts a loop running `copies` iterations of `l` at a time in front of it,
+/// and returns its header and body. `l` is left to finish the iterations
+/// that remain.
+fn unroll_partially(f: &mut Function, l: &CountedLoop, copies: usize) -> Option<Vec<BlockId>> {
+    if copies < 2 {
+        return None;
+    }
+    let limit = limit(f, l, copies)?;
+    let header = f.new_block("unrolled_start");
+    let body = f.new_block("unrolled_body");
+
+    let phis: Vec<Value> = l
+        .phis
+        .iter()
+        .map(|(dst, _, _)| {
+            let info = f.values[dst.0].clone();
+            f.new_value(info.ty, info.name)
+        })
+        .collect();
+    let current: Vec<Operand> = phis.iter().copied().map(Operand::Value).collect();
+    let mut values = current.clone();
+    let mut instrs = Vec::new();
+    for _ in 0..copies {
+        values = copy_body(f, l, &values, &mut instrs);
+    }
+    f.blocks[body.0].instrs = instrs;
+    f.blocks[body.0].term = Terminator::Jump(header);
+
+    let mut header_instrs: Vec<Instr> = phis
+        .iter()
+        .zip(&l.phis)
+        .zip(values)
+        .map(|((&dst, &(_, init, _)), next)| Instr::Phi {
+            dst,
+            args: vec![(l.preheader, init), (body, next)],
+        })
+        .collect();
+    let counter = l
+        .phis
+        .iter()
+        .position(|(dst, _, _)| *dst == l.counter)
+        .unwrap();
+    let cond = f.new_value(Type::Bool, None);
+    header_instrs.push(Instr::Binary {
+        dst: cond,
+        op: if l.step > 0 { Op::Lt } else { Op::Gt },
+        ty: l.ty,
+        lhs: current[counter],
+        rhs: limit,
+    });
+    f.blocks[header.0].instrs = header_instrs;
+    f.blocks[header.0].term = Terminator::Branch {
+        cond: Operand::Value(cond),
+        then_block: body,
+        else_block: l.header,
+    };
+
+    // The original loop now starts where the unrolled one stopped
+    f.blocks[l.preheader.0].term = Terminator::Jump(header);
+    for (instr, value) in f.blocks[l.header.0].instrs.iter_mut().zip(current) {
+        if let Instr::Phi { args, .. } = instr {
+            for (pred, arg) in args {
+                if *pred == l.preheader {
+                    *pred = header;
+                    *arg = value;
+                }
+            }
+        }
+    }
+    Some(vec![header, body])
+}
+
+/// The operand that the unrolled loop's counter is compared against, with
+/// `<` when counting up and `>` when counting down, so that the comparison
+/// holds exactly when the original loop would run `copies` more times.
+/// Works it out at the end of the preheader unless the bound is a constant.
+/// When that many iterations can never remain, the limit is the end of the
+/// counter's range, which the comparison never passes; `None` if that is
+/// already known now.
+fn limit(f: &mut Function, l: &CountedLoop, copies: usize) -> Option<Operand> {
+    let inclusive = matches!(l.op, Op::Lte | Op::Gte);
+    let distance = (copies as i128 - 1) * l.step.unsigned_abs() as i128 - inclusive as i128;
+    let (min, max) = range(l.ty);
+    let upward = l.step > 0;
+    let (shift, check, threshold, end) = if upward {
+        (Op::Sub, Op::Gte, min + distance, min)
+    } else {
+        (Op::Add, Op::Lte, max - distance, max)
+    };
+    if !l.ty.fits(threshold) {
+        return None;
+    }
+    match l.bound {
+        Operand::Int(bound) => {
+            let bound = value(l.ty, bound);
+            let limit = if upward {
+                bound - distance
+            } else {
+                bound + distance
+            };
+            l.ty.fits(limit).then_some(Operand::Int(limit as i64))
+        }
+        bound if distance == 0 => Some(bound),
+        bound => {
+            let fits = f.new_value(Type::Bool, None);
+            let shifted = f.new_value(l.ty, None);
+            let limit = f.new_value(l.ty, None);
+            f.blocks[l.preheader.0].instrs.extend([
+                Instr::Binary {
+                    dst: fits,
+                    op: check,
+                    ty: l.ty,
+                    lhs: bound,
+                    rhs: Operand::Int(threshold as i64),
+                },
+                Instr::Binary {
+                    dst: shifted,
+                    op: shift,
+                    ty: l.ty,
+                    lhs: bound,
+                    rhs: Operand::Int(distance as i64),
+                },
+                Instr::Select {
+                    dst: limit,
+                    cond: Operand::Value(fits),
+                    then_value: Operand::Value(shifted),
+                    else_value: Operand::Int(end as i64),
+                },
+            ]);
+            Some(Operand::Value(limit))
+        }
+    }
+}
+
+/// Appends a copy of the body of `l` to `instrs`, reading `values` for the
+/// header phis, and returns the values the phis take next. Integer
+/// arithmetic on constants is worked out on the way, so that the counter of
+/// a fully unrolled loop becomes a constant.
+fn copy_body(
+    f: &mut Function,
+    l: &CountedLoop,
+    values: &[Operand],
+    instrs: &mut Vec<Instr>,
+) -> Vec<Operand> {
+    let mut map: HashMap<Value, Operand> = l
+        .phis
+        .iter()
+        .map(|(dst, _, _)| *dst)
+        .zip(values.iter().copied())
+        .collect();
+    let renamed = |map: &HashMap<Value, Operand>, operand: Operand| match operand {
+        Operand::Value(value) => map.get(&value).copied().unwrap_or(operand),
+        _ => operand,
+    };
+    for mut instr in f.blocks[l.body.0].instrs.clone() {
+        for operand in instr.operands_mut() {
+            *operand = renamed(&map, *operand);
+        }
+        let Some(dst) = instr.dst() else {
+            instrs.push(instr);
+            continue;
+        };
+        if let Some(constant) = constant(&instr) {
+            map.insert(dst, constant);
+            continue;
+        }
+        let info = f.values[dst.0].clone();
+        let copy = f.new_value(info.ty, info.name);
+        *instr.dst_mut().unwrap() = copy;
+        map.insert(dst, Operand::Value(copy));
+        instrs.push(instr);
+    }
+    l.phis
+        .iter()
+        .map(|(_, _, next)| renamed(&map, *next))
+        .collect()
+}
+
+/// What `instr` computes, if it is a copy or integer arithmetic on
+/// constants.
+fn constant(instr: &Instr) -> Option<Operand> {
+    match *instr {
+        Instr::Copy { src, .. } => Some(src),
+        Instr::Binary {
+            op,
+            ty,
+            lhs: Operand::Int(a),
+            rhs: Operand::Int(b),
+            ..
+        } => {
+            let n = match op {
+                Op::Add => a.wrapping_add(b),
+                Op::Sub => a.wrapping_sub(b),
+                Op::Mul => a.wrapping_mul(b),
+                _ => return None,
+            };
+            Some(Operand::Int(ty.wrap(n)))
+        }
+        _ => None,
+    }
+}
+
+/// How many times `value` is read in `f`.
+fn uses(f: &Function, value: Value) -> usize {
+    f.blocks
+        .iter()
+        .flat_map(|block| {
+            block
+                .instrs
+                .iter()
+                .flat_map(Instr::operands)
+                .chain(block.term.operands())
+        })
+        .filter(|operand| **operand == Operand::Value(value))
+        .count()
+}
+
+/// The integer `n` of type `ty` as a number, reading unsigned types as such.
+fn value(ty: Type, n: i64) -> i128 {
+    if ty.is_signed() {
+        n as i128
+    } else {
+        n as u64 as i128
+    }
+}
+
+/// `n` as a signed number of `ty`'s width.
+fn signed(ty: Type, n: i64) -> i64 {
+    match ty.size() {
+        1 => n as i8 as i64,
+        2 => n as i16 as i64,
+        4 => n as i32 as i64,
+        _ => n,
+    }
+}
+
+/// The smallest and largest values of the integer type `ty`.
+fn range(ty: Type) -> (i128, i128) {
+    let bits = ty.size() as u32 * 8;
+    if ty.is_signed() {
+        (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+    } else {
+        (0, (1 << bits) - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gvn, loops, test_util::ssa_module, verify::verify};
+
+    fn unrolled(source: &str, factor: usize) -> Function {
+        let mut module = ssa_module(source);
+        gvn::optimize_module(&mut module);
+        loops::optimize_module(&mut module);
+        optimize_module(&mut module, factor);
+        let f = module
+            .functions
+            .into_iter()
+            .find(|f| f.name == "f")
+            .unwrap();
+        verify(&f).unwrap();
+        f
+    }
+
+    fn loop_count(f: &Function) -> usize {
+        find_loops(f, &DomTree::new(f)).len()
+    }
+
+    fn count(f: &Function, name: &str, pred: impl Fn(&Instr) -> bool) -> usize {
+        f.blocks
+            .iter()
+            .filter(|block| block.name == name)
+            .flat_map(|block| &block.instrs)
+            .filter(|instr| pred(instr))
+            .count()
+    }
+
+    #[test]
+    fn test_small_constant_loops_unroll_fully() {
+        let f = unrolled(
+            "fn f(s: i64) -> i64 { let mut t = s; let mut i = 0; while (i < 4) { t = t * 3 + i; i = i + 1; } return t; } exit(f(1) as u8);",
+            DEFAULT_FACTOR,
+        );
+        assert_eq!(loop_count(&f), 0);
+        // The counter is gone, and its values are constants in the copies
+        let adds = count(&f, "unrolled", |instr| {
+            matches!(
+                instr,
+                Instr::Binary {
+                    op: Op::Add,
+                    rhs: Operand::Int(_),
+                    ..
+                }
+            )
+        });
+        assert_eq!(adds, 4);
+        assert_eq!(f.blocks.len(), 3);
+    }
+
+    #[test]
+    fn test_unknown_trip_counts_keep_a_remainder_loop() {
+        let f = unrolled(
+            "fn f(n: i64) -> i64 { let mut s = 0; let mut i = 0; while (i < n) { s = s + i; i = i + 1; } return s; } exit(f(10) as u8);",
+            DEFAULT_FACTOR,
+        );
+        assert_eq!(loop_count(&f), 2);
+        let adds = count(&f, "unrolled_body", |instr| {
+            matches!(instr, Instr::Binary { op: Op::Add, .. })
+        });
+        assert_eq!(adds, 2 * DEFAULT_FACTOR);
+        // `n - 3` would wrap for the smallest bounds, where the unrolled loop
+        // must not run at all
+        assert_eq!(
+            count(&f, "entry", |instr| matches!(instr, Instr::Select { .. })),
+            1
+        );
+    }
+
+    #[test]
+    fn test_unrolling_respects_factor_and_budget() {
+        let source = "fn f(s: i64) -> i64 { let mut t = s; let mut i = 10; while (i >= 0) { t = t + i; i = i - 2; } return t; } exit(f(1) as u8);";
+        // Six iterations fit the budget whatever the factor
+        let f = unrolled(source, 1);
+        assert_eq!(loop_count(&f), 0);
+
+        let long = "fn f(s: i64) -> i64 { let mut t = s; let mut i = 0; while (i < 1000) { t = t + i; i = i + 1; } return t; } exit(f(1) as u8);";
+        let f = unrolled(long, 1);
+        assert_eq!(loop_count(&f), 1);
+        let f = unrolled(long, 3);
+        assert_eq!(loop_count(&f), 2);
+        let header = f
+            .blocks
+            .iter()
+            .find(|b| b.name == "unrolled_start")
+            .unwrap();
+        assert!(matches!(
+            header.instrs.last(),
+            Some(Instr::Binary {
+                op: Op::Lt,
+                rhs: Operand::Int(998),
+                ..
+            })
+        ));
+        // A body too large for two copies stays as it is
+        let body = "t = t * 3 + i;".repeat(20);
+        let big = format!(
+            "fn f(s: i64) -> i64 {{ let mut t = s; let mut i = 0; while (i < 1000) {{ {} i = i + 1; }} return t; }} exit(f(1) as u8);",
+            body
+        );
+        let f = unrolled(&big, DEFAULT_FACTOR);
+        assert_eq!(loop_count(&f), 1);
+        // Counting all of `u64` is unrolled partially, not fully
+        let huge = "fn f(s: u64) -> u64 { let mut t = s; let mut i: u64 = 0; let n: u64 = 18446744073709551615; \
+             while (i < n) { t = t + i; i = i + 1; } return t; } exit(f(1) as u8);";
+        let f = unrolled(huge, DEFAULT_FACTOR);
+        assert_eq!(loop_count(&f), 2);
+    }
+}
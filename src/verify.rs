@@ -0,0 +1,209 @@
+//! Consistency checks for IR in SSA form.
+//!
+//! A failure here means an earlier pass produced broken IR, not that the
+//! program being compiled is wrong.
+
+use crate::{
+    dom::DomTree,
+    ir::{BlockId, Function, Instr, Module, Operand, Terminator, Value},
+};
+
+/// Checks every function in `module`, collecting all the problems found.
+pub fn verify_module(module: &Module) -> Result<(), Vec<String>> {
+    let errors: Vec<String> = module
+        .functions
+        .iter()
+        .filter_map(|f| verify(f).err())
+        .flatten()
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Checks that every block ends in a terminator whose targets exist, that
+/// phis come first and match the block's predecessors, that each value is
+/// assigned once, and that every use is dominated by its definition.
+pub fn verify(f: &Function) -> Result<(), Vec<String>> {
+    let mut errors = Errors {
+        f,
+        list: Vec::new(),
+    };
+    if f.blocks.is_empty() {
+        errors.add(0, "function has no blocks".to_string());
+        return errors.finish();
+    }
+
+    // Where each value is defined: the block, and the index of the
+    // instruction in it, or `None` for a parameter
+    let mut defs: Vec<Option<(BlockId, Option<usize>)>> = vec![None; f.values.len()];
+    for &param in &f.params {
+        defs[param.0] = Some((BlockId(0), None));
+    }
+    for (id, block) in f.blocks.iter().enumerate() {
+        for (i, instr) in block.instrs.iter().enumerate() {
+            if let Some(dst) = instr.dst() {
+                if defs[dst.0].is_some() {
+                    errors.add(
+                        id,
+                        format!("{} is assigned more than once", f.fmt_value(dst)),
+                    );
+                }
+                defs[dst.0] = Some((BlockId(id), Some(i)));
+            }
+        }
+        if block.term == Terminator::Pending {
+            errors.add(id, "block does not end in a terminator".to_string());
+        }
+        for succ in block.term.successors() {
+            if succ.0 >= f.blocks.len() {
+                errors.add(id, format!("jump to nonexistent block bb{}", succ.0));
+            }
+        }
+    }
+    if !errors.list.is_empty() {
+        return errors.finish();
+    }
+
+    let dom = DomTree::new(f);
+    let preds = f.predecessors();
+    // Whether the definition of `value` is available just before
+    // instruction `index` of `block` (or at its end, for `None`)
+    let available = |value: Value, block: BlockId, index: Option<usize>| match defs[value.0] {
+        None => false,
+        Some((def_block, def_index)) if def_block == block => match (def_index, index) {
+            (Some(def), Some(use_)) => def < use_,
+            _ => true,
+        },
+        Some((def_block, _)) => dom.dominates(def_block, block),
+    };
+
+    for (id, block) in f.blocks.iter().enumerate() {
+        // Nothing can be said about uses in blocks that never run
+        if !dom.is_reachable(BlockId(id)) {
+            continue;
+        }
+        let mut in_phis = true;
+        for (i, instr) in block.instrs.iter().enumerate() {
+            let Instr::Phi { dst, args } = instr else {
+                in_phis = false;
+                for value in instr.operands().into_iter().filter_map(Operand::as_value) {
+                    if !available(value, BlockId(id), Some(i)) {
+                        errors.undominated(id, value);
+                    }
+                }
+                continue;
+            };
+            if !in_phis {
+                errors.add(
+                    id,
+                    format!("phi for {} follows other instructions", f.fmt_value(*dst)),
+                );
+            }
+            let mut from: Vec<BlockId> = args.iter().map(|(pred, _)| *pred).collect();
+            from.sort();
+            let mut expected = preds[id].clone();
+            expected.sort();
+            if from != expected {
+                errors.add(
+                    id,
+                    format!(
+                        "phi for {} does not match the block's predecessors",
+                        f.fmt_value(*dst)
+                    ),
+                );
+            }
+            // A phi reads each operand at the end of its predecessor
+            for (pred, arg) in args {
+                if let Some(value) = arg.as_value() {
+                    if dom.is_reachable(*pred) && !available(value, *pred, None) {
+                        errors.undominated(id, value);
+                    }
+                }
+            }
+        }
+        for value in block
+            .term
+            .operands()
+            .into_iter()
+            .filter_map(Operand::as_value)
+        {
+            if !available(value, BlockId(id), None) {
+                errors.undominated(id, value);
+            }
+        }
+    }
+    errors.finish()
+}
+
+struct Errors<'a> {
+    f: &'a Function,
+    list: Vec<String>,
+}
+
+impl Errors<'_> {
+    fn add(&mut self, block: usize, message: String) {
+        self.list
+            .push(format!("in `{}`, bb{}: {}", self.f.name, block, message));
+    }
+
+    fn undominated(&mut self, block: usize, value: Value) {
+        let message = format!(
+            "use of {} is not dominated by its definition",
+            self.f.fmt_value(value)
+        );
+        self.add(block, message);
+    }
+
+    fn finish(self) -> Result<(), Vec<String>> {
+        if self.list.is_empty() {
+            Ok(())
+        } else {
+            Err(self.list)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ssa_module;
+
+    fn ssa_function(source: &str) -> Function {
+        ssa_module(source).functions.remove(0)
+    }
+
+    #[test]
+    fn test_rejects_undominated_use() {
+        let mut f = ssa_function("let c = 1; let mut x = 0; if (c == 1) { x = 2; } exit(x);");
+        assert_eq!(verify(&f), Ok(()));
+
+        // Make the exit read the value assigned in only one arm
+        let assigned = f.blocks[1].instrs[0].dst().unwrap();
+        let end = f.blocks.len() - 1;
+        f.blocks[end].term = Terminator::Exit(Operand::Value(assigned));
+        let errors = verify(&f).unwrap_err();
+        assert_eq!(
+            errors,
+            [format!(
+                "in `_start`, bb{}: use of {} is not dominated by its definition",
+                end,
+                f.fmt_value(assigned)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_rejects_unterminated_block_and_double_assignment() {
+        let mut f = ssa_function("let x = 1; exit(x);");
+        f.blocks[0].term = Terminator::Pending;
+        let copy = f.blocks[0].instrs[0].clone();
+        f.blocks[0].instrs.push(copy);
+        let errors = verify(&f).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].ends_with("is assigned more than once"));
+        assert!(errors[1].ends_with("block does not end in a terminator"));
+    }
+}